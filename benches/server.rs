@@ -19,7 +19,7 @@ macro_rules! hello_multi {
                 for mut stream in streams.iter() {
                     match iter.next() {
                         Some(_) => {
-                            stream.write(b"HELLO bar\n").unwrap();
+                            stream.write_all(b"HELLO bar\n").unwrap();
                             count += 1;
                         }
                         None => {
@@ -57,7 +57,7 @@ fn criterion_benchmark(c: &mut Criterion) {
             let ret = b"0 Hello\n";
             let start = std::time::Instant::now();
             for _ in 0..iters {
-                stream.write(b"HELLO bar\n").unwrap();
+                stream.write_all(b"HELLO bar\n").unwrap();
                 let size = stream.read(&mut buf).unwrap();
                 assert_eq!(&buf[0..size], ret);
             }
@@ -77,7 +77,7 @@ fn criterion_benchmark(c: &mut Criterion) {
             let ret = b"1";
             let start = std::time::Instant::now();
             for _ in 0..iters {
-                stream.write(b"ADDJOB aa 300 bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb\n").unwrap();
+                stream.write_all(b"ADDJOB aa 300 bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb\n").unwrap();
                 let _size = stream.read(&mut buf).unwrap();
                 assert_eq!(&buf[0..1], ret);
             }
@@ -92,18 +92,18 @@ fn criterion_benchmark(c: &mut Criterion) {
             let mut buf = vec![0; 4096];
             let ret = b"1";
             for _ in 0..iters {
-                stream.write(b"ADDJOB aa 300 bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb\n").unwrap();
+                stream.write_all(b"ADDJOB aa 300 bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb\n").unwrap();
                 let _size = stream.read(&mut buf).unwrap();
                 assert_eq!(&buf[0..1], ret);
             }
             let start = std::time::Instant::now();
             for _ in 0..iters {
-                stream.write(b"GETJOB aa\n").unwrap();
+                stream.write_all(b"GETJOB aa\n").unwrap();
                 let _size = stream.read(&mut buf).unwrap();
                 assert_eq!(&buf[0..1], ret);
             }
             let elapsed = start.elapsed();
-            stream.write(b"DELQUE aa\n").unwrap();
+            stream.write_all(b"DELQUE aa\n").unwrap();
             let _size = stream.read(&mut buf).unwrap();
             let _ = stream.shutdown(Shutdown::Both);
             elapsed
@@ -116,12 +116,12 @@ fn criterion_benchmark(c: &mut Criterion) {
             let ret = b"1";
             let mut jobs = Vec::new();
             for _ in 0..iters {
-                stream.write(b"ADDJOB aa 300 bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb\n").unwrap();
+                stream.write_all(b"ADDJOB aa 300 bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb\n").unwrap();
                 let _size = stream.read(&mut buf).unwrap();
                 assert_eq!(&buf[0..1], ret);
             }
             for _ in 0..iters {
-                stream.write(b"GETJOB aa\n").unwrap();
+                stream.write_all(b"GETJOB aa\n").unwrap();
                 let size = stream.read(&mut buf).unwrap();
                 assert_eq!(size, 94);
                 assert_eq!(&buf[0..1], ret);
@@ -129,12 +129,12 @@ fn criterion_benchmark(c: &mut Criterion) {
             }
             let start = std::time::Instant::now();
             for job in jobs.iter() {
-                stream.write([b"ACKJOB ", job.as_slice(), b"\n"].concat().as_slice()).unwrap();
+                stream.write_all([b"ACKJOB ", job.as_slice(), b"\n"].concat().as_slice()).unwrap();
                 let _size = stream.read(&mut buf).unwrap();
                 assert_eq!(&buf[0..1], ret);
             }
             let elapsed = start.elapsed();
-            stream.write(b"DELQUE aa\n").unwrap();
+            stream.write_all(b"DELQUE aa\n").unwrap();
             let _size = stream.read(&mut buf).unwrap();
             let _ = stream.shutdown(Shutdown::Both);
             elapsed
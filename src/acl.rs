@@ -0,0 +1,154 @@
+use crate::command::Command;
+use crate::config::{Config, UserConfig};
+use crate::limit::Limit;
+use crate::utils::{glob, is_delimiter, secure_eq};
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+pub(crate) struct Auth {
+    // Tokens accepted by AUTH, which allow every command.
+    tokens: Vec<Vec<u8>>,
+    users: HashMap<Vec<u8>, Arc<User>>,
+}
+
+impl Auth {
+    pub(crate) fn new(config: &Config) -> io::Result<Auth> {
+        Ok(Auth {
+            tokens: config
+                .auth_tokens
+                .iter()
+                .map(|token| token.as_bytes().to_vec())
+                .collect(),
+            users: config
+                .users
+                .iter()
+                .map(|(name, user)| {
+                    Ok((name.as_bytes().to_vec(), Arc::new(User::new(name, user)?)))
+                })
+                .collect::<io::Result<_>>()?,
+        })
+    }
+
+    // No authentication is required if neither tokens nor users are given.
+    #[inline]
+    pub(crate) fn is_open(&self) -> bool {
+        self.tokens.is_empty() && self.users.is_empty()
+    }
+
+    // Authenticate by `AUTH <token>` or `AUTH <user> <password>`.
+    // The user is `None` for the tokens, which are not restricted.
+    pub(crate) fn authenticate(&self, arg: &[u8]) -> Option<Option<Arc<User>>> {
+        let mut iter = arg.split(is_delimiter).filter(|s| !s.is_empty());
+        match (iter.next(), iter.next()) {
+            _ if self.is_open() => Some(None),
+            (Some(token), None) => self
+                .tokens
+                .iter()
+                .any(|t| secure_eq(t, token))
+                .then_some(None),
+            (Some(name), Some(password)) => self
+                .users
+                .get(name)
+                .filter(|user| user.verify(password))
+                .map(|user| Some(user.clone())),
+            _ => None,
+        }
+    }
+}
+
+// A user given by `[users.<name>]` of the configuration file.
+pub(crate) struct User {
+    pub(crate) name: String,
+    password: Vec<u8>,
+    // `None` allows every command.
+    commands: Option<Vec<Command>>,
+    queues: Vec<Vec<u8>>,
+    // Shared by all connections of the user.
+    pub(crate) limit: Limit,
+}
+
+impl User {
+    pub(crate) fn new(name: &str, config: &UserConfig) -> io::Result<User> {
+        let commands = match config.commands.iter().any(|cmd| cmd == "*") {
+            true => None,
+            false => Some(
+                config
+                    .commands
+                    .iter()
+                    .map(|cmd| {
+                        Command::from(cmd.to_uppercase().as_bytes()).ok_or_else(|| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                format!("users.{}: unknown command: {}", name, cmd),
+                            )
+                        })
+                    })
+                    .collect::<io::Result<_>>()?,
+            ),
+        };
+        Ok(User {
+            name: name.to_owned(),
+            password: config.password.as_bytes().to_vec(),
+            commands,
+            queues: config
+                .queues
+                .iter()
+                .map(|queue| queue.as_bytes().to_vec())
+                .collect(),
+            limit: Limit::new(&format!("users.{}.rate_limit", name), &config.rate_limit)?,
+        })
+    }
+
+    pub(crate) fn verify(&self, password: &[u8]) -> bool {
+        secure_eq(&self.password, password)
+    }
+
+    // Whether the user may run the command on every queue given by the arguments.
+    // Commands taking job IDs are checked by the queue managers, which know the queues
+    // of the jobs. See `permits_queue`.
+    pub(crate) fn permits(&self, cmd: &Command, arg: &[u8]) -> bool {
+        let allowed = match self.commands.as_ref() {
+            Some(commands) => commands.contains(cmd),
+            None => true,
+        };
+        allowed
+            && match cmd {
+                // They show the jobs and the connections of every queue.
+                Command::SYNC | Command::CLIENT => self.queues.iter().any(|q| q == b"*"),
+                _ => queues(cmd, arg)
+                    .into_iter()
+                    .all(|name| self.permits_queue(name)),
+            }
+    }
+
+    pub(crate) fn permits_queue(&self, name: &[u8]) -> bool {
+        self.queues.iter().any(|pattern| glob(pattern, name))
+    }
+}
+
+// Only the name, which is enough to tell the user in the logs.
+impl fmt::Debug for User {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("User").field("name", &self.name).finish()
+    }
+}
+
+// The queue names in the arguments of the command.
+fn queues<'a>(cmd: &Command, arg: &'a [u8]) -> Vec<&'a [u8]> {
+    let names = arg.split(is_delimiter).filter(|name| !name.is_empty());
+    match cmd {
+        Command::GETJOB => names.collect(),
+        Command::MOVEQUE => names.take(2).collect(),
+        Command::ADDJOB
+        | Command::STATQUE
+        | Command::DELQUE
+        | Command::CREATEQUE
+        | Command::PAUSEQUE
+        | Command::RESUMEQUE
+        | Command::PURGEQUE
+        | Command::REQUEUE => names.take(1).collect(),
+        _ => Vec::new(),
+    }
+}
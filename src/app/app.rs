@@ -2,12 +2,13 @@
 extern crate log;
 
 use env_logger::Env;
-use qust::Server;
-use std::env::args;
+use log::LevelFilter;
+use qust::config::{ConfigFile, BUFFER_SIZE, HOST, MAX_BUFFER_SIZE, PORT, REPLY_HIGH_WATERMARK};
+use qust::{Config, Server};
+use std::env::{self, args, Args};
+use std::path::PathBuf;
 use std::process::exit;
-
-const HOST: &str = "127.0.0.1";
-const PORT: &str = "9000";
+use std::str::FromStr;
 
 fn show_help() {
     println!(
@@ -26,11 +27,95 @@ fn show_help() {
             format!("        Set a host. Default: {}", HOST).as_str(),
             "    -p, --port <port>",
             format!("        Set a port. Default: {}", PORT).as_str(),
+            "    -s, --unix-socket <path>",
+            "        Listen on the Unix domain socket, in addition to TCP.",
+            "    -b, --bind <address>",
+            "        Listen on one more address: <host>:<port>, [<IPv6 address>]:<port> or",
+            "        unix:<path>. This option can be given multiple times.",
+            "    --no-tcp",
+            "        Do not listen on --host and --port. Use it with --unix-socket or --bind.",
+            "    --tls-cert <path>, --tls-key <path>",
+            "        Accept TCP connections with TLS using the certificate chain and the private",
+            "        key (PEM). Requires the `tls` feature.",
+            "    --tls-client-ca <path>",
+            "        Require client certificates signed by the CA certificates (PEM).",
+            "    --auth-token <token>",
+            "        Require AUTH with the token. This option can be given multiple times.",
+            "    -c, --config <path>",
+            "        Read settings from the TOML file. The file is read again on SIGHUP.",
+            "    --buffer-size <bytes>",
+            format!(
+                "        Set a size of the read buffer. Default: {}",
+                BUFFER_SIZE
+            )
+            .as_str(),
+            "    --max-buffer-size <bytes>",
+            format!(
+                "        Set a max size of a request. Default: {}",
+                MAX_BUFFER_SIZE
+            )
+            .as_str(),
+            "    --reply-high-watermark <bytes>",
+            "        Stop reading the requests of a connection while its replies waiting to be",
+            "        written exceed the size. It is a soft limit, which the replies to the",
+            "        requests already read may exceed. 0 means unlimited.",
+            format!("        Default: {}", REPLY_HIGH_WATERMARK).as_str(),
+            "    --max-memory <bytes>",
+            "        Set an upper bound of memory used by jobs. Default: 0 (unlimited)",
+            "    --evict-queue <queue name>",
+            "        Allow dropping the oldest ready jobs of the queue when memory is full.",
+            "        This option can be given multiple times.",
+            "    --persist <path>",
+            "        Save the queues to the file on shutdown and load them on startup. In the",
+            "        cluster mode, the file keeps the log of the node instead.",
+            "    --shutdown-timeout <seconds>",
+            "        Wait for in-flight requests and replies on shutdown. Default: 0 (immediate)",
+            "        Send the signal again to shut down immediately.",
+            "    --max-connections <number>",
+            "        Reject connections beyond the number. Default: 0 (unlimited)",
+            "    --idle-timeout <seconds>",
+            "        Close connections which send nothing for the seconds. Default: 0 (disabled)",
+            "    --reply-timeout <seconds>",
+            "        Close connections which do not read the reply for the seconds.",
+            "        Default: 0 (disabled)",
+            "    --requeue-on-disconnect",
+            "        Return the jobs got by a connection to ready as soon as it is closed,",
+            "        instead of waiting for their retry time.",
+            "    --replica-of <host>:<port>",
+            "        Follow the primary as a read-only replica until PROMOTE.",
+            "    --replica-auth <token>",
+            "        Authenticate to the primary by AUTH with the token or `<user> <password>`.",
+            "    --cluster <host>:<port>",
+            "        Join the cluster of the nodes, which replicate the queues by Raft. Give every",
+            "        node including this one, in the same order on all nodes. This option can be",
+            "        given multiple times.",
+            "    --cluster-node <index>",
+            "        Set the index of this node in --cluster. Default: 0",
+            "    --cluster-auth <token>",
+            "        Authenticate to the other nodes by AUTH with the token or `<user> <password>`.",
+            "        Required in the cluster mode. Only the connections authenticated by it may",
+            "        send RAFT.",
+            "    --shards <number>",
+            "        Run the number of queue manager threads, among which the queues are",
+            "        partitioned by their names. Default: 1",
+            "    --io-threads <number>",
+            "        Run the number of threads which read the requests and write the replies.",
+            "        Default: 1",
             "    --help",
             "        Prints help information. Use --help for more details.",
             "    --version",
             "        Prints version information.",
             "",
+            "ENVIRONMENT:",
+            "    QUST_CONFIG, QUST_HOST, QUST_PORT, QUST_TCP, QUST_UNIX_SOCKET, QUST_BIND,",
+            "    QUST_TLS_CERT, QUST_TLS_KEY, QUST_TLS_CLIENT_CA, QUST_AUTH_TOKENS,",
+            "    QUST_LOG_LEVEL, QUST_BUFFER_SIZE, QUST_MAX_BUFFER_SIZE, QUST_MAX_MEMORY,",
+            "    QUST_PERSIST, QUST_SHUTDOWN_TIMEOUT, QUST_MAX_CONNECTIONS, QUST_IDLE_TIMEOUT,",
+            "    QUST_REPLY_TIMEOUT, QUST_REQUEUE_ON_DISCONNECT, QUST_REPLICA_OF,",
+            "    QUST_REPLICA_AUTH, QUST_CLUSTER, QUST_CLUSTER_NODE, QUST_CLUSTER_AUTH,",
+            "    QUST_SHARDS, QUST_IO_THREADS, QUST_REPLY_HIGH_WATERMARK",
+            "        Override the configuration file. The options override them.",
+            "",
         ]
         .join("\n")
     );
@@ -67,63 +152,151 @@ macro_rules! show_help {
     };
 }
 
-fn main() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
-        .format_timestamp_micros()
-        .format_module_path(false)
-        .init();
+fn value(args: &mut Args, name: &str) -> String {
+    match args.next() {
+        Some(arg) => {
+            show_help!(arg);
+            arg
+        }
+        None => {
+            println!("error: Not found {0}. Please you set a {0}.", name);
+            show_help_mini();
+            exit(1);
+        }
+    }
+}
+
+fn parse<T: FromStr>(args: &mut Args, name: &str) -> T
+where
+    T::Err: std::fmt::Display,
+{
+    match value(args, name).parse() {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            show_help_mini();
+            exit(1);
+        }
+    }
+}
 
-    let mut host = HOST.to_owned();
-    let mut port = PORT.to_owned();
+fn main() {
+    let mut opts = ConfigFile::default();
+    let mut config_path = env::var_os("QUST_CONFIG").map(PathBuf::from);
+    let mut evict_queues = Vec::new();
 
     let mut args = args();
     // skip arg[0]
     args.next();
-    loop {
-        match args.next() {
-            Some(arg) => {
-                show_help!(arg);
-                if arg == "-h" || arg == "--host" {
-                    match args.next() {
-                        Some(arg) => {
-                            show_help!(arg);
-                            host = arg.to_owned()
-                        }
-                        None => {
-                            println!("error: Not found host. Please you set a host.");
-                            show_help_mini();
-                            exit(1);
-                        }
-                    }
-                } else if arg == "-p" || arg == "--port" {
-                    match args.next() {
-                        Some(arg) => {
-                            show_help!(arg);
-                            port = arg.to_owned()
-                        }
-                        None => {
-                            println!("error: Not found port. Please you set a port.");
-                            show_help_mini();
-                            exit(1);
-                        }
-                    }
-                }
-            }
-            None => break,
+    while let Some(arg) = args.next() {
+        show_help!(arg);
+        if arg == "-h" || arg == "--host" {
+            opts.host = Some(value(&mut args, "host"));
+        } else if arg == "-p" || arg == "--port" {
+            opts.port = Some(parse(&mut args, "port"));
+        } else if arg == "-s" || arg == "--unix-socket" {
+            opts.unix_socket = Some(PathBuf::from(value(&mut args, "path")));
+        } else if arg == "-b" || arg == "--bind" {
+            opts.bind
+                .get_or_insert_with(Vec::new)
+                .push(value(&mut args, "address"));
+        } else if arg == "--no-tcp" {
+            opts.tcp = Some(false);
+        } else if arg == "--tls-cert" {
+            opts.tls_cert = Some(PathBuf::from(value(&mut args, "path")));
+        } else if arg == "--tls-key" {
+            opts.tls_key = Some(PathBuf::from(value(&mut args, "path")));
+        } else if arg == "--tls-client-ca" {
+            opts.tls_client_ca = Some(PathBuf::from(value(&mut args, "path")));
+        } else if arg == "--auth-token" {
+            opts.auth_tokens
+                .get_or_insert_with(Vec::new)
+                .push(value(&mut args, "token"));
+        } else if arg == "-c" || arg == "--config" {
+            config_path = Some(PathBuf::from(value(&mut args, "path")));
+        } else if arg == "--buffer-size" {
+            opts.buffer_size = Some(parse(&mut args, "size"));
+        } else if arg == "--max-buffer-size" {
+            opts.max_buffer_size = Some(parse(&mut args, "size"));
+        } else if arg == "--reply-high-watermark" {
+            opts.reply_high_watermark = Some(parse(&mut args, "size"));
+        } else if arg == "--max-memory" {
+            opts.max_memory = Some(parse(&mut args, "size"));
+        } else if arg == "--evict-queue" {
+            evict_queues.push(value(&mut args, "queue").into_bytes());
+        } else if arg == "--persist" {
+            opts.persist = Some(PathBuf::from(value(&mut args, "path")));
+        } else if arg == "--shutdown-timeout" {
+            opts.shutdown_timeout = Some(parse(&mut args, "seconds"));
+        } else if arg == "--max-connections" {
+            opts.max_connections = Some(parse(&mut args, "number"));
+        } else if arg == "--idle-timeout" {
+            opts.idle_timeout = Some(parse(&mut args, "seconds"));
+        } else if arg == "--reply-timeout" {
+            opts.reply_timeout = Some(parse(&mut args, "seconds"));
+        } else if arg == "--requeue-on-disconnect" {
+            opts.requeue_on_disconnect = Some(true);
+        } else if arg == "--replica-of" {
+            opts.replica_of = Some(value(&mut args, "address"));
+        } else if arg == "--replica-auth" {
+            opts.replica_auth = Some(value(&mut args, "token"));
+        } else if arg == "--cluster" {
+            opts.cluster
+                .get_or_insert_with(Vec::new)
+                .push(value(&mut args, "address"));
+        } else if arg == "--cluster-node" {
+            opts.cluster_node = Some(parse(&mut args, "index"));
+        } else if arg == "--cluster-auth" {
+            opts.cluster_auth = Some(value(&mut args, "token"));
+        } else if arg == "--shards" {
+            opts.shards = Some(parse(&mut args, "number"));
+        } else if arg == "--io-threads" {
+            opts.io_threads = Some(parse(&mut args, "number"));
         }
     }
 
-    let addr = match [host.as_str(), port.as_str()].join(":").parse() {
-        Ok(addr) => addr,
+    // The settings are overridden in order of the configuration file,
+    // the environment variables and the command line arguments.
+    let mut config = Config {
+        log_level: match env::var("RUST_LOG") {
+            // RUST_LOG may have filters per module, which are left to env_logger.
+            Ok(s) => s.parse().unwrap_or(LevelFilter::Trace),
+            Err(_) => LevelFilter::Info,
+        },
+        ..Config::default()
+    };
+    if let Some(path) = config_path {
+        if let Err(e) = config.load(&path) {
+            eprintln!("error: {}: {}", path.display(), e);
+            exit(1);
+        }
+    }
+    let env = match ConfigFile::from_env() {
+        Ok(env) => env,
         Err(e) => {
             eprintln!("error: {}", e);
-            show_help_mini();
             exit(1);
         }
     };
+    // They are applied again when the configuration file is reloaded.
+    config.overrides = vec![env, opts];
+    config.evict_queues = evict_queues;
+    config.apply_overrides();
+
+    env_logger::Builder::from_env(Env::default().default_filter_or("trace"))
+        .format_timestamp_micros()
+        .format_module_path(false)
+        .init();
+    // The level can be changed by reloading the configuration file.
+    log::set_max_level(config.log_level);
 
-    info!("You can connect to the server using `nc`:");
-    info!(" $ nc {}", addr);
-    info!("You'll see our welcome message and anything you type we'll be printed here.");
-    Server::run(addr).unwrap();
+    if config.tcp {
+        info!("You can connect to the server using `nc`:");
+        info!(" $ nc {} {}", config.host, config.port);
+        info!("You'll see our welcome message and anything you type we'll be printed here.");
+    }
+    if let Err(e) = Server::run(config) {
+        eprintln!("error: {}", e);
+        exit(1);
+    }
 }
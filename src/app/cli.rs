@@ -1,19 +1,31 @@
 use interaction::{Interaction, InteractionBuilder};
 use qust::command;
-use qust::command::ENABLE_COMMANDS;
+use qust::command::{Command, ENABLE_COMMANDS};
+#[cfg(feature = "tls")]
+use qust::tls;
+#[cfg(feature = "tls")]
+use rustls::ClientConfig;
 use std::env;
 use std::env::args;
+use std::fs::File;
 use std::io;
-use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpStream};
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, Cursor, IsTerminal, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::str::from_utf8;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
 
 const HOST: &str = "127.0.0.1";
 const PORT: &str = "9000";
 const HISTORY_SIZE: usize = 3000;
 
+// The exit codes of the commands run without the prompt, by the worst reply.
+const EXIT_OK: i32 = 0;
+const EXIT_EMPTY: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+
 fn show_help() {
     println!(
         "{}",
@@ -25,12 +37,34 @@ fn show_help() {
             "",
             "USAGE:",
             format!("    {} -h {} -p {}", env!("CARGO_BIN_NAME"), HOST, PORT).as_str(),
+            format!("    {} [OPTIONS] <command> [<arguments>...]", env!("CARGO_BIN_NAME")).as_str(),
+            format!("    {} [OPTIONS] -f <path>", env!("CARGO_BIN_NAME")).as_str(),
+            "",
+            "ARGS:",
+            "    <command> [<arguments>...]",
+            "        Run the command and exit, e.g. ADDJOB <queue name> <retry> <job>.",
             "",
             "OPTIONS:",
             "    -h, --host <host>",
             format!("        Set a host. Default: {}", HOST).as_str(),
             "    -p, --port <port>",
             format!("        Set a port. Default: {}", PORT).as_str(),
+            "    -a, --auth <token>",
+            "        Authenticate connections with the token.",
+            "    --tls-ca <path>",
+            "        Connect with TLS, verifying the server by the CA certificates (PEM).",
+            "        Requires the `tls` feature.",
+            "    --tls-cert <path>, --tls-key <path>",
+            "        Present the client certificate chain and the private key (PEM).",
+            "    --tls-server-name <name>",
+            "        Set a name to verify the server certificate. Default: the host",
+            "    -f, --file <path>",
+            "        Run the commands in the file line by line and exit. `-` reads the standard input,",
+            "        which is also read when it is not a terminal.",
+            "    -o, --output <format>",
+            "        Show the replies as `pretty`, `raw` (`<status> <data>`) or `json`",
+            "        (`{\"status\": <status>, \"data\": <data>}`).",
+            "        Default: pretty with the prompt, raw otherwise",
             "    --history-size <size>",
             format!("        Set a size of history. Default: {}", HISTORY_SIZE).as_str(),
             "    --help",
@@ -38,6 +72,15 @@ fn show_help() {
             "    --version",
             "        Prints version information.",
             "",
+            "EXIT STATUS:",
+            format!("    {}  Every reply has the status 1.", EXIT_OK).as_str(),
+            format!("    {}  A reply has the status 0, e.g. GETJOB without jobs.", EXIT_EMPTY).as_str(),
+            format!(
+                "    {}  A reply has the status -1, a command is invalid or the server is unreachable.",
+                EXIT_ERROR
+            )
+            .as_str(),
+            "",
         ]
         .join("\n")
     );
@@ -74,10 +117,197 @@ macro_rules! show_help {
     };
 }
 
+fn path(args: &mut env::Args) -> PathBuf {
+    match args.next() {
+        Some(arg) => {
+            show_help!(arg);
+            PathBuf::from(arg)
+        }
+        None => {
+            println!("error: Not found path. Please you set a path.");
+            show_help_mini();
+            exit(1);
+        }
+    }
+}
+
+trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "closed by the server")
+}
+
+// Read bytes until the delimiter, which is removed.
+fn read_until<R: BufRead>(reader: &mut R, delimiter: u8) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.read_until(delimiter, &mut buf)?;
+    match buf.pop() {
+        Some(b) if b == delimiter => Ok(buf),
+        _ => Err(closed()),
+    }
+}
+
+// Escape the bytes as a JSON string.
+fn json_string(bytes: &[u8]) -> String {
+    let mut s = String::from("\"");
+    for c in String::from_utf8_lossy(bytes).chars() {
+        match c {
+            '"' => s.push_str("\\\""),
+            '\\' => s.push_str("\\\\"),
+            '\n' => s.push_str("\\n"),
+            '\r' => s.push_str("\\r"),
+            '\t' => s.push_str("\\t"),
+            c if (c as u32) < 0x20 => s.push_str(&format!("\\u{:04x}", c as u32)),
+            c => s.push(c),
+        }
+    }
+    s.push('"');
+    s
+}
+
+// Parse `<command> <arguments>` into the command and the request to send.
+fn parse(input: &[u8]) -> Option<(Command, Vec<u8>)> {
+    let mut iter = input.splitn(2, |c| *c == b' ');
+    let cmd = iter
+        .next()
+        .and_then(|cmd| from_utf8(cmd).ok())
+        .and_then(|cmd| Command::from(cmd.to_uppercase().as_bytes()))?;
+    let request = iter
+        .next()
+        .map(|tail| [cmd.as_str(), b" ", tail, b"\n"].concat())
+        .unwrap_or([cmd.as_str(), b"\n"].concat());
+    Some((cmd, request))
+}
+
+fn show_commands() {
+    eprintln!("Invalid the command. In this version, the follow is a enable command.");
+    for cmd in ENABLE_COMMANDS.iter() {
+        eprintln!("* {}", from_utf8(cmd.as_str()).unwrap());
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Output {
+    Pretty,
+    Raw,
+    Json,
+}
+
+impl Output {
+    fn from(value: &str) -> Option<Output> {
+        match value {
+            "pretty" => Some(Output::Pretty),
+            "raw" => Some(Output::Raw),
+            "json" => Some(Output::Json),
+            _ => None,
+        }
+    }
+
+    fn show(self, cmd: Command, reply: &Reply) {
+        match self {
+            Output::Pretty => println!("{}", reply.pretty(cmd)),
+            Output::Raw => {
+                let mut stdout = io::stdout().lock();
+                let _ = stdout
+                    .write_all(
+                        &[reply.status.as_slice(), b" ", reply.data.as_slice(), b"\n"].concat(),
+                    )
+                    .and_then(|_| stdout.flush());
+            }
+            Output::Json => {
+                let status = from_utf8(&reply.status)
+                    .ok()
+                    .and_then(|s| s.parse::<i8>().ok())
+                    .map(|status| status.to_string())
+                    .unwrap_or_else(|| json_string(&reply.status));
+                println!(
+                    "{{\"status\": {}, \"data\": {}}}",
+                    status,
+                    json_string(&reply.data)
+                );
+            }
+        }
+    }
+}
+
+// A reply of `<status> <data>\n`.
+struct Reply {
+    status: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl Reply {
+    fn exit_code(&self) -> i32 {
+        match self.status.as_slice() {
+            b"1" | b"2" => EXIT_OK,
+            b"0" => EXIT_EMPTY,
+            _ => EXIT_ERROR,
+        }
+    }
+
+    // Show the reply to a person, with the fields named for the command.
+    fn pretty(&self, cmd: Command) -> String {
+        let data = String::from_utf8_lossy(&self.data);
+        match self.status.as_slice() {
+            b"-1" => return format!("(error) {}", data),
+            b"0" if data.is_empty() => return String::from("(empty)"),
+            b"1" if data.is_empty() => return String::from("OK"),
+            b"0" | b"1" => {}
+            _ => return format!("{} {}", String::from_utf8_lossy(&self.status), data),
+        }
+        let fields: Vec<&str> = data.split(' ').collect();
+        let named = |names: &[&str]| {
+            names
+                .iter()
+                .zip(fields.iter())
+                .map(|(name, field)| format!("{}: {}", name, field))
+                .collect::<Vec<String>>()
+                .join("\n")
+        };
+        match cmd {
+            Command::GETJOB => match data.split_once(' ') {
+                Some((id, job)) => format!("id: {}\njob: {}", id, job),
+                None => data.into_owned(),
+            },
+            Command::ACKJOB if fields.len() > 1 => format!(
+                "acknowledged: {}\nunknown: {}",
+                fields[0],
+                fields[1..].join(" ")
+            ),
+            Command::STATQUE if fields.len() == 4 => {
+                named(&["jobs", "running", "acknowledged", "deleted"])
+            }
+            Command::CLIENT => data.replace("; ", "\n"),
+            _ => data.into_owned(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct TlsOptions {
+    ca: Option<PathBuf>,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    server_name: Option<String>,
+}
+
 struct App {
     history_file: PathBuf,
     history_limit: usize,
-    addr: SocketAddr,
+    // `<host>:<port>` shown in the prompt.
+    addr: String,
+    addrs: Vec<SocketAddr>,
+    auth: Option<String>,
+    #[cfg(feature = "tls")]
+    tls: Option<(Arc<ClientConfig>, String)>,
+    // Kept open over the commands.
+    stream: Option<BufReader<Box<dyn Stream>>>,
+    // Run without the prompt.
+    command: Option<Vec<u8>>,
+    file: Option<PathBuf>,
+    output: Option<Output>,
 }
 
 impl App {
@@ -90,91 +320,278 @@ impl App {
             .iter()
             .collect(),
             history_limit: 3000,
-            addr: format!("{}:{}", HOST, PORT).parse().unwrap(),
+            addr: format!("{}:{}", HOST, PORT),
+            addrs: Vec::new(),
+            auth: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            stream: None,
+            command: None,
+            file: None,
+            output: None,
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    fn configure_tls(&mut self, host: String, opts: TlsOptions) {
+        let ca = match opts.ca {
+            Some(ca) => ca,
+            None => return,
+        };
+        let cert_key = match (opts.cert.as_ref(), opts.key.as_ref()) {
+            (Some(cert), Some(key)) => Some((cert.as_path(), key.as_path())),
+            (None, None) => None,
+            _ => {
+                eprintln!("error: Both of --tls-cert and --tls-key are required.");
+                exit(1);
+            }
+        };
+        match tls::client_config(&ca, cert_key) {
+            Ok(config) => self.tls = Some((config, opts.server_name.unwrap_or(host))),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                exit(1);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn configure_tls(&mut self, _host: String, opts: TlsOptions) {
+        if opts.ca.is_some() || opts.cert.is_some() || opts.key.is_some() {
+            eprintln!("error: TLS is not supported. Build with the `tls` feature.");
+            exit(1);
+        }
+    }
+
+    fn connect(&self) -> io::Result<Box<dyn Stream>> {
+        let stream = TcpStream::connect(self.addrs.as_slice())?;
+        #[cfg(feature = "tls")]
+        let stream: Box<dyn Stream> = match self.tls.as_ref() {
+            Some((config, server_name)) => {
+                Box::new(tls::connect(config.clone(), server_name, stream)?)
+            }
+            None => Box::new(stream),
+        };
+        #[cfg(not(feature = "tls"))]
+        let stream: Box<dyn Stream> = Box::new(stream);
+        match self.auth.as_ref() {
+            Some(token) => App::authenticate(stream, token),
+            None => Ok(stream),
+        }
+    }
+
+    fn authenticate(mut stream: Box<dyn Stream>, token: &str) -> io::Result<Box<dyn Stream>> {
+        stream.write_all(format!("AUTH {}\n", token).as_bytes())?;
+        let mut reply = Vec::new();
+        let mut buf = [0; 64];
+        while reply.last() != Some(&b'\n') {
+            match stream.read(&mut buf)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => reply.extend(&buf[..n]),
+            }
+        }
+        match reply.starts_with(b"1 ") {
+            true => Ok(stream),
+            false => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                String::from_utf8_lossy(&reply).trim_end().to_owned(),
+            )),
         }
     }
 
     fn arg_parse(mut self) -> Self {
         let mut host = HOST.to_owned();
         let mut port = PORT.to_owned();
-        let mut history_size = HISTORY_SIZE.clone();
+        let mut history_size = HISTORY_SIZE;
+        let mut tls_opts = TlsOptions::default();
 
         let mut args = args();
         // skip arg[0]
         args.next();
-        loop {
-            match args.next() {
-                Some(arg) => {
-                    show_help!(arg);
-                    if arg == "-h" || arg == "--host" {
-                        match args.next() {
-                            Some(arg) => {
-                                show_help!(arg);
-                                host = arg.to_owned()
-                            }
-                            None => {
-                                println!("error: Not found host. Please you set a host.");
-                                show_help_mini();
-                                exit(1);
-                            }
-                        }
-                    } else if arg == "-p" || arg == "--port" {
-                        match args.next() {
-                            Some(arg) => {
-                                show_help!(arg);
-                                port = arg.to_owned()
-                            }
+        while let Some(arg) = args.next() {
+            show_help!(arg);
+            if arg == "-h" || arg == "--host" {
+                match args.next() {
+                    Some(arg) => {
+                        show_help!(arg);
+                        host = arg.to_owned()
+                    }
+                    None => {
+                        println!("error: Not found host. Please you set a host.");
+                        show_help_mini();
+                        exit(1);
+                    }
+                }
+            } else if arg == "-p" || arg == "--port" {
+                match args.next() {
+                    Some(arg) => {
+                        show_help!(arg);
+                        port = arg.to_owned()
+                    }
+                    None => {
+                        println!("error: Not found port. Please you set a port.");
+                        show_help_mini();
+                        exit(1);
+                    }
+                }
+            } else if arg == "-a" || arg == "--auth" {
+                match args.next() {
+                    Some(arg) => {
+                        show_help!(arg);
+                        self.auth = Some(arg)
+                    }
+                    None => {
+                        println!("error: Not found token. Please you set a token.");
+                        show_help_mini();
+                        exit(1);
+                    }
+                }
+            } else if arg == "--tls-ca" {
+                tls_opts.ca = Some(path(&mut args));
+            } else if arg == "--tls-cert" {
+                tls_opts.cert = Some(path(&mut args));
+            } else if arg == "--tls-key" {
+                tls_opts.key = Some(path(&mut args));
+            } else if arg == "--tls-server-name" {
+                match args.next() {
+                    Some(arg) => {
+                        show_help!(arg);
+                        tls_opts.server_name = Some(arg)
+                    }
+                    None => {
+                        println!("error: Not found name. Please you set a server name.");
+                        show_help_mini();
+                        exit(1);
+                    }
+                }
+            } else if arg == "-f" || arg == "--file" {
+                self.file = Some(path(&mut args));
+            } else if arg == "-o" || arg == "--output" {
+                match args.next() {
+                    Some(arg) => {
+                        show_help!(arg);
+                        self.output = match Output::from(&arg) {
+                            Some(output) => Some(output),
                             None => {
-                                println!("error: Not found port. Please you set a port.");
+                                eprintln!("error: Unknown output format: {}", arg);
                                 show_help_mini();
                                 exit(1);
                             }
                         }
-                    } else if arg == "--history-size" {
-                        match args.next() {
-                            Some(arg) => {
-                                show_help!(arg);
-                                history_size = match arg.parse() {
-                                    Ok(s) => s,
-                                    Err(e) => {
-                                        eprintln!("error: {}", e);
-                                        show_help();
-                                        exit(1);
-                                    }
-                                }
-                            }
-                            None => {
-                                println!(
-                                    "error: Not found size. Please you set a size of history."
-                                );
-                                show_help_mini();
+                    }
+                    None => {
+                        println!("error: Not found format. Please you set an output format.");
+                        show_help_mini();
+                        exit(1);
+                    }
+                }
+            } else if arg == "--history-size" {
+                match args.next() {
+                    Some(arg) => {
+                        show_help!(arg);
+                        history_size = match arg.parse() {
+                            Ok(s) => s,
+                            Err(e) => {
+                                eprintln!("error: {}", e);
+                                show_help();
                                 exit(1);
                             }
                         }
                     }
+                    None => {
+                        println!("error: Not found size. Please you set a size of history.");
+                        show_help_mini();
+                        exit(1);
+                    }
                 }
-                None => break,
+            } else if !arg.starts_with('-') {
+                // The rest of the arguments are the command.
+                let command: Vec<String> = Some(arg).into_iter().chain(args.by_ref()).collect();
+                self.command = Some(command.join(" ").into_bytes());
             }
         }
 
-        self.addr = match [host.as_str(), port.as_str()].join(":").parse() {
-            Ok(addr) => addr,
+        let addrs = port
+            .parse::<u16>()
+            .map_err(|e| e.to_string())
+            .and_then(|port| {
+                (host.as_str(), port)
+                    .to_socket_addrs()
+                    .map_err(|e| e.to_string())
+            });
+        self.addrs = match addrs {
+            Ok(addrs) => addrs.collect(),
             Err(e) => {
                 eprintln!("error: {}", e);
                 show_help_mini();
                 exit(1);
             }
         };
+        self.addr = format!("{}:{}", host, port);
         self.history_limit = history_size;
+        self.configure_tls(host, tls_opts);
         self
     }
 
+    // Send the request over the kept connection and read the reply. When the server
+    // has closed it, e.g. by the idle timeout, before replying, the request is sent
+    // again over a new connection.
+    fn request(&mut self, cmd: Command, request: &[u8]) -> io::Result<Reply> {
+        // Only a request which could not be written is sent again, since the server
+        // may have run one which it has not replied to.
+        if self.stream.is_some() && self.send(request).is_err() {
+            self.stream = None;
+        }
+        if self.stream.is_none() {
+            self.stream = Some(BufReader::new(self.connect()?));
+            if let Err(err) = self.send(request) {
+                self.stream = None;
+                return Err(err);
+            }
+        }
+        let reply = self.receive(cmd);
+        // Which part of the reply is left is unknown after an error, and the changes
+        // of the queues are pushed after the reply to SYNC.
+        if reply.is_err() || cmd == Command::SYNC {
+            self.stream = None;
+        }
+        reply
+    }
+
+    fn send(&mut self, request: &[u8]) -> io::Result<()> {
+        let stream = self.stream.as_mut().unwrap().get_mut();
+        stream.write_all(request)?;
+        stream.flush()
+    }
+
+    fn receive(&mut self, cmd: Command) -> io::Result<Reply> {
+        let reader = self.stream.as_mut().unwrap();
+        let status = read_until(reader, b' ')?;
+        let data = match (cmd, status.as_slice()) {
+            // `1 <size> <snapshot>\n`, in which the snapshot has line feeds.
+            (Command::SYNC, b"1") => {
+                let size: usize = from_utf8(&read_until(reader, b' ')?)
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid size"))?;
+                let mut data = vec![0; size + 1];
+                reader.read_exact(&mut data)?;
+                match data.pop() {
+                    Some(b'\n') => data,
+                    _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid size")),
+                }
+            }
+            _ => read_until(reader, b'\n')?,
+        };
+        Ok(Reply { status, data })
+    }
+
     fn interaction(&self) -> Interaction {
         InteractionBuilder::new()
             .prompt(format!("{} >", &self.addr).as_bytes())
             .history_limit(self.history_limit)
             .completion(|input, completions| {
-                if input.len() == 0 {
+                if input.is_empty() {
                     for cmd in ENABLE_COMMANDS.iter() {
                         completions.push(cmd.as_str().to_vec());
                     }
@@ -189,53 +606,29 @@ impl App {
             .build()
     }
 
-    fn run(self) {
+    fn interact(&mut self) {
         let mut inter = self.interaction();
         'input: loop {
             match inter.line() {
                 Ok(input) => {
-                    if input.len() == 0 {
+                    if input.is_empty() {
                         continue 'input;
                     }
-                    let mut iter = input.splitn(2, |c| *c == b' ');
-                    let cmd = match iter
-                        .next()
-                        .and_then(|cmd| from_utf8(cmd).ok())
-                        .and_then(|cmd| command::Command::from(cmd.to_uppercase().as_bytes()))
-                    {
-                        Some(cmd) => cmd,
+                    let (cmd, request) = match parse(&input) {
+                        Some(parsed) => parsed,
                         None => {
-                            eprintln!("Invalid the command. In this version, the follow is a enable command.");
-                            for cmd in ENABLE_COMMANDS.iter() {
-                                eprintln!("* {}", from_utf8(cmd.as_str()).unwrap());
-                            }
+                            show_commands();
                             continue 'input;
                         }
                     };
 
-                    if cmd == command::Command::QUIT {
+                    if cmd == Command::QUIT {
                         break 'input;
                     }
 
-                    let mut stream = TcpStream::connect("127.0.0.1:9000").unwrap();
-                    let request = iter
-                        .next()
-                        .and_then(|tail| Some([cmd.as_str(), b" ", tail, b"\n"].concat()))
-                        .unwrap_or([cmd.as_str(), b"\n"].concat());
-                    if let Err(e) = stream.write(request.as_slice()) {
-                        eprintln!("Error: {}", e);
-                        continue 'input;
-                    }
-                    let mut buf = vec![0; 4096];
-                    match stream.read(&mut buf) {
-                        Ok(n) => {
-                            // TODO
-                            print!("{}", from_utf8(&buf[..n]).unwrap());
-                        }
-                        Err(e) => {
-                            eprintln!("Error: {}", e);
-                            continue 'input;
-                        }
+                    match self.request(cmd, &request) {
+                        Ok(reply) => self.output.unwrap_or(Output::Pretty).show(cmd, &reply),
+                        Err(e) => eprintln!("Error: {}", e),
                     }
                 }
                 Err(e) if e.kind() == io::ErrorKind::Interrupted => {
@@ -248,6 +641,66 @@ impl App {
             }
         }
     }
+
+    // Run the command given by the arguments, or the commands read line by line, and
+    // return the exit code by the worst reply. It stops at the first connection error.
+    fn batch(&mut self) -> i32 {
+        let input: Box<dyn BufRead> = match (self.command.take(), self.file.take()) {
+            (Some(command), _) => Box::new(Cursor::new(command)),
+            (None, Some(path)) if path != Path::new("-") => match File::open(&path) {
+                Ok(file) => Box::new(BufReader::new(file)),
+                Err(e) => {
+                    eprintln!("error: {}: {}", path.display(), e);
+                    return EXIT_ERROR;
+                }
+            },
+            _ => Box::new(io::stdin().lock()),
+        };
+        let output = self.output.unwrap_or(Output::Raw);
+        let mut code = EXIT_OK;
+        for line in input.split(b'\n') {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return EXIT_ERROR;
+                }
+            };
+            let line = line.strip_suffix(b"\r").unwrap_or(&line);
+            if line.is_empty() {
+                continue;
+            }
+            let (cmd, request) = match parse(line) {
+                Some(parsed) => parsed,
+                None => {
+                    show_commands();
+                    code = EXIT_ERROR;
+                    continue;
+                }
+            };
+            if cmd == Command::QUIT {
+                break;
+            }
+            match self.request(cmd, &request) {
+                Ok(reply) => {
+                    output.show(cmd, &reply);
+                    code = code.max(reply.exit_code());
+                }
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    return EXIT_ERROR;
+                }
+            }
+        }
+        code
+    }
+
+    fn run(mut self) {
+        if self.command.is_some() || self.file.is_some() || !io::stdin().is_terminal() {
+            exit(self.batch());
+        }
+        self.interact();
+    }
 }
 
 fn main() {
@@ -0,0 +1,693 @@
+use crate::command::Command;
+use crate::config::Config;
+use crate::message::{Request, TERMINATION};
+use crate::utils::is_delimiter;
+use mio::Token;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::{from_utf8, FromStr};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+// The election timeout is chosen at random between them (milliseconds).
+const ELECTION_TIMEOUT_MIN: u64 = 300;
+const ELECTION_TIMEOUT_MAX: u64 = 600;
+// How long to wait for a peer to accept the connection and to reply.
+const PEER_TIMEOUT: Duration = Duration::from_millis(500);
+// Upper bound of the entries sent by an APPEND message.
+const MAX_ENTRIES: usize = 256;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+struct Entry {
+    term: u64,
+    // A command for the queue manager. Empty for the entry added by a new leader.
+    data: Vec<u8>,
+}
+
+// The Raft consensus among the nodes given by `cluster`. The queue manager proposes
+// the commands changing the queues, and applies them once they are committed.
+//
+// Messages are sent by `RAFT <message>` to the client port of the peers:
+//   VOTE <term> <candidate> <last log index> <last log term>
+//     -> <term> <granted: 0 or 1>
+//   APPEND <term> <leader> <prev log index> <prev log term> <commit index> <term>:<hex entry> ...
+//     -> <term> <success: 0 or 1> <last index matched, or the index to retry from>
+//
+// The term, the vote and the log are appended to the `persist` file, and synced before
+// the node replies or sends a message, so a restarted node keeps its promises:
+//   TERM <term> <voted for, or ->
+//   ENTRY <index> <term> <hex entry>
+// An entry replaces the one of the same index and the entries after it.
+pub(crate) struct Cluster {
+    id: usize,
+    nodes: Vec<String>,
+    // Channels to the threads sending the messages, `None` for this node.
+    peers: Vec<Option<Sender<Vec<u8>>>>,
+    role: Role,
+    term: u64,
+    voted_for: Option<usize>,
+    leader: Option<usize>,
+    votes: Vec<bool>,
+    // The index of `log[i]` is `i + 1`.
+    log: Vec<Entry>,
+    commit: usize,
+    applied: usize,
+    next_index: Vec<usize>,
+    match_index: Vec<usize>,
+    // The number of messages sent to each peer and not answered yet.
+    in_flight: Vec<usize>,
+    // The last time each peer answered the leader.
+    acked: Vec<Instant>,
+    // When to start an election, or to send heartbeats as the leader.
+    deadline: Instant,
+    path: PathBuf,
+    file: File,
+    // The records which are not written to `file` yet.
+    unsynced: Vec<u8>,
+}
+
+fn election_timeout() -> Duration {
+    let range = (ELECTION_TIMEOUT_MAX - ELECTION_TIMEOUT_MIN) as u128;
+    Duration::from_millis(ELECTION_TIMEOUT_MIN + (Uuid::new_v4().as_u128() % range) as u64)
+}
+
+fn parse<T: FromStr>(buf: Option<&[u8]>) -> Option<T> {
+    buf.and_then(|buf| from_utf8(buf).ok())
+        .and_then(|s| s.parse().ok())
+}
+
+fn encode(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode(hex: &[u8]) -> Option<Vec<u8>> {
+    let chunks = hex.chunks_exact(2);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+    chunks
+        .map(|c| {
+            from_utf8(c)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok())
+        })
+        .collect()
+}
+
+impl Cluster {
+    pub(crate) fn new(config: &Config, sender: &Sender<Box<Request>>) -> io::Result<Cluster> {
+        let n = config.cluster.len();
+        let id = config.cluster_node;
+        if id >= n {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cluster_node must be an index of cluster",
+            ));
+        }
+        let path = config.persist.clone().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "persist is required in the cluster mode",
+            )
+        })?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let (term, voted_for, log) = load(&mut file, &path, n)?;
+        if !log.is_empty() || term > 0 {
+            info!(
+                "Loaded the term {} and {} entries from {}",
+                term,
+                log.len(),
+                path.display()
+            );
+        }
+        let peers = (0..n)
+            .map(|i| {
+                if i == id {
+                    return None;
+                }
+                let (tx, rx) = channel();
+                let addr = config.cluster[i].clone();
+                let auth = config.cluster_auth.clone();
+                let sender = sender.clone();
+                thread::spawn(move || peer(i, &addr, auth.as_deref(), rx, sender));
+                Some(tx)
+            })
+            .collect();
+        let now = Instant::now();
+        Ok(Cluster {
+            id,
+            nodes: config.cluster.clone(),
+            peers,
+            role: Role::Follower,
+            term,
+            voted_for,
+            leader: None,
+            votes: vec![false; n],
+            log,
+            commit: 0,
+            applied: 0,
+            next_index: vec![1; n],
+            match_index: vec![0; n],
+            in_flight: vec![0; n],
+            acked: vec![now; n],
+            deadline: now + election_timeout(),
+            path,
+            file,
+            unsynced: Vec::new(),
+        })
+    }
+
+    // Record the term and the vote, which are written by `sync`.
+    fn save_term(&mut self) {
+        let voted_for = self
+            .voted_for
+            .map_or_else(|| "-".to_owned(), |v| v.to_string());
+        self.unsynced
+            .extend(format!("TERM {} {}\n", self.term, voted_for).as_bytes());
+    }
+
+    // Record the entries from the index.
+    fn save_entries(&mut self, from: usize) {
+        for index in from..=self.log.len() {
+            let entry = &self.log[index - 1];
+            self.unsynced.extend(
+                format!("ENTRY {} {} {}\n", index, entry.term, encode(&entry.data)).as_bytes(),
+            );
+        }
+    }
+
+    // Write the records to the disk. False if they are not, so the node should not
+    // tell anything depending on them.
+    fn sync(&mut self) -> bool {
+        if self.unsynced.is_empty() {
+            return true;
+        }
+        let res = self
+            .file
+            .write_all(&self.unsynced)
+            .and_then(|_| self.file.sync_data());
+        match res {
+            Ok(_) => {
+                self.unsynced.clear();
+                true
+            }
+            Err(err) => {
+                error!("Failed to write {}: {}", self.path.display(), err);
+                false
+            }
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    // The address of the leader, if another node is.
+    pub(crate) fn leader(&self) -> Option<&str> {
+        self.leader
+            .filter(|leader| *leader != self.id)
+            .map(|leader| self.nodes[leader].as_str())
+    }
+
+    // How long to wait until the next tick.
+    pub(crate) fn timeout(&self) -> Duration {
+        self.deadline.saturating_duration_since(Instant::now())
+    }
+
+    fn last_term(&self) -> u64 {
+        self.log.last().map_or(0, |entry| entry.term)
+    }
+
+    fn term_at(&self, index: usize) -> u64 {
+        match index {
+            0 => 0,
+            _ => self.log[index - 1].term,
+        }
+    }
+
+    fn is_majority(&self, count: usize) -> bool {
+        count * 2 > self.nodes.len()
+    }
+
+    fn send(&mut self, peer: usize, message: Vec<u8>) {
+        if !self.sync() {
+            return;
+        }
+        if let Some(Some(tx)) = self.peers.get(peer) {
+            if tx.send(message).is_ok() {
+                self.in_flight[peer] += 1;
+            }
+        }
+    }
+
+    // Send the entries from `next_index`, or a heartbeat if the peer has all of them.
+    fn send_append(&mut self, peer: usize) {
+        if self.in_flight[peer] > 0 {
+            return;
+        }
+        let prev = self.next_index[peer] - 1;
+        let mut message = format!(
+            "APPEND {} {} {} {} {}",
+            self.term,
+            self.id,
+            prev,
+            self.term_at(prev),
+            self.commit
+        );
+        for entry in self.log[prev..].iter().take(MAX_ENTRIES) {
+            message.push_str(&format!(" {}:{}", entry.term, encode(&entry.data)));
+        }
+        self.send(peer, message.into_bytes());
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<usize>) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.save_term();
+        }
+        if self.role == Role::Leader {
+            info!("Stepped down from the leader of term {}", self.term);
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.deadline = Instant::now() + election_timeout();
+    }
+
+    fn start_election(&mut self) {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(self.id);
+        self.save_term();
+        self.leader = None;
+        self.votes = vec![false; self.nodes.len()];
+        self.votes[self.id] = true;
+        self.deadline = Instant::now() + election_timeout();
+        info!("Started an election for term {}", self.term);
+        let message = format!(
+            "VOTE {} {} {} {}",
+            self.term,
+            self.id,
+            self.log.len(),
+            self.last_term()
+        );
+        for peer in 0..self.nodes.len() {
+            self.send(peer, message.clone().into_bytes());
+        }
+        self.count_votes();
+    }
+
+    fn count_votes(&mut self) {
+        if self.role == Role::Candidate
+            && self.is_majority(self.votes.iter().filter(|v| **v).count())
+        {
+            info!("Elected as the leader of term {}", self.term);
+            let now = Instant::now();
+            self.role = Role::Leader;
+            self.leader = Some(self.id);
+            self.next_index = vec![self.log.len() + 1; self.nodes.len()];
+            self.match_index = vec![0; self.nodes.len()];
+            self.acked = vec![now; self.nodes.len()];
+            // The entries of the former terms are committed along with this one.
+            self.propose(Vec::new());
+            self.deadline = now + HEARTBEAT_INTERVAL;
+        }
+    }
+
+    fn advance_commit(&mut self) {
+        // This node counts only if it has the entries on the disk.
+        if !self.sync() {
+            return;
+        }
+        for index in (self.commit + 1..=self.log.len()).rev() {
+            // Only the entries of the current term are committed by counting.
+            if self.log[index - 1].term != self.term {
+                break;
+            }
+            let count = (0..self.nodes.len())
+                .filter(|i| *i == self.id || self.match_index[*i] >= index)
+                .count();
+            if self.is_majority(count) {
+                self.commit = index;
+                break;
+            }
+        }
+    }
+
+    // Append the command to the log as the leader, and return its index.
+    pub(crate) fn propose(&mut self, data: Vec<u8>) -> usize {
+        self.log.push(Entry {
+            term: self.term,
+            data,
+        });
+        self.save_entries(self.log.len());
+        for peer in 0..self.nodes.len() {
+            if peer != self.id {
+                self.send_append(peer);
+            }
+        }
+        self.advance_commit();
+        self.log.len()
+    }
+
+    // Start an election, or send heartbeats as the leader.
+    pub(crate) fn tick(&mut self) {
+        let now = Instant::now();
+        if now < self.deadline {
+            return;
+        }
+        if self.role != Role::Leader {
+            return self.start_election();
+        }
+        // Step down if the majority has not answered for a while, so that the clients
+        // are not kept waiting by a leader which has been cut off.
+        let timeout = Duration::from_millis(ELECTION_TIMEOUT_MAX);
+        let alive = (0..self.nodes.len())
+            .filter(|i| *i == self.id || now.saturating_duration_since(self.acked[*i]) < timeout)
+            .count();
+        if !self.is_majority(alive) {
+            warn!("Lost the majority of the cluster");
+            return self.become_follower(self.term, None);
+        }
+        for peer in 0..self.nodes.len() {
+            if peer != self.id {
+                self.send_append(peer);
+            }
+        }
+        self.deadline = now + HEARTBEAT_INTERVAL;
+    }
+
+    // Handle a message from another node, and return the reply once the changes
+    // are on the disk.
+    pub(crate) fn handle_message(&mut self, arg: &[u8]) -> Option<Vec<u8>> {
+        let reply = self.receive(arg);
+        match self.sync() {
+            true => reply,
+            false => None,
+        }
+    }
+
+    fn receive(&mut self, arg: &[u8]) -> Option<Vec<u8>> {
+        let mut iter = arg.split(is_delimiter);
+        let kind = iter.next()?;
+        let term: u64 = parse(iter.next())?;
+        match kind {
+            b"VOTE" => {
+                let candidate: usize = parse(iter.next())?;
+                let last_index: usize = parse(iter.next())?;
+                let last_term: u64 = parse(iter.next())?;
+                if candidate >= self.nodes.len() {
+                    return None;
+                }
+                if term > self.term {
+                    self.become_follower(term, None);
+                }
+                let up_to_date = last_term > self.last_term()
+                    || (last_term == self.last_term() && last_index >= self.log.len());
+                let granted = term == self.term
+                    && up_to_date
+                    && match self.voted_for {
+                        Some(v) => v == candidate,
+                        None => true,
+                    };
+                if granted {
+                    if self.voted_for.is_none() {
+                        self.voted_for = Some(candidate);
+                        self.save_term();
+                    }
+                    self.deadline = Instant::now() + election_timeout();
+                }
+                Some(format!("{} {}", self.term, granted as u8).into_bytes())
+            }
+            b"APPEND" => {
+                let leader: usize = parse(iter.next())?;
+                let prev: usize = parse(iter.next())?;
+                let prev_term: u64 = parse(iter.next())?;
+                let commit: usize = parse(iter.next())?;
+                if leader >= self.nodes.len() {
+                    return None;
+                }
+                if term < self.term {
+                    return Some(format!("{} 0 0", self.term).into_bytes());
+                }
+                self.become_follower(term, Some(leader));
+                if prev > self.log.len() {
+                    return Some(format!("{} 0 {}", self.term, self.log.len()).into_bytes());
+                }
+                if self.term_at(prev) != prev_term {
+                    return Some(
+                        format!("{} 0 {}", self.term, prev.saturating_sub(1)).into_bytes(),
+                    );
+                }
+                let mut index = prev;
+                let mut changed = None;
+                for entry in iter.filter(|s| !s.is_empty()) {
+                    let mut parts = entry.splitn(2, |b| *b == b':');
+                    let term: u64 = parse(parts.next())?;
+                    let data = decode(parts.next()?)?;
+                    index += 1;
+                    if index <= self.log.len() {
+                        if self.log[index - 1].term == term {
+                            continue;
+                        }
+                        self.log.truncate(index - 1);
+                    }
+                    self.log.push(Entry { term, data });
+                    changed.get_or_insert(index);
+                }
+                if let Some(from) = changed {
+                    self.save_entries(from);
+                }
+                if commit > self.commit {
+                    self.commit = commit.min(index);
+                }
+                Some(format!("{} 1 {}", self.term, index).into_bytes())
+            }
+            _ => None,
+        }
+    }
+
+    // Handle the reply to a message, which is given as `<peer> <kind> <term> <reply>`.
+    pub(crate) fn handle_reply(&mut self, arg: &[u8]) {
+        let mut iter = arg.split(is_delimiter);
+        let (peer, kind, term) = match (
+            parse::<usize>(iter.next()),
+            iter.next(),
+            parse::<u64>(iter.next()),
+        ) {
+            (Some(peer), Some(kind), Some(term)) if peer < self.nodes.len() => (peer, kind, term),
+            _ => return,
+        };
+        self.in_flight[peer] = self.in_flight[peer].saturating_sub(1);
+        let reply_term = match iter.next() {
+            Some(b"FAIL") | None => return,
+            buf => match parse::<u64>(buf) {
+                Some(term) => term,
+                None => return,
+            },
+        };
+        if reply_term > self.term {
+            return self.become_follower(reply_term, None);
+        }
+        // The reply to a message of a former term.
+        if term != self.term {
+            return;
+        }
+        let success = iter.next() == Some(b"1");
+        match kind {
+            b"VOTE" if success => {
+                self.votes[peer] = true;
+                self.count_votes();
+            }
+            b"APPEND" if self.role == Role::Leader => {
+                self.acked[peer] = Instant::now();
+                let index: usize = match parse(iter.next()) {
+                    Some(index) => index,
+                    None => return,
+                };
+                if success {
+                    self.match_index[peer] = index;
+                    self.next_index[peer] = index + 1;
+                    self.advance_commit();
+                } else {
+                    self.next_index[peer] = (index + 1).min(self.next_index[peer] - 1).max(1);
+                }
+                if self.next_index[peer] <= self.log.len() {
+                    self.send_append(peer);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // Take the next committed entry to apply.
+    pub(crate) fn next_committed(&mut self) -> Option<(usize, Vec<u8>)> {
+        if self.applied >= self.commit {
+            return None;
+        }
+        self.applied += 1;
+        Some((self.applied, self.log[self.applied - 1].data.clone()))
+    }
+}
+
+// Read the records of the file, and drop the last one if it is not complete.
+fn load(
+    file: &mut File,
+    path: &Path,
+    nodes: usize,
+) -> io::Result<(u64, Option<usize>, Vec<Entry>)> {
+    let (mut term, mut voted_for, mut log) = (0, None, Vec::new());
+    let mut reader = BufReader::new(&mut *file);
+    let mut line = Vec::new();
+    let mut size = 0;
+    let invalid = |size| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: invalid record at {}", path.display(), size),
+        )
+    };
+    loop {
+        line.clear();
+        let n = reader.read_until(TERMINATION, &mut line)?;
+        if line.pop() != Some(TERMINATION) {
+            break;
+        }
+        let mut iter = line.split(is_delimiter);
+        match iter.next() {
+            Some(b"TERM") => {
+                term = parse(iter.next()).ok_or_else(|| invalid(size))?;
+                voted_for = match iter.next() {
+                    Some(b"-") => None,
+                    buf => Some(
+                        parse(buf)
+                            .filter(|v| *v < nodes)
+                            .ok_or_else(|| invalid(size))?,
+                    ),
+                };
+            }
+            Some(b"ENTRY") => {
+                let index: usize = parse(iter.next())
+                    .filter(|index| 0 < *index && *index <= log.len() + 1)
+                    .ok_or_else(|| invalid(size))?;
+                let term = parse(iter.next()).ok_or_else(|| invalid(size))?;
+                let data = iter.next().and_then(decode).ok_or_else(|| invalid(size))?;
+                log.truncate(index - 1);
+                log.push(Entry { term, data });
+            }
+            _ => return Err(invalid(size)),
+        }
+        size += n as u64;
+    }
+    // The record cut by a crash has not been synced, so nothing depends on it.
+    file.set_len(size)?;
+    Ok((term, voted_for, log))
+}
+
+fn connect(addr: &str, auth: Option<&str>) -> io::Result<(TcpStream, BufReader<TcpStream>)> {
+    let address = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address"))?;
+    let stream = TcpStream::connect_timeout(&address, PEER_TIMEOUT)?;
+    stream.set_read_timeout(Some(PEER_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    if let Some(auth) = auth {
+        call(
+            &mut writer,
+            &mut reader,
+            format!("AUTH {}", auth).as_bytes(),
+        )?;
+    }
+    Ok((writer, reader))
+}
+
+// Send the request and return the data of the reply, which must be `1 <data>`.
+fn call(
+    writer: &mut TcpStream,
+    reader: &mut BufReader<TcpStream>,
+    req: &[u8],
+) -> io::Result<Vec<u8>> {
+    writer.write_all(&[req, &[TERMINATION]].concat())?;
+    let mut reply = Vec::new();
+    reader.read_until(TERMINATION, &mut reply)?;
+    if reply.pop() != Some(TERMINATION) {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "closed"));
+    }
+    match reply.strip_prefix(b"1 ") {
+        Some(data) => Ok(data.to_vec()),
+        None => Err(io::Error::other(
+            String::from_utf8_lossy(&reply).into_owned(),
+        )),
+    }
+}
+
+// Send the messages to the peer one by one, and pass the replies to the queue manager
+// as `<peer> <kind> <term> <reply>`, where the reply is `FAIL` if it is not available.
+fn peer(
+    id: usize,
+    addr: &str,
+    auth: Option<&str>,
+    receiver: Receiver<Vec<u8>>,
+    sender: Sender<Box<Request>>,
+) {
+    let mut conn = None;
+    for message in receiver.iter() {
+        if conn.is_none() {
+            conn = connect(addr, auth)
+                .map_err(|err| debug!("Cluster node {}: {}", addr, err))
+                .ok();
+        }
+        let reply = match conn.as_mut() {
+            Some((writer, reader)) => call(writer, reader, &[b"RAFT ", &message[..]].concat()),
+            None => Err(io::Error::new(io::ErrorKind::NotConnected, "not connected")),
+        };
+        let reply = reply.unwrap_or_else(|err| {
+            debug!("Cluster node {}: {}", addr, err);
+            conn = None;
+            b"FAIL".to_vec()
+        });
+        let mut iter = message.splitn(3, is_delimiter);
+        let (kind, term) = (
+            iter.next().unwrap_or_default(),
+            iter.next().unwrap_or_default(),
+        );
+        let arg = [
+            format!("{} ", id).as_bytes(),
+            kind,
+            b" ",
+            term,
+            b" ",
+            &reply,
+        ]
+        .concat();
+        let req = Box::new(Request {
+            // Not replied by the queue manager.
+            token: Token(0),
+            cmd: Command::PEER,
+            arg,
+            user: None,
+            seq: 0,
+        });
+        if sender.send(req).is_err() {
+            return;
+        }
+    }
+}
@@ -1,34 +1,80 @@
 use crate::utils::compare;
 use std::str::from_utf8;
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum Command {
     TERMINATE,
+    RELOAD,
+    DUMP,
+    // The connection given by the token has been closed.
+    DISCONNECT,
+    // A snapshot or a record received from the primary by a replica.
+    REPLICATE,
+    // A reply from another node of the cluster.
+    PEER,
+    // A request passed on by another shard of the queue manager.
+    SHARD,
     QUIT,
     HELLO,
+    AUTH,
     ADDJOB,
     GETJOB,
     ACKJOB,
     STATQUE,
     DELQUE,
+    CREATEQUE,
+    PAUSEQUE,
+    RESUMEQUE,
+    PURGEQUE,
+    MOVEQUE,
+    REQUEUE,
+    DELJOB,
+    CLIENT,
+    SYNC,
+    PROMOTE,
+    RAFT,
 }
 
 const QUIT: &[u8] = b"QUIT";
 const HELLO: &[u8] = b"HELLO";
+const AUTH: &[u8] = b"AUTH";
 const ADDJOB: &[u8] = b"ADDJOB";
 const GETJOB: &[u8] = b"GETJOB";
 const ACKJOB: &[u8] = b"ACKJOB";
 const STATQUE: &[u8] = b"STATQUE";
 const DELQUE: &[u8] = b"DELQUE";
+const CREATEQUE: &[u8] = b"CREATEQUE";
+const PAUSEQUE: &[u8] = b"PAUSEQUE";
+const RESUMEQUE: &[u8] = b"RESUMEQUE";
+const PURGEQUE: &[u8] = b"PURGEQUE";
+const MOVEQUE: &[u8] = b"MOVEQUE";
+const REQUEUE: &[u8] = b"REQUEUE";
+const DELJOB: &[u8] = b"DELJOB";
+const CLIENT: &[u8] = b"CLIENT";
+const SYNC: &[u8] = b"SYNC";
+const PROMOTE: &[u8] = b"PROMOTE";
+const RAFT: &[u8] = b"RAFT";
 
-pub const ENABLE_COMMANDS: [Command; 7] = [
+pub const ENABLE_COMMANDS: [Command; 19] = [
     Command::ACKJOB,
     Command::ADDJOB,
+    Command::AUTH,
+    Command::CLIENT,
+    Command::CREATEQUE,
+    Command::DELJOB,
     Command::DELQUE,
     Command::GETJOB,
     Command::HELLO,
+    Command::MOVEQUE,
+    Command::PAUSEQUE,
+    Command::PROMOTE,
+    Command::PURGEQUE,
     Command::QUIT,
+    Command::RAFT,
+    Command::REQUEUE,
+    Command::RESUMEQUE,
     Command::STATQUE,
+    Command::SYNC,
 ];
 
 impl Command {
@@ -43,10 +89,34 @@ impl Command {
             Some(Command::STATQUE)
         } else if value == DELQUE {
             Some(Command::DELQUE)
+        } else if value == CREATEQUE {
+            Some(Command::CREATEQUE)
+        } else if value == PAUSEQUE {
+            Some(Command::PAUSEQUE)
+        } else if value == RESUMEQUE {
+            Some(Command::RESUMEQUE)
+        } else if value == PURGEQUE {
+            Some(Command::PURGEQUE)
+        } else if value == MOVEQUE {
+            Some(Command::MOVEQUE)
+        } else if value == REQUEUE {
+            Some(Command::REQUEUE)
+        } else if value == DELJOB {
+            Some(Command::DELJOB)
         } else if value == QUIT {
             Some(Command::QUIT)
         } else if value == HELLO {
             Some(Command::HELLO)
+        } else if value == AUTH {
+            Some(Command::AUTH)
+        } else if value == CLIENT {
+            Some(Command::CLIENT)
+        } else if value == SYNC {
+            Some(Command::SYNC)
+        } else if value == PROMOTE {
+            Some(Command::PROMOTE)
+        } else if value == RAFT {
+            Some(Command::RAFT)
         } else {
             None
         }
@@ -55,16 +125,52 @@ impl Command {
     pub fn as_str(&self) -> &[u8] {
         match self {
             Command::TERMINATE => b"",
+            Command::RELOAD => b"",
+            Command::DUMP => b"",
+            Command::DISCONNECT => b"",
+            Command::REPLICATE => b"",
+            Command::PEER => b"",
+            Command::SHARD => b"",
             Command::QUIT => QUIT,
             Command::HELLO => HELLO,
+            Command::AUTH => AUTH,
             Command::ADDJOB => ADDJOB,
             Command::GETJOB => GETJOB,
             Command::ACKJOB => ACKJOB,
             Command::STATQUE => STATQUE,
             Command::DELQUE => DELQUE,
+            Command::CREATEQUE => CREATEQUE,
+            Command::PAUSEQUE => PAUSEQUE,
+            Command::RESUMEQUE => RESUMEQUE,
+            Command::PURGEQUE => PURGEQUE,
+            Command::MOVEQUE => MOVEQUE,
+            Command::REQUEUE => REQUEUE,
+            Command::DELJOB => DELJOB,
+            Command::CLIENT => CLIENT,
+            Command::SYNC => SYNC,
+            Command::PROMOTE => PROMOTE,
+            Command::RAFT => RAFT,
         }
     }
 
+    // Whether the command changes the queues, which is rejected by replicas.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::ADDJOB
+                | Command::GETJOB
+                | Command::ACKJOB
+                | Command::DELJOB
+                | Command::DELQUE
+                | Command::CREATEQUE
+                | Command::PAUSEQUE
+                | Command::RESUMEQUE
+                | Command::PURGEQUE
+                | Command::MOVEQUE
+                | Command::REQUEUE
+        )
+    }
+
     pub fn estimate(value: &[u8]) -> Vec<Command> {
         let mut cmds = Vec::new();
 
@@ -89,12 +195,48 @@ impl Command {
         if Some(idx) == compare(value.as_bytes(), DELQUE) {
             cmds.push(Command::DELQUE);
         }
+        if Some(idx) == compare(value.as_bytes(), CREATEQUE) {
+            cmds.push(Command::CREATEQUE);
+        }
+        if Some(idx) == compare(value.as_bytes(), PAUSEQUE) {
+            cmds.push(Command::PAUSEQUE);
+        }
+        if Some(idx) == compare(value.as_bytes(), RESUMEQUE) {
+            cmds.push(Command::RESUMEQUE);
+        }
+        if Some(idx) == compare(value.as_bytes(), PURGEQUE) {
+            cmds.push(Command::PURGEQUE);
+        }
+        if Some(idx) == compare(value.as_bytes(), MOVEQUE) {
+            cmds.push(Command::MOVEQUE);
+        }
+        if Some(idx) == compare(value.as_bytes(), REQUEUE) {
+            cmds.push(Command::REQUEUE);
+        }
+        if Some(idx) == compare(value.as_bytes(), DELJOB) {
+            cmds.push(Command::DELJOB);
+        }
         if Some(idx) == compare(value.as_bytes(), QUIT) {
             cmds.push(Command::QUIT);
         }
         if Some(idx) == compare(value.as_bytes(), HELLO) {
             cmds.push(Command::HELLO);
         }
+        if Some(idx) == compare(value.as_bytes(), AUTH) {
+            cmds.push(Command::AUTH);
+        }
+        if Some(idx) == compare(value.as_bytes(), CLIENT) {
+            cmds.push(Command::CLIENT);
+        }
+        if Some(idx) == compare(value.as_bytes(), SYNC) {
+            cmds.push(Command::SYNC);
+        }
+        if Some(idx) == compare(value.as_bytes(), PROMOTE) {
+            cmds.push(Command::PROMOTE);
+        }
+        if Some(idx) == compare(value.as_bytes(), RAFT) {
+            cmds.push(Command::RAFT);
+        }
         cmds
     }
 }
@@ -0,0 +1,391 @@
+use log::LevelFilter;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+pub const HOST: &str = "127.0.0.1";
+pub const PORT: u16 = 9000;
+pub const BUFFER_SIZE: usize = 128 * 1024; // 128KB
+pub const MAX_BUFFER_SIZE: usize = 1024 * 1024 + 1024; // about 1MB
+pub const REPLY_HIGH_WATERMARK: usize = 4 * 1024 * 1024; // 4MB
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    // Allow dropping the oldest ready jobs of the queue when memory is full.
+    pub evict: bool,
+    // Pause or resume the queue. The queue is created if it does not exist.
+    pub paused: Option<bool>,
+}
+
+// A token bucket, which is refilled `rate` tokens per second up to `burst`.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateConfig {
+    pub rate: f64,
+    // Defaults to `rate`.
+    pub burst: Option<f64>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UserConfig {
+    pub password: String,
+    // Names of the allowed commands. `*` allows every command.
+    pub commands: Vec<String>,
+    // Patterns of the allowed queue names, where `*` matches any characters.
+    pub queues: Vec<String>,
+    // Rate limits shared by all connections of the user. See `Config::rate_limit`.
+    pub rate_limit: HashMap<String, RateConfig>,
+}
+
+// A set of settings given by a configuration file, environment variables or
+// command line arguments. Absent keys leave the current values as they are.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub host: Option<String>,
+    pub port: Option<u16>,
+    pub tcp: Option<bool>,
+    pub unix_socket: Option<PathBuf>,
+    pub bind: Option<Vec<String>>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_client_ca: Option<PathBuf>,
+    pub auth_tokens: Option<Vec<String>>,
+    pub users: Option<HashMap<String, UserConfig>>,
+    pub log_level: Option<LevelFilter>,
+    pub buffer_size: Option<usize>,
+    pub max_buffer_size: Option<usize>,
+    pub reply_high_watermark: Option<usize>,
+    pub max_memory: Option<usize>,
+    pub persist: Option<PathBuf>,
+    pub shutdown_timeout: Option<u64>,
+    pub max_connections: Option<usize>,
+    pub idle_timeout: Option<u64>,
+    pub reply_timeout: Option<u64>,
+    pub requeue_on_disconnect: Option<bool>,
+    pub replica_of: Option<String>,
+    pub replica_auth: Option<String>,
+    pub cluster: Option<Vec<String>>,
+    pub cluster_node: Option<usize>,
+    pub cluster_auth: Option<String>,
+    pub shards: Option<usize>,
+    pub io_threads: Option<usize>,
+    pub rate_limit: Option<HashMap<String, RateConfig>>,
+    pub queues: Option<HashMap<String, QueueConfig>>,
+}
+
+fn var<T: FromStr>(key: &str) -> io::Result<Option<T>>
+where
+    T::Err: std::fmt::Display,
+{
+    match env::var(key) {
+        Ok(s) => s
+            .parse()
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", key, e))),
+        Err(_) => Ok(None),
+    }
+}
+
+// Read a list separated by commas.
+fn list(key: &str) -> io::Result<Option<Vec<String>>> {
+    Ok(var::<String>(key)?.map(|s| s.split(',').map(|s| s.trim().to_owned()).collect()))
+}
+
+impl ConfigFile {
+    pub fn read(path: &Path) -> io::Result<ConfigFile> {
+        let s = fs::read_to_string(path)?;
+        toml::from_str(&s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    // Read the `QUST_*` environment variables.
+    pub fn from_env() -> io::Result<ConfigFile> {
+        Ok(ConfigFile {
+            host: var("QUST_HOST")?,
+            port: var("QUST_PORT")?,
+            tcp: var("QUST_TCP")?,
+            unix_socket: var("QUST_UNIX_SOCKET")?,
+            bind: list("QUST_BIND")?,
+            tls_cert: var("QUST_TLS_CERT")?,
+            tls_key: var("QUST_TLS_KEY")?,
+            tls_client_ca: var("QUST_TLS_CLIENT_CA")?,
+            auth_tokens: list("QUST_AUTH_TOKENS")?,
+            users: None,
+            log_level: var("QUST_LOG_LEVEL")?,
+            buffer_size: var("QUST_BUFFER_SIZE")?,
+            max_buffer_size: var("QUST_MAX_BUFFER_SIZE")?,
+            reply_high_watermark: var("QUST_REPLY_HIGH_WATERMARK")?,
+            max_memory: var("QUST_MAX_MEMORY")?,
+            persist: var("QUST_PERSIST")?,
+            shutdown_timeout: var("QUST_SHUTDOWN_TIMEOUT")?,
+            max_connections: var("QUST_MAX_CONNECTIONS")?,
+            idle_timeout: var("QUST_IDLE_TIMEOUT")?,
+            reply_timeout: var("QUST_REPLY_TIMEOUT")?,
+            requeue_on_disconnect: var("QUST_REQUEUE_ON_DISCONNECT")?,
+            replica_of: var("QUST_REPLICA_OF")?,
+            replica_auth: var("QUST_REPLICA_AUTH")?,
+            cluster: list("QUST_CLUSTER")?,
+            cluster_node: var("QUST_CLUSTER_NODE")?,
+            cluster_auth: var("QUST_CLUSTER_AUTH")?,
+            shards: var("QUST_SHARDS")?,
+            io_threads: var("QUST_IO_THREADS")?,
+            rate_limit: None,
+            queues: None,
+        })
+    }
+
+    pub fn queues(&self) -> Option<HashMap<Vec<u8>, QueueConfig>> {
+        self.queues.as_ref().map(|queues| {
+            queues
+                .iter()
+                .map(|(name, queue)| (name.as_bytes().to_vec(), queue.clone()))
+                .collect()
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    // Listen on `host`:`port`.
+    pub tcp: bool,
+    // Listen on the Unix domain socket, in addition to TCP.
+    pub unix_socket: Option<PathBuf>,
+    // More addresses to listen on: `<host>:<port>`, `[<IPv6 address>]:<port>` or `unix:<path>`.
+    pub bind: Vec<String>,
+    // Certificate chain and private key (PEM) to accept TCP connections with TLS.
+    // Requires the `tls` feature.
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    // CA certificates (PEM) to verify client certificates. Clients without a
+    // valid certificate are rejected.
+    pub tls_client_ca: Option<PathBuf>,
+    // Tokens accepted by AUTH. Every connection must be authenticated by one of
+    // them unless it is empty.
+    pub auth_tokens: Vec<String>,
+    // Users authenticated by `AUTH <user> <password>`, whose commands and
+    // queues are restricted.
+    pub users: HashMap<String, UserConfig>,
+    // Configuration file, which is read again on SIGHUP.
+    pub path: Option<PathBuf>,
+    // The environment variables and the command line arguments, which are applied over
+    // the configuration file again when it is reloaded.
+    pub overrides: Vec<ConfigFile>,
+    // Queues given by `--evict-queue`, in addition to the ones of `queues`.
+    pub evict_queues: Vec<Vec<u8>>,
+    pub log_level: LevelFilter,
+    // Size of the buffer to read from a connection at once.
+    pub buffer_size: usize,
+    // Upper bound of a request. Larger requests are replied with an error.
+    pub max_buffer_size: usize,
+    // The requests of a connection are not read while its replies waiting to be written
+    // exceed the bytes. The replies to the requests already read may exceed it.
+    // `0` means unlimited.
+    pub reply_high_watermark: usize,
+    // Upper bound (bytes) of the jobs kept by all queues. `0` means unlimited.
+    pub memory_limit: usize,
+    pub queues: HashMap<Vec<u8>, QueueConfig>,
+    // File to save the queues on shutdown and to load them on startup.
+    pub persist: Option<PathBuf>,
+    // How long to wait for in-flight requests and replies on shutdown.
+    // `0` closes the connections immediately.
+    pub shutdown_timeout: Duration,
+    // Upper bound of the connections. `0` means unlimited.
+    pub max_connections: usize,
+    // Close connections which send nothing for the duration. `0` disables it.
+    pub idle_timeout: Duration,
+    // Close connections which do not read the reply for the duration. `0` disables it.
+    pub reply_timeout: Duration,
+    // Return the jobs leased by a connection to ready as soon as it is closed,
+    // instead of waiting for their retry time.
+    pub requeue_on_disconnect: bool,
+    // Follow the primary at `<host>:<port>` as a read-only replica until PROMOTE.
+    pub replica_of: Option<String>,
+    // Sent by AUTH to the primary: `<token>` or `<user> <password>`.
+    pub replica_auth: Option<String>,
+    // Addresses (`<host>:<port>`) of all nodes of the cluster, which replicate the
+    // commands changing the queues by Raft. Empty disables the cluster mode.
+    pub cluster: Vec<String>,
+    // Index of this node in `cluster`.
+    pub cluster_node: usize,
+    // Sent by AUTH to the other nodes: `<token>` or `<user> <password>`.
+    pub cluster_auth: Option<String>,
+    // The number of queue manager threads. The queues are partitioned among them
+    // by the hash of their names.
+    pub shards: usize,
+    // The number of network I/O threads. Each of them accepts the connections from
+    // the listeners and serves them.
+    pub io_threads: usize,
+    // Rate limits per connection by command names. `*` limits all commands together.
+    pub rate_limit: HashMap<String, RateConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            host: HOST.to_owned(),
+            port: PORT,
+            tcp: true,
+            unix_socket: None,
+            bind: Vec::new(),
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            auth_tokens: Vec::new(),
+            users: HashMap::new(),
+            path: None,
+            overrides: Vec::new(),
+            evict_queues: Vec::new(),
+            log_level: LevelFilter::Info,
+            buffer_size: BUFFER_SIZE,
+            max_buffer_size: MAX_BUFFER_SIZE,
+            reply_high_watermark: REPLY_HIGH_WATERMARK,
+            memory_limit: 0,
+            queues: HashMap::new(),
+            persist: None,
+            shutdown_timeout: Duration::from_secs(0),
+            max_connections: 0,
+            idle_timeout: Duration::from_secs(0),
+            reply_timeout: Duration::from_secs(0),
+            requeue_on_disconnect: false,
+            replica_of: None,
+            replica_auth: None,
+            cluster: Vec::new(),
+            cluster_node: 0,
+            cluster_auth: None,
+            shards: 1,
+            io_threads: 1,
+            rate_limit: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(&mut self, path: &Path) -> io::Result<()> {
+        self.apply(ConfigFile::read(path)?);
+        self.path = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    // Read the configuration file again, and apply the overrides over it as on startup.
+    pub fn reload(&mut self) -> io::Result<&Path> {
+        let path = self
+            .path
+            .clone()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no configuration file"))?;
+        self.apply(ConfigFile::read(&path)?);
+        self.apply_overrides();
+        Ok(self.path.as_deref().unwrap())
+    }
+
+    // Apply the environment variables and the command line arguments in order.
+    pub fn apply_overrides(&mut self) {
+        for file in self.overrides.clone() {
+            self.apply(file);
+        }
+        for name in self.evict_queues.iter() {
+            self.queues.entry(name.clone()).or_default().evict = true;
+        }
+    }
+
+    pub fn apply(&mut self, file: ConfigFile) {
+        if let Some(queues) = file.queues() {
+            self.queues = queues;
+        }
+        if let Some(host) = file.host {
+            self.host = host;
+        }
+        if let Some(port) = file.port {
+            self.port = port;
+        }
+        if let Some(tcp) = file.tcp {
+            self.tcp = tcp;
+        }
+        if let Some(path) = file.unix_socket {
+            self.unix_socket = Some(path);
+        }
+        if let Some(bind) = file.bind {
+            self.bind = bind;
+        }
+        if let Some(path) = file.tls_cert {
+            self.tls_cert = Some(path);
+        }
+        if let Some(path) = file.tls_key {
+            self.tls_key = Some(path);
+        }
+        if let Some(path) = file.tls_client_ca {
+            self.tls_client_ca = Some(path);
+        }
+        if let Some(tokens) = file.auth_tokens {
+            self.auth_tokens = tokens;
+        }
+        if let Some(users) = file.users {
+            self.users = users;
+        }
+        if let Some(level) = file.log_level {
+            self.log_level = level;
+        }
+        if let Some(size) = file.buffer_size {
+            self.buffer_size = size;
+        }
+        if let Some(size) = file.max_buffer_size {
+            self.max_buffer_size = size;
+        }
+        if let Some(size) = file.reply_high_watermark {
+            self.reply_high_watermark = size;
+        }
+        if let Some(size) = file.max_memory {
+            self.memory_limit = size;
+        }
+        if let Some(path) = file.persist {
+            self.persist = Some(path);
+        }
+        if let Some(secs) = file.shutdown_timeout {
+            self.shutdown_timeout = Duration::from_secs(secs);
+        }
+        if let Some(max) = file.max_connections {
+            self.max_connections = max;
+        }
+        if let Some(secs) = file.idle_timeout {
+            self.idle_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = file.reply_timeout {
+            self.reply_timeout = Duration::from_secs(secs);
+        }
+        if let Some(requeue) = file.requeue_on_disconnect {
+            self.requeue_on_disconnect = requeue;
+        }
+        if let Some(primary) = file.replica_of {
+            self.replica_of = Some(primary);
+        }
+        if let Some(auth) = file.replica_auth {
+            self.replica_auth = Some(auth);
+        }
+        if let Some(nodes) = file.cluster {
+            self.cluster = nodes;
+        }
+        if let Some(node) = file.cluster_node {
+            self.cluster_node = node;
+        }
+        if let Some(auth) = file.cluster_auth {
+            self.cluster_auth = Some(auth);
+        }
+        if let Some(shards) = file.shards {
+            self.shards = shards;
+        }
+        if let Some(threads) = file.io_threads {
+            self.io_threads = threads;
+        }
+        if let Some(limit) = file.rate_limit {
+            self.rate_limit = limit;
+        }
+    }
+}
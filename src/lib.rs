@@ -1,11 +1,22 @@
 #[macro_use]
 extern crate log;
 
+mod acl;
+mod cluster;
 pub mod command;
+pub mod config;
+mod limit;
 pub mod message;
+mod net;
 pub mod queue;
+mod replica;
 pub mod server;
+mod shard;
 pub mod signal;
+#[cfg(feature = "tls")]
+pub mod tls;
 pub mod utils;
+mod worker;
 
+pub use crate::config::Config;
 pub use crate::server::Server;
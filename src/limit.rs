@@ -0,0 +1,116 @@
+use crate::command::Command;
+use crate::config::RateConfig;
+use std::collections::HashMap;
+use std::io;
+use std::time::Instant;
+
+// Rate limits by command names, given by `rate_limit` of the configuration file.
+#[derive(Default)]
+pub(crate) struct Limit {
+    commands: HashMap<Command, RateConfig>,
+    // `*` limits all commands together.
+    all: Option<RateConfig>,
+}
+
+impl Limit {
+    pub(crate) fn new(scope: &str, config: &HashMap<String, RateConfig>) -> io::Result<Limit> {
+        let mut limit = Limit::default();
+        for (name, rate) in config.iter() {
+            if !(rate.rate > 0.0 && rate.burst.unwrap_or(rate.rate) >= 1.0) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{}.{}: rate must be positive and burst at least 1",
+                        scope, name
+                    ),
+                ));
+            }
+            if name == "*" {
+                limit.all = Some(*rate);
+                continue;
+            }
+            match Command::from(name.to_uppercase().as_bytes()) {
+                Some(cmd) => {
+                    limit.commands.insert(cmd, *rate);
+                }
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{}: unknown command: {}", scope, name),
+                    ))
+                }
+            }
+        }
+        Ok(limit)
+    }
+
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.commands.is_empty() && self.all.is_none()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn new(rate: &RateConfig, now: Instant) -> Bucket {
+        Bucket {
+            tokens: rate.burst.unwrap_or(rate.rate),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: &RateConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.rate).min(rate.burst.unwrap_or(rate.rate));
+        self.updated = now;
+    }
+}
+
+// Token buckets of a connection or a user. The key `None` is for all commands.
+#[derive(Default)]
+pub(crate) struct Buckets {
+    buckets: HashMap<Option<Command>, Bucket>,
+}
+
+impl Buckets {
+    // Whether a token for the command is left, without taking it.
+    pub(crate) fn has(&mut self, limit: &Limit, cmd: Command) -> bool {
+        let now = Instant::now();
+        for (key, rate) in rates(limit, cmd).iter() {
+            if let Some(rate) = rate {
+                let bucket = self
+                    .buckets
+                    .entry(*key)
+                    .or_insert_with(|| Bucket::new(rate, now));
+                bucket.refill(rate, now);
+                if bucket.tokens < 1.0 {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    // Take a token for the command, which `has` has found.
+    pub(crate) fn spend(&mut self, limit: &Limit, cmd: Command) {
+        for (key, rate) in rates(limit, cmd).iter() {
+            if rate.is_some() {
+                if let Some(bucket) = self.buckets.get_mut(key) {
+                    bucket.tokens -= 1.0;
+                }
+            }
+        }
+    }
+}
+
+// The rates of the command and of all commands, which are taken together.
+fn rates(limit: &Limit, cmd: Command) -> [(Option<Command>, Option<&RateConfig>); 2] {
+    [
+        (Some(cmd), limit.commands.get(&cmd)),
+        (None, limit.all.as_ref()),
+    ]
+}
@@ -1,13 +1,25 @@
+use crate::acl::User;
 use crate::command::Command;
 use mio::Token;
+use std::collections::VecDeque;
+use std::io::{self, IoSlice, Write};
+use std::sync::Arc;
 
 pub(crate) const TERMINATION: u8 = b'\n';
+// Status of the records pushed to replicas after SYNC, which are not replies to requests.
+pub(crate) const PUSH: i8 = 2;
+// The max number of chunks written by a call of `write_vectored`.
+const MAX_SLICES: usize = 64;
 
 #[derive(Debug)]
 pub struct Request {
     pub token: Token,
     pub cmd: Command,
     pub arg: Vec<u8>,
+    // The user of the connection, who may remove only the jobs of the user's queues.
+    pub(crate) user: Option<Arc<User>>,
+    // The number of the request on the connection, by which the replies are put in order.
+    pub(crate) seq: u64,
 }
 
 #[derive(Debug)]
@@ -15,6 +27,8 @@ pub struct Reply {
     pub token: Token,
     pub status: i8,
     pub data: Vec<u8>,
+    // Written after `data` without copying it, e.g. the job got by GETJOB.
+    pub payload: Option<Arc<[u8]>>,
 }
 
 impl Reply {
@@ -22,6 +36,7 @@ impl Reply {
         [
             format!("{} ", self.status).as_bytes(),
             self.data.as_slice(),
+            self.payload.as_deref().unwrap_or_default(),
             &[TERMINATION],
         ]
         .concat()
@@ -31,6 +46,94 @@ impl Reply {
             token,
             status: -1,
             data: b"Error".to_vec(),
+            payload: None,
+        }
+    }
+    pub fn out_of_memory(token: Token) -> Reply {
+        Reply {
+            token,
+            status: -1,
+            data: b"OutOfMemory".to_vec(),
+            payload: None,
+        }
+    }
+    pub fn ok(token: Token) -> Reply {
+        Reply {
+            token,
+            status: 1,
+            data: b"OK".to_vec(),
+            payload: None,
+        }
+    }
+    // The credential of AUTH is wrong.
+    pub fn auth_failed(token: Token) -> Reply {
+        Reply {
+            token,
+            status: -1,
+            data: b"AuthFailed".to_vec(),
+            payload: None,
+        }
+    }
+    // The connection has to be authenticated by AUTH first.
+    pub fn no_auth(token: Token) -> Reply {
+        Reply {
+            token,
+            status: -1,
+            data: b"NoAuth".to_vec(),
+            payload: None,
+        }
+    }
+    // The user is not allowed to run the command on the queues.
+    pub fn no_perm(token: Token) -> Reply {
+        Reply {
+            token,
+            status: -1,
+            data: b"NoPerm".to_vec(),
+            payload: None,
+        }
+    }
+    // The rate limit of the connection or the user is exceeded.
+    pub fn throttled(token: Token) -> Reply {
+        Reply {
+            token,
+            status: -1,
+            data: b"Throttled".to_vec(),
+            payload: None,
+        }
+    }
+    // The server is a replica, which rejects commands changing the queues.
+    pub fn read_only(token: Token) -> Reply {
+        Reply {
+            token,
+            status: -1,
+            data: b"ReadOnly".to_vec(),
+            payload: None,
+        }
+    }
+    // The server is a follower of the cluster. The client should retry on the leader.
+    pub fn redirect(token: Token, leader: &str) -> Reply {
+        Reply {
+            token,
+            status: -1,
+            data: format!("Redirect {}", leader).into_bytes(),
+            payload: None,
+        }
+    }
+    // No leader of the cluster is known, e.g. while an election is in progress.
+    pub fn no_leader(token: Token) -> Reply {
+        Reply {
+            token,
+            status: -1,
+            data: b"NoLeader".to_vec(),
+            payload: None,
+        }
+    }
+    pub fn too_many_connections(token: Token) -> Reply {
+        Reply {
+            token,
+            status: -1,
+            data: b"TooManyConnections".to_vec(),
+            payload: None,
         }
     }
     pub fn empty(token: Token) -> Reply {
@@ -38,6 +141,92 @@ impl Reply {
             token,
             status: 0,
             data: vec![0; 0],
+            payload: None,
+        }
+    }
+}
+
+enum Chunk {
+    Static(&'static [u8]),
+    Owned(Vec<u8>),
+    Shared(Arc<[u8]>),
+}
+
+impl Chunk {
+    fn status(status: i8) -> Chunk {
+        match status {
+            -1 => Chunk::Static(b"-1 "),
+            0 => Chunk::Static(b"0 "),
+            1 => Chunk::Static(b"1 "),
+            2 => Chunk::Static(b"2 "),
+            status => Chunk::Owned(format!("{} ", status).into_bytes()),
+        }
+    }
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Chunk::Static(chunk) => chunk,
+            Chunk::Owned(chunk) => chunk,
+            Chunk::Shared(chunk) => chunk,
+        }
+    }
+}
+
+// The replies to be written to a connection. They are kept in chunks, which are written
+// by vectored I/O, so neither the data nor the payload is copied.
+#[derive(Default)]
+pub(crate) struct Outbox {
+    chunks: VecDeque<Chunk>,
+    // The number of the bytes of the first chunk which have been written.
+    cursor: usize,
+    // The number of the bytes which have not been written.
+    len: usize,
+}
+
+impl Outbox {
+    pub(crate) fn push(&mut self, reply: Reply) {
+        self.append(Chunk::status(reply.status));
+        self.append(Chunk::Owned(reply.data));
+        if let Some(payload) = reply.payload {
+            self.append(Chunk::Shared(payload));
+        }
+        self.append(Chunk::Static(&[TERMINATION]));
+    }
+    fn append(&mut self, chunk: Chunk) {
+        if !chunk.as_slice().is_empty() {
+            self.len += chunk.as_slice().len();
+            self.chunks.push_back(chunk);
+        }
+    }
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+    #[inline]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    // Write as much as possible by a call, and drop the bytes which have been written.
+    pub(crate) fn write_to<W: Write>(&mut self, writer: &mut W) -> io::Result<usize> {
+        let slices: Vec<IoSlice> = self
+            .chunks
+            .iter()
+            .take(MAX_SLICES)
+            .enumerate()
+            .map(|(i, chunk)| match i {
+                0 => IoSlice::new(&chunk.as_slice()[self.cursor..]),
+                _ => IoSlice::new(chunk.as_slice()),
+            })
+            .collect();
+        let n = writer.write_vectored(&slices)?;
+        self.len -= n;
+        self.cursor += n;
+        while let Some(chunk) = self.chunks.front() {
+            if self.cursor < chunk.as_slice().len() {
+                break;
+            }
+            self.cursor -= chunk.as_slice().len();
+            self.chunks.pop_front();
         }
+        Ok(n)
     }
 }
@@ -0,0 +1,248 @@
+#[cfg(feature = "tls")]
+use crate::tls::TlsStream;
+use mio::event::Source;
+use mio::net::{TcpStream, UnixStream};
+use mio::unix::SourceFd;
+use mio::{Interest, Registry, Token};
+use std::fs;
+use std::io::{self, IoSlice, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, ToSocketAddrs};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+
+// A listening socket, which is cloned for each I/O thread to accept the connections
+// by its own `Poll`.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    // The socket file is removed when the listener is dropped, unless it is a clone.
+    Unix(UnixListener, PathBuf, bool),
+}
+
+fn bind_addrs<A: ToSocketAddrs>(addr: A) -> io::Result<Vec<Listener>> {
+    let mut addrs: Vec<SocketAddr> = addr.to_socket_addrs()?.collect();
+    // A host name may be resolved into the same address more than once.
+    addrs.sort();
+    addrs.dedup();
+    addrs.into_iter().map(Listener::bind_tcp).collect()
+}
+
+impl Listener {
+    // Bind every address of `<host>:<port>`, `[<IPv6 address>]:<port>` or `unix:<path>`.
+    pub(crate) fn bind(address: &str) -> io::Result<Vec<Listener>> {
+        match address.strip_prefix("unix:") {
+            Some(path) => Ok(vec![Listener::bind_unix(Path::new(path))?]),
+            None => bind_addrs(address),
+        }
+    }
+
+    // Bind every address of the host, which may be an IPv6 address in brackets.
+    pub(crate) fn bind_host(host: &str, port: u16) -> io::Result<Vec<Listener>> {
+        bind_addrs((host.trim_start_matches('[').trim_end_matches(']'), port))
+    }
+
+    pub(crate) fn bind_tcp(addr: SocketAddr) -> io::Result<Listener> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Tcp(listener))
+    }
+
+    pub(crate) fn bind_unix(path: &Path) -> io::Result<Listener> {
+        // Remove the socket file left by the previous process.
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            if meta.file_type().is_socket() {
+                fs::remove_file(path)?;
+            }
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Listener::Unix(listener, path.to_path_buf(), true))
+    }
+
+    // Share the socket with another thread.
+    pub(crate) fn try_clone(&self) -> io::Result<Listener> {
+        match self {
+            Listener::Tcp(listener) => Ok(Listener::Tcp(listener.try_clone()?)),
+            Listener::Unix(listener, path, _) => {
+                Ok(Listener::Unix(listener.try_clone()?, path.clone(), false))
+            }
+        }
+    }
+
+    // Accept a connection, with a description of the peer for logging.
+    pub(crate) fn accept(&self) -> io::Result<(Stream, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept()?;
+                stream.set_nonblocking(true)?;
+                Ok((Stream::Tcp(TcpStream::from_std(stream)), addr.to_string()))
+            }
+            Listener::Unix(listener, path, _) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nonblocking(true)?;
+                Ok((
+                    Stream::Unix(UnixStream::from_std(stream)),
+                    format!("unix:{}", path.display()),
+                ))
+            }
+        }
+    }
+
+    pub(crate) fn describe(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => listener
+                .local_addr()
+                .map(|addr| addr.to_string())
+                .unwrap_or_else(|_| String::from("-")),
+            Listener::Unix(_, path, _) => format!("unix:{}", path.display()),
+        }
+    }
+
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener, _, _) => listener.as_raw_fd(),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path, true) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+impl Source for Listener {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).deregister(registry)
+    }
+}
+
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+}
+
+impl Stream {
+    // Start a TLS session on a TCP stream. Unix domain sockets are left as they are.
+    #[cfg(feature = "tls")]
+    pub(crate) fn into_tls(self, config: Arc<rustls::ServerConfig>) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => Ok(Stream::Tls(Box::new(TlsStream::new(config, stream)?))),
+            stream => Ok(stream),
+        }
+    }
+
+    pub(crate) fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            Stream::Unix(stream) => stream.shutdown(how),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write_vectored(bufs),
+            Stream::Unix(stream) => stream.write_vectored(bufs),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.write_vectored(bufs),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+impl Source for Stream {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.register(registry, token, interests),
+            Stream::Unix(stream) => stream.register(registry, token, interests),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.reregister(registry, token, interests),
+            Stream::Unix(stream) => stream.reregister(registry, token, interests),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.deregister(registry),
+            Stream::Unix(stream) => stream.deregister(registry),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.deregister(registry),
+        }
+    }
+}
@@ -1,14 +1,23 @@
+use crate::acl::User;
+use crate::cluster::Cluster;
 use crate::command::Command;
-use crate::message::{Reply, Request};
+use crate::config::{Config, QueueConfig};
+use crate::message::{Reply, Request, PUSH, TERMINATION};
+use crate::shard::Shards;
 use crate::utils::is_delimiter;
-use mio::Waker;
+use crate::worker::Workers;
+use mio::Token;
 use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::str::from_utf8;
-use std::sync::mpsc::{Receiver, Sender};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
 use std::time::Duration;
-use std::time::SystemTime;
+use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
 const JOB_ID_SIZE: usize = 32;
@@ -16,10 +25,13 @@ type JobId = [u8; JOB_ID_SIZE];
 
 struct Job {
     id: JobId,
-    job: Vec<u8>,
+    // Shared with the replies of GETJOB, which write it without copying.
+    job: Arc<[u8]>,
     retry: Duration,
     running: bool,
     start: SystemTime,
+    // The connection which got the job by GETJOB.
+    owner: Option<Token>,
 }
 
 macro_rules! next {
@@ -37,63 +49,134 @@ macro_rules! next {
     };
 }
 
+fn new_id() -> JobId {
+    let mut id = [0; JOB_ID_SIZE];
+    Uuid::new_v4().to_simple().encode_lower(id.as_mut());
+    id
+}
+
 impl Job {
-    fn new(job: Vec<u8>, retry: Duration) -> Self {
-        let mut id = [0; JOB_ID_SIZE];
-        Uuid::new_v4().to_simple().encode_lower(id.as_mut());
+    fn new(job: Arc<[u8]>, retry: Duration) -> Self {
+        Job::with_id(new_id(), job, retry)
+    }
+    fn with_id(id: JobId, job: Arc<[u8]>, retry: Duration) -> Self {
         Job {
             id,
             job,
             retry,
             running: false,
             start: SystemTime::now(),
+            owner: None,
         }
     }
-    fn run(&mut self) {
+    // The owner is `None` on replicas, which do not know the connections of the primary.
+    fn run(&mut self, owner: Option<Token>, now: SystemTime) {
         self.running = true;
-        self.start = SystemTime::now();
+        self.start = now;
+        self.owner = owner;
+    }
+    fn ready(&mut self) {
+        self.running = false;
+        self.owner = None;
+    }
+    // Whether the job is running and its retry time has not passed yet.
+    fn is_leased(&self) -> bool {
+        self.running && !self.is_retry()
     }
     fn is_retry(&self) -> bool {
-        self.start.elapsed().unwrap() > self.retry
+        self.is_retry_at(SystemTime::now())
+    }
+    fn is_retry_at(&self, now: SystemTime) -> bool {
+        now.duration_since(self.start).unwrap_or_default() > self.retry
+    }
+    fn size(&self) -> usize {
+        JOB_ID_SIZE + self.job.len()
     }
 }
 
 struct Queue {
     jobs: Vec<Job>,
+    paused: bool,
+    // The number of jobs removed by ACKJOB and DELJOB, respectively.
+    acked: usize,
+    deleted: usize,
 }
 
 impl Queue {
     fn new() -> Queue {
-        Queue { jobs: Vec::new() }
+        Queue {
+            jobs: Vec::new(),
+            paused: false,
+            acked: 0,
+            deleted: 0,
+        }
     }
     fn add(&mut self, job: Job) {
         self.jobs.push(job);
     }
-    fn get(&mut self) -> Option<&Job> {
+    fn get(&mut self, owner: Option<Token>, now: SystemTime) -> Option<&Job> {
         for i in 0..self.jobs.len() {
             if let Some(job) = self.jobs.get_mut(i) {
-                if !job.running {
-                    job.run();
-                    return self.jobs.get(i);
-                } else if job.is_retry() {
-                    job.run();
+                if !job.running || job.is_retry_at(now) {
+                    job.run(owner, now);
                     return self.jobs.get(i);
                 }
             }
         }
         None
     }
-    fn ack(&mut self, job_id: &[u8]) -> Option<()> {
+    fn remove(&mut self, job_id: &[u8]) -> Option<Job> {
         for i in 0..self.jobs.len() {
             if let Some(job) = self.jobs.get(i) {
                 if job.id == *job_id {
-                    self.jobs.remove(i);
-                    return Some(());
+                    return Some(self.jobs.remove(i));
                 }
             }
         }
         None
     }
+    fn evict(&mut self) -> Option<(usize, Job)> {
+        // Jobs are kept in insertion order, so the first ready job is the oldest one.
+        let i = self.jobs.iter().position(|job| !job.running)?;
+        Some((i, self.jobs.remove(i)))
+    }
+    // Put the evicted job back where it was.
+    fn put_back(&mut self, i: usize, job: Job) {
+        self.jobs.insert(i.min(self.jobs.len()), job);
+    }
+    fn purge(&mut self) -> Vec<Job> {
+        let (ready, running) = self.jobs.drain(..).partition(|job| !job.running);
+        self.jobs = running;
+        ready
+    }
+    fn take(&mut self, n: usize) -> Vec<Job> {
+        let n = n.min(self.jobs.len());
+        self.jobs.drain(..n).collect()
+    }
+    fn requeue(&mut self) -> usize {
+        let mut count = 0usize;
+        for job in self.jobs.iter_mut().filter(|job| job.running) {
+            job.ready();
+            count += 1;
+        }
+        count
+    }
+    // Return the jobs leased by the connection to ready.
+    fn release(&mut self, owner: Token) -> Vec<JobId> {
+        let mut ids = Vec::new();
+        for job in self
+            .jobs
+            .iter_mut()
+            .filter(|job| job.running && job.owner == Some(owner))
+        {
+            job.ready();
+            ids.push(job.id);
+        }
+        ids
+    }
+    fn get_mut(&mut self, job_id: &[u8]) -> Option<&mut Job> {
+        self.jobs.iter_mut().find(|job| job.id == *job_id)
+    }
     fn len(&self) -> usize {
         self.jobs.len()
     }
@@ -106,32 +189,695 @@ impl Queue {
         }
         count
     }
+    fn size(&self) -> usize {
+        self.jobs.iter().map(|job| job.size()).sum()
+    }
+    fn ready_size(&self) -> usize {
+        self.jobs
+            .iter()
+            .filter(|job| !job.running)
+            .map(|job| job.size())
+            .sum()
+    }
     fn clean(&mut self) {
         self.jobs.clear()
     }
 }
 
+// Commands which give the same result on replicas, so that they are pushed as they are.
+// The others are pushed as the records of their results, e.g. `RUN <job id>` for GETJOB.
+fn replayed(cmd: &Command) -> bool {
+    cmd.is_write() && !matches!(cmd, Command::ADDJOB | Command::GETJOB)
+}
+
+fn job_id(buf: &[u8]) -> Option<JobId> {
+    let mut id = [0; JOB_ID_SIZE];
+    match buf.len() == JOB_ID_SIZE {
+        true => {
+            id.copy_from_slice(buf);
+            Some(id)
+        }
+        false => None,
+    }
+}
+
+// Parse `<queue name> <job id> <retry seconds> <job>` of the ADD records.
+fn restore_job(arg: &[u8]) -> io::Result<(&[u8], Job)> {
+    let mut iter = arg.splitn(4, is_delimiter);
+    let name = iter.next().unwrap_or_default();
+    let id = iter
+        .next()
+        .and_then(job_id)
+        .ok_or_else(|| invalid_data("invalid job id"))?;
+    let secs = parse::<u64>(iter.next())?;
+    let job = iter.next().unwrap_or_default().into();
+    Ok((name, Job::with_id(id, job, Duration::from_secs(secs))))
+}
+
+// The entry of the cluster log for the command, which fixes the values chosen by the leader:
+//   ADD <queue name> <job id> <retry seconds> <job>
+//   GETJOB <milliseconds since the epoch> <queue name> ... <queue name>
+// The other commands are given as they are.
+fn entry(req: &Request) -> Result<Vec<u8>, Reply> {
+    match req.cmd {
+        Command::ADDJOB => {
+            // command: ADDJOB <queue name> <retry seconds> <job>
+            let mut iter = req.arg.split(is_delimiter);
+            match (next!(iter), parse::<u64>(next!(iter)), next!(iter)) {
+                (Some(name), Ok(secs), Some(job)) => {
+                    let job = Job::new(job.into(), Duration::from_secs(secs));
+                    Ok([
+                        b"ADD ",
+                        name,
+                        b" ",
+                        &job.id[..],
+                        format!(" {} ", secs).as_bytes(),
+                        &job.job,
+                    ]
+                    .concat())
+                }
+                _ => Err(Reply::error(req.token)),
+            }
+        }
+        Command::GETJOB => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis();
+            Ok([format!("GETJOB {} ", now).as_bytes(), &req.arg].concat())
+        }
+        _ => Ok([req.cmd.as_str(), b" ", &req.arg].concat()),
+    }
+}
+
+// The jobs taken out of the evictable queues, with the queue names and the positions.
+type Evicted = Vec<(Vec<u8>, usize, Job)>;
+
 pub struct QueueManager {
     queues: HashMap<Vec<u8>, Queue>,
     reverse: HashMap<JobId, Vec<u8>>,
+    // The limit of the memory used by the jobs of all shards.
+    memory_limit: usize,
+    // The memory used by the jobs of all shards, and of this one.
+    memory: Arc<AtomicUsize>,
+    memory_used: usize,
+    evict_queues: Vec<Vec<u8>>,
+    // The jobs evicted for the job of another shard, by its ID, which are dropped once the
+    // job is added, or put back.
+    evicted: HashMap<JobId, Evicted>,
+    persist: Option<PathBuf>,
+    // Shared with the I/O threads, which reload it.
+    config: Arc<Mutex<Config>>,
+    requeue_on_disconnect: bool,
+    // Connections which have sent SYNC, to push the records to.
+    replicas: Vec<Token>,
+    // Changes made by the current request, which are pushed to the replicas.
+    records: Vec<Vec<u8>>,
+    cluster: Option<Cluster>,
+    // Connections waiting for the commands at the log indexes to be committed, with
+    // the sequence numbers of the requests.
+    pending: HashMap<usize, (Token, u64)>,
+    // Replies to send after the current request.
+    replies: Vec<(u64, Reply)>,
+    // The sequence number of the request being handled, which is passed on with it
+    // to the other shards.
+    seq: u64,
+    // This manager owns the queues whose names are hashed to `index` by `shards`.
+    index: usize,
+    shards: Shards,
+}
+
+// JOB <job id> <retry seconds> <job size>\n<job>\n, followed by
+// RUN <job id> <milliseconds since the epoch> <owner or ->\n if `leases` is set and the job
+// is running.
+fn write_job<W: Write>(writer: &mut W, job: &Job, leases: bool) -> io::Result<()> {
+    writer.write_all(b"JOB ")?;
+    writer.write_all(&job.id)?;
+    writer.write_all(format!(" {} {}\n", job.retry.as_secs(), job.job.len()).as_bytes())?;
+    writer.write_all(&job.job)?;
+    writer.write_all(&[TERMINATION])?;
+    if leases && job.running {
+        let start = job
+            .start
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let owner = job
+            .owner
+            .map_or(String::from("-"), |owner| owner.0.to_string());
+        writer.write_all(b"RUN ")?;
+        writer.write_all(&job.id)?;
+        writer.write_all(format!(" {} {}\n", start, owner).as_bytes())?;
+    }
+    Ok(())
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn parse<T: std::str::FromStr>(buf: Option<&[u8]>) -> io::Result<T> {
+    buf.and_then(|buf| from_utf8(buf).ok())
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid_data("invalid number in snapshot"))
 }
 
 impl QueueManager {
-    fn new() -> Self {
+    fn new(
+        config: &Config,
+        shared: Arc<Mutex<Config>>,
+        memory: Arc<AtomicUsize>,
+        index: usize,
+        shards: Shards,
+    ) -> Self {
         QueueManager {
             queues: HashMap::new(),
             reverse: HashMap::new(),
+            memory_limit: config.memory_limit,
+            memory,
+            memory_used: 0,
+            evict_queues: Vec::new(),
+            evicted: HashMap::new(),
+            persist: config.persist.clone(),
+            config: shared,
+            requeue_on_disconnect: config.requeue_on_disconnect,
+            replicas: Vec::new(),
+            records: Vec::new(),
+            cluster: None,
+            pending: HashMap::new(),
+            replies: Vec::new(),
+            seq: 0,
+            index,
+            shards,
+        }
+    }
+    #[inline]
+    fn owns(&self, name: &[u8]) -> bool {
+        self.shards.index(name) == self.index
+    }
+    // The next shard in the ring.
+    #[inline]
+    fn next(&self) -> usize {
+        (self.index + 1) % self.shards.len()
+    }
+    #[inline]
+    fn is_last(&self) -> bool {
+        self.index + 1 == self.shards.len()
+    }
+    // Pass the request on to another shard, which replies to it.
+    fn forward(&self, index: usize, token: Token, cmd: Command, arg: Vec<u8>) {
+        self.forward_as(index, token, cmd, arg, None);
+    }
+    // Pass the request on with the user of the connection, who restricts it.
+    fn forward_as(
+        &self,
+        index: usize,
+        token: Token,
+        cmd: Command,
+        arg: Vec<u8>,
+        user: Option<Arc<User>>,
+    ) {
+        // The shard has stopped if the server is shutting down.
+        let _ = self.shards.send_to(
+            index,
+            Box::new(Request {
+                token,
+                cmd,
+                arg,
+                user,
+                seq: self.seq,
+            }),
+        );
+    }
+    // A new job ID owned by this shard, so that ACKJOB finds the job at once.
+    fn new_id(&self) -> JobId {
+        loop {
+            let id = new_id();
+            if self.owns(&id) {
+                return id;
+            }
+        }
+    }
+
+    fn configure(&mut self, queues: &HashMap<Vec<u8>, QueueConfig>) {
+        let queues: Vec<(&Vec<u8>, &QueueConfig)> =
+            queues.iter().filter(|(name, _)| self.owns(name)).collect();
+        let mut evict_queues: Vec<Vec<u8>> = queues
+            .iter()
+            .filter(|(_, queue)| queue.evict)
+            .map(|(name, _)| (*name).clone())
+            .collect();
+        evict_queues.sort();
+        self.evict_queues = evict_queues;
+        for (name, config) in queues {
+            if let Some(paused) = config.paused {
+                self.queues
+                    .entry(name.clone())
+                    .or_insert_with(Queue::new)
+                    .paused = paused;
+                self.record_command(Command::CREATEQUE, name);
+                match paused {
+                    true => self.record_command(Command::PAUSEQUE, name),
+                    false => self.record_command(Command::RESUMEQUE, name),
+                }
+            }
+        }
+    }
+    // Apply the configuration reloaded by an I/O thread.
+    fn reload(&mut self) {
+        let config = self.config.clone();
+        let config = config.lock().unwrap();
+        self.memory_limit = config.memory_limit;
+        self.configure(&config.queues);
+    }
+    fn dump(&self) {
+        info!(
+            "Shard {}: memory: {} bytes, all shards: {} / {} bytes, queues: {}, jobs: {}",
+            self.index,
+            self.memory_used,
+            self.memory.load(Ordering::Relaxed),
+            self.memory_limit,
+            self.queues.len(),
+            self.reverse.len()
+        );
+        for (name, queue) in self.queues.iter() {
+            info!(
+                "Queue {}: jobs={} running={} acked={} deleted={} paused={}",
+                String::from_utf8_lossy(name),
+                queue.len(),
+                queue.running_jobs(),
+                queue.acked,
+                queue.deleted,
+                queue.paused
+            );
+        }
+    }
+
+    // Snapshot format: one record per queue followed by its jobs.
+    //   QUE <queue name> <paused: 0 or 1> <acked> <deleted>\n
+    //   JOB <job id> <retry seconds> <job size>\n<job>\n
+    //   RUN <job id> <milliseconds since the epoch> <owner or ->\n
+    // RUN follows running jobs only in the snapshots sent to replicas and other shards.
+    // The file stores them as ready ones, because their workers are gone after a restart.
+    fn write_snapshot<W: Write>(&self, writer: &mut W, leases: bool) -> io::Result<()> {
+        for (name, queue) in self.queues.iter() {
+            writer.write_all(b"QUE ")?;
+            writer.write_all(name)?;
+            writer.write_all(
+                format!(
+                    " {} {} {}\n",
+                    if queue.paused { 1 } else { 0 },
+                    queue.acked,
+                    queue.deleted
+                )
+                .as_bytes(),
+            )?;
+            for job in queue.jobs.iter() {
+                write_job(writer, job, leases)?;
+            }
+        }
+        Ok(())
+    }
+    // Save the snapshots of the other shards, given by `head`, and this one.
+    fn save(&self, path: &Path, head: &[u8]) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        writer.write_all(head)?;
+        self.write_snapshot(&mut writer, false)?;
+        writer.flush()?;
+        drop(writer);
+        fs::rename(&tmp, path)
+    }
+    // Add the queues and the jobs of the snapshot, except the queues of the other shards.
+    // The jobs before any QUE record are added to `queue`, which must exist. The owners
+    // of the running jobs are kept if `owners` is set, i.e. the snapshot is of this server.
+    fn read_snapshot<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+        queue: Option<&[u8]>,
+        owners: bool,
+    ) -> io::Result<()> {
+        let mut line = Vec::new();
+        let mut current: Option<Vec<u8>> = queue.map(<[u8]>::to_vec);
+        let mut owned = true;
+        loop {
+            line.clear();
+            if reader.read_until(TERMINATION, &mut line)? == 0 {
+                break;
+            }
+            if line.pop() != Some(TERMINATION) {
+                return Err(invalid_data("truncated snapshot"));
+            }
+            let mut iter = line.split(is_delimiter);
+            match iter.next() {
+                Some(b"QUE") => {
+                    let name = next!(iter).ok_or_else(|| invalid_data("missing queue name"))?;
+                    owned = self.owns(name);
+                    if !owned {
+                        continue;
+                    }
+                    let mut queue = Queue::new();
+                    queue.paused = next!(iter) == Some(b"1");
+                    // The counters are absent in the files saved by older versions.
+                    if let Some(acked) = next!(iter) {
+                        queue.acked = parse(Some(acked))?;
+                        queue.deleted = parse(next!(iter))?;
+                    }
+                    self.queues.insert(name.to_vec(), queue);
+                    current = Some(name.to_vec());
+                }
+                Some(b"JOB") => {
+                    let mut id = [0; JOB_ID_SIZE];
+                    match next!(iter) {
+                        Some(buf) if buf.len() == JOB_ID_SIZE => id.copy_from_slice(buf),
+                        _ => return Err(invalid_data("invalid job id")),
+                    }
+                    let secs = parse::<u64>(next!(iter))?;
+                    let size = parse::<usize>(next!(iter))?;
+                    let mut job = vec![0; size + 1];
+                    reader.read_exact(&mut job)?;
+                    if job.pop() != Some(TERMINATION) {
+                        return Err(invalid_data("invalid job size"));
+                    }
+                    if !owned {
+                        continue;
+                    }
+                    let name = current
+                        .as_ref()
+                        .ok_or_else(|| invalid_data("job without queue"))?;
+                    let job = Job::with_id(id, job.into(), Duration::from_secs(secs));
+                    self.alloc(job.size());
+                    self.reverse.insert(id, name.clone());
+                    self.queues.get_mut(name).unwrap().add(job);
+                }
+                Some(b"RUN") if !owned => {}
+                Some(b"RUN") => {
+                    let id = next!(iter);
+                    // The start time and the owner are absent in the snapshots of older versions.
+                    let start = match next!(iter) {
+                        Some(millis) => UNIX_EPOCH + Duration::from_millis(parse(Some(millis))?),
+                        None => SystemTime::now(),
+                    };
+                    let owner = next!(iter)
+                        .filter(|_| owners)
+                        .and_then(|owner| parse::<usize>(Some(owner)).ok())
+                        .map(Token);
+                    let job = id
+                        .and_then(|id| self.job_mut(id))
+                        .ok_or_else(|| invalid_data("unknown job id"))?;
+                    job.run(owner, start);
+                }
+                _ => return Err(invalid_data("unknown record")),
+            }
+        }
+        Ok(())
+    }
+    fn load(&mut self, path: &Path) -> io::Result<()> {
+        let mut reader = match File::open(path) {
+            Ok(file) => BufReader::new(file),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        self.read_snapshot(&mut reader, None, false)?;
+        info!(
+            "Shard {}: loaded {} jobs from {}",
+            self.index,
+            self.reverse.len(),
+            path.display()
+        );
+        Ok(())
+    }
+    fn job_mut(&mut self, job_id: &[u8]) -> Option<&mut Job> {
+        let name = self.reverse.get(job_id)?;
+        self.queues.get_mut(name)?.get_mut(job_id)
+    }
+    // Called when the connection is closed.
+    fn disconnect(&mut self, token: Token) {
+        self.replicas.retain(|replica| *replica != token);
+        if !self.requeue_on_disconnect {
+            return;
+        }
+        if let Some(cluster) = self.cluster.as_mut() {
+            // Only the leader knows the owners. The jobs are returned through the log.
+            if cluster.is_leader() {
+                for job in self.queues.values().flat_map(|queue| queue.jobs.iter()) {
+                    if job.running && job.owner == Some(token) {
+                        cluster.propose([b"READY ", &job.id[..]].concat());
+                    }
+                }
+            }
+            return;
+        }
+        let ids: Vec<JobId> = self
+            .queues
+            .values_mut()
+            .flat_map(|queue| queue.release(token))
+            .collect();
+        if !ids.is_empty() {
+            debug!("Requeued {} jobs of connection {}", ids.len(), token.0);
+        }
+        for id in ids {
+            self.record([b"READY ", &id[..]].concat());
         }
     }
 
+    // Keep the change to push it to the replicas.
+    fn record(&mut self, record: Vec<u8>) {
+        if !self.replicas.is_empty() {
+            self.records.push(record);
+        }
+    }
+    fn record_command(&mut self, cmd: Command, arg: &[u8]) {
+        self.record([cmd.as_str(), b" ", arg].concat());
+    }
+    // Send the records to the replicas as `2 <record size> <record>`. A record is shared
+    // by the replies to all replicas.
+    fn publish(&mut self, workers: &Workers, woken: &mut [bool]) {
+        for record in self.records.drain(..) {
+            let data = format!("{} ", record.len()).into_bytes();
+            let record: Arc<[u8]> = record.into();
+            for token in self.replicas.iter() {
+                woken[workers.index(*token)] = true;
+                // The records are not replies to requests.
+                workers.send(
+                    0,
+                    Box::new(Reply {
+                        token: *token,
+                        status: PUSH,
+                        data: data.clone(),
+                        payload: Some(record.clone()),
+                    }),
+                );
+            }
+        }
+    }
+    #[inline]
+    fn handle_sync(&mut self, req: &Request) -> Option<Reply> {
+        // command: SYNC
+        self.sync(req.token, Vec::new())
+    }
+    // Add the snapshot of this shard to the ones of the previous shards, and pass it on
+    // to the next shard. The last one replies with the whole snapshot.
+    fn sync(&mut self, token: Token, mut snapshot: Vec<u8>) -> Option<Reply> {
+        // Writing to a Vec never fails.
+        self.write_snapshot(&mut snapshot, true).unwrap();
+        if !self.replicas.contains(&token) {
+            self.replicas.push(token);
+        }
+        info!(
+            "Replica {} synced {} jobs of shard {}",
+            token.0,
+            self.reverse.len(),
+            self.index
+        );
+        if !self.is_last() {
+            let arg = [b"SYNC ", snapshot.as_slice()].concat();
+            self.forward(self.index + 1, token, Command::SHARD, arg);
+            return None;
+        }
+        Some(Reply {
+            token,
+            status: 1,
+            // data: b"<snapshot size> ", payload: b"<snapshot>"
+            data: format!("{} ", snapshot.len()).into_bytes(),
+            payload: Some(snapshot.into()),
+        })
+    }
+    // Apply a snapshot or a record of the primary. The records are passed on to
+    // the replicas of this server as they are. A record of an unknown job is passed on
+    // to the next shard until `hops` reaches the number of the shards.
+    fn replicate(&mut self, record: &[u8], hops: usize) -> io::Result<()> {
+        let mut iter = record.splitn(2, is_delimiter);
+        let kind = iter.next().unwrap_or_default();
+        let arg = iter.next().unwrap_or_default();
+        match kind {
+            b"SNAPSHOT" => {
+                self.queues.clear();
+                self.reverse.clear();
+                self.free(self.memory_used);
+                // The owners are the connections of the primary.
+                self.read_snapshot(&mut &arg[..], None, false)?;
+                info!(
+                    "Shard {}: synced {} jobs from the primary",
+                    self.index,
+                    self.reverse.len()
+                );
+                return Ok(());
+            }
+            b"ADD" => {
+                let (name, job) = restore_job(arg)?;
+                self.alloc(job.size());
+                self.reverse.insert(job.id, name.to_vec());
+                self.queues
+                    .entry(name.to_vec())
+                    .or_insert_with(Queue::new)
+                    .add(job);
+            }
+            b"RUN" | b"READY" | b"DROP" if !self.reverse.contains_key(arg) => {
+                if hops >= self.shards.len() {
+                    return Err(invalid_data("unknown job id"));
+                }
+                let arg = [format!("REPLICATE {} ", hops + 1).as_bytes(), record].concat();
+                let index = (self.index + 1) % self.shards.len();
+                self.forward(index, Token(0), Command::SHARD, arg);
+                return Ok(());
+            }
+            b"RUN" | b"READY" => {
+                let job = self
+                    .job_mut(arg)
+                    .ok_or_else(|| invalid_data("unknown job id"))?;
+                match kind {
+                    b"RUN" => job.run(None, SystemTime::now()),
+                    _ => job.ready(),
+                }
+            }
+            b"DROP" => {
+                let job = self
+                    .reverse
+                    .remove(arg)
+                    .and_then(|name| self.queues.get_mut(&name)?.remove(arg))
+                    .ok_or_else(|| invalid_data("unknown job id"))?;
+                self.free(job.size());
+            }
+            _ => {
+                let req = Request {
+                    token: Token(0),
+                    cmd: Command::from(kind)
+                        .filter(replayed)
+                        .ok_or_else(|| invalid_data("unknown record"))?,
+                    arg: arg.to_vec(),
+                    user: None,
+                    seq: 0,
+                };
+                // The command is recorded again by itself.
+                self.handle(&req);
+                return Ok(());
+            }
+        }
+        self.record(record.to_vec());
+        Ok(())
+    }
+
+    // Called on shutdown. The snapshots of the shards are passed on from one to
+    // another, and the last one saves them all to the file.
+    fn persist(&self, mut snapshot: Vec<u8>) {
+        if !self.is_last() {
+            if self.persist.is_some() {
+                // Writing to a Vec never fails.
+                self.write_snapshot(&mut snapshot, false).unwrap();
+            }
+            self.forward(self.index + 1, Token(0), Command::TERMINATE, snapshot);
+            return;
+        }
+        if let Some(path) = self.persist.as_ref() {
+            match self.save(path, &snapshot) {
+                Ok(_) => info!("Saved the queues to {}", path.display()),
+                Err(err) => error!("Failed to save {}: {}", path.display(), err),
+            }
+        }
+    }
+
+    // Start a thread for each shard, which receives the requests by `receivers`.
     pub fn run(
-        waker: Arc<Waker>,
-        sender: Sender<Box<Reply>>,
-        receiver: Receiver<Box<Request>>,
-    ) -> JoinHandle<()> {
-        let mut manager = QueueManager::new();
-        thread::spawn(move || {
-            for req in receiver.iter() {
+        shared: &Arc<Mutex<Config>>,
+        workers: Workers,
+        shards: &Shards,
+        receivers: Vec<Receiver<Box<Request>>>,
+    ) -> io::Result<Vec<JoinHandle<()>>> {
+        let config = shared.lock().unwrap();
+        if config.shards == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "shards must be positive",
+            ));
+        }
+        if !config.cluster.is_empty() {
+            // The nodes restore the queues from the log of the cluster.
+            if config.replica_of.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "replica_of is not supported in the cluster mode",
+                ));
+            }
+            // Only the peers may send RAFT, which they authenticate by.
+            if config.cluster_auth.is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "cluster_auth is required in the cluster mode",
+                ));
+            }
+            if shards.len() > 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "shards are not supported in the cluster mode",
+                ));
+            }
+        }
+        let mut managers = Vec::with_capacity(receivers.len());
+        let memory = Arc::new(AtomicUsize::new(0));
+        for (index, receiver) in receivers.into_iter().enumerate() {
+            let mut manager = QueueManager::new(
+                &config,
+                shared.clone(),
+                memory.clone(),
+                index,
+                shards.clone(),
+            );
+            if !config.cluster.is_empty() {
+                manager.cluster = Some(Cluster::new(&config, shards.sender(index))?);
+                // The file keeps the log of the cluster instead of the queues.
+                manager.persist = None;
+            }
+            if let Some(path) = manager.persist.clone() {
+                manager.load(&path)?;
+            }
+            manager.configure(&config.queues);
+            managers.push((manager, receiver));
+        }
+        Ok(managers
+            .into_iter()
+            .map(|(manager, receiver)| {
+                let workers = workers.clone();
+                thread::spawn(move || manager.serve(receiver, workers))
+            })
+            .collect())
+    }
+    fn serve(mut self, receiver: Receiver<Box<Request>>, workers: Workers) {
+        loop {
+            // Wake up for the timers of the cluster, even if no request comes.
+            let req = match self.cluster.as_ref() {
+                Some(cluster) => match receiver.recv_timeout(cluster.timeout()) {
+                    Ok(req) => Some(req),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                },
+                None => match receiver.recv() {
+                    Ok(req) => Some(req),
+                    Err(_) => return,
+                },
+            };
+            if let Some(req) = req {
                 debug!(
                     "Catch request: {:?} {:?} {:?} [{:p}]",
                     req.token,
@@ -139,27 +885,339 @@ impl QueueManager {
                     req.arg.len(),
                     req
                 );
-                let res = Box::new(match req.cmd {
-                    Command::ADDJOB => manager.handle_addjob(&req),
-                    Command::GETJOB => manager.handle_getjob(&req),
-                    Command::ACKJOB => manager.handle_ackjob(&req),
-                    Command::STATQUE => manager.handle_statque(&req),
-                    Command::DELQUE => manager.handle_delque(&req),
-                    Command::TERMINATE => return,
-                    Command::QUIT => manager.handle_quit(&req),
-                    Command::HELLO => manager.handle_hello(&req),
-                });
-                debug!(
-                    "Send reply: {:?} {:?} {:?} [{:p}]",
-                    res.token,
-                    res.status,
-                    res.data.len(),
-                    res
-                );
-                sender.send(res).unwrap();
-                waker.wake().expect("unable to wake");
+                match req.cmd {
+                    Command::TERMINATE => {
+                        self.persist(req.arg);
+                        return;
+                    }
+                    Command::RELOAD => self.reload(),
+                    Command::DUMP => self.dump(),
+                    Command::DISCONNECT => self.disconnect(req.token),
+                    Command::REPLICATE => {
+                        if let Err(err) = self.replicate(&req.arg, 1) {
+                            error!("Failed to replicate: {}", err);
+                        }
+                    }
+                    Command::PEER => {
+                        if let Some(cluster) = self.cluster.as_mut() {
+                            cluster.handle_reply(&req.arg);
+                        }
+                    }
+                    _ => {
+                        self.seq = req.seq;
+                        self.request(&req);
+                    }
+                }
             }
-        })
+            self.tick();
+            self.flush(&workers);
+        }
+    }
+    // Send the replies and push the records to the replicas, then wake the I/O threads
+    // which own the connections.
+    fn flush(&mut self, workers: &Workers) {
+        if self.replies.is_empty() && self.records.is_empty() {
+            return;
+        }
+        let mut woken = vec![false; workers.len()];
+        for (seq, res) in self.replies.drain(..) {
+            // The requests made by the queue manager itself, e.g. the records replayed
+            // on replicas, are not replied.
+            if res.token == Token(0) {
+                continue;
+            }
+            let res = Box::new(res);
+            debug!(
+                "Send reply: {:?} {:?} {:?} [{:p}]",
+                res.token,
+                res.status,
+                res.data.len(),
+                res
+            );
+            woken[workers.index(res.token)] = true;
+            workers.send(seq, res);
+        }
+        self.publish(workers, &mut woken);
+        for (index, _) in woken.iter().enumerate().filter(|(_, woken)| **woken) {
+            workers.wake(index);
+        }
+    }
+    // Handle the request. The commands changing the queues are proposed to the
+    // cluster instead, and replied when they are committed.
+    fn request(&mut self, req: &Request) {
+        let restricted;
+        let req = match (req.user.as_ref(), req.cmd) {
+            (Some(user), Command::ACKJOB | Command::DELJOB) if self.cluster.is_some() => {
+                restricted = self.restrict(req, user);
+                &restricted
+            }
+            _ => req,
+        };
+        let res = match self.cluster.as_mut() {
+            Some(cluster) if req.cmd.is_write() => match cluster.leader() {
+                _ if cluster.is_leader() => match entry(req) {
+                    Ok(entry) => {
+                        let index = cluster.propose(entry);
+                        self.pending.insert(index, (req.token, req.seq));
+                        return;
+                    }
+                    Err(res) => res,
+                },
+                Some(leader) => Reply::redirect(req.token, leader),
+                None => Reply::no_leader(req.token),
+            },
+            Some(cluster) if req.cmd == Command::RAFT => match cluster.handle_message(&req.arg) {
+                Some(data) => Reply {
+                    token: req.token,
+                    status: 1,
+                    data,
+                    payload: None,
+                },
+                None => Reply::error(req.token),
+            },
+            _ => match self.handle(req) {
+                Some(res) => res,
+                // Replied by another shard.
+                None => return,
+            },
+        };
+        self.replies.push((req.seq, res));
+    }
+    // Leave out the jobs of the queues which the user may not use, since the nodes
+    // apply the entry without the user.
+    fn restrict(&self, req: &Request, user: &User) -> Request {
+        let ids: Vec<&[u8]> = req
+            .arg
+            .split(is_delimiter)
+            .filter(|id| match self.reverse.get(*id) {
+                Some(name) => user.permits_queue(name),
+                None => true,
+            })
+            .collect();
+        Request {
+            token: req.token,
+            cmd: req.cmd,
+            arg: ids.join(b" ".as_ref()),
+            user: None,
+            seq: req.seq,
+        }
+    }
+    // Run the timers of the cluster, and apply the committed entries.
+    fn tick(&mut self) {
+        let cluster = match self.cluster.as_mut() {
+            Some(cluster) => cluster,
+            None => return,
+        };
+        cluster.tick();
+        let mut entries = Vec::new();
+        while let Some(entry) = cluster.next_committed() {
+            entries.push(entry);
+        }
+        let leader = match cluster.is_leader() {
+            true => None,
+            false => Some(cluster.leader().map(str::to_owned)),
+        };
+        for (index, entry) in entries {
+            self.apply(index, &entry);
+        }
+        // The commands which are not committed yet may be lost with the leadership.
+        if let Some(leader) = leader {
+            for (_, (token, seq)) in self.pending.drain() {
+                self.replies.push((
+                    seq,
+                    match leader.as_ref() {
+                        Some(leader) => Reply::redirect(token, leader),
+                        None => Reply::no_leader(token),
+                    },
+                ));
+            }
+        }
+    }
+    // Apply the committed entry, and reply if it has been proposed by this node.
+    fn apply(&mut self, index: usize, entry: &[u8]) {
+        let (owner, seq) = match self.pending.remove(&index) {
+            Some((token, seq)) => (Some(token), seq),
+            None => (None, 0),
+        };
+        let token = owner.unwrap_or(Token(0));
+        self.seq = seq;
+        let mut iter = entry.splitn(2, is_delimiter);
+        let kind = iter.next().unwrap_or_default();
+        let arg = iter.next().unwrap_or_default();
+        let res = match kind {
+            // Added by a new leader.
+            b"" => return,
+            b"ADD" => Some(match restore_job(arg) {
+                Ok((name, job)) => self.add_job(token, name, job, 0),
+                Err(_) => Reply::error(token),
+            }),
+            b"GETJOB" => {
+                let mut iter = arg.splitn(2, is_delimiter);
+                match parse::<u64>(iter.next()) {
+                    Ok(millis) => self.get_job(
+                        token,
+                        iter.next().unwrap_or_default(),
+                        owner,
+                        UNIX_EPOCH + Duration::from_millis(millis),
+                    ),
+                    Err(_) => Some(Reply::error(token)),
+                }
+            }
+            b"READY" => {
+                if let Some(job) = self.job_mut(arg) {
+                    job.ready();
+                    self.record(entry.to_vec());
+                }
+                return;
+            }
+            _ => match Command::from(kind).filter(replayed) {
+                Some(cmd) => self.handle(&Request {
+                    token,
+                    cmd,
+                    arg: arg.to_vec(),
+                    user: None,
+                    seq,
+                }),
+                None => Some(Reply::error(token)),
+            },
+        };
+        if let (Some(_), Some(res)) = (owner, res) {
+            self.replies.push((seq, res));
+        }
+    }
+    // Handle the request, or pass it on to another shard, which replies to it instead.
+    fn handle(&mut self, req: &Request) -> Option<Reply> {
+        let res = match req.cmd {
+            Command::ADDJOB => return self.handle_addjob(req),
+            Command::GETJOB => return self.handle_getjob(req),
+            // They record the jobs removed by this shard by themselves.
+            Command::ACKJOB => return self.handle_ackjob(req),
+            Command::DELJOB => return self.handle_deljob(req),
+            Command::STATQUE => self.handle_statque(req),
+            Command::DELQUE => self.handle_delque(req),
+            Command::CREATEQUE => self.handle_createque(req),
+            Command::PAUSEQUE => self.handle_pauseque(req, true),
+            Command::RESUMEQUE => self.handle_pauseque(req, false),
+            Command::PURGEQUE => self.handle_purgeque(req),
+            Command::MOVEQUE => self.handle_moveque(req)?,
+            Command::REQUEUE => self.handle_requeue(req),
+            Command::QUIT => self.handle_quit(req),
+            Command::HELLO => self.handle_hello(req),
+            Command::CLIENT => return self.handle_client(req),
+            Command::SYNC => return self.handle_sync(req),
+            Command::SHARD => return self.handle_shard(req),
+            // Handled by the server.
+            Command::AUTH | Command::PROMOTE => Reply::error(req.token),
+            // Handled by `request` in the cluster mode.
+            Command::RAFT => Reply::error(req.token),
+            // Handled by `serve`.
+            Command::TERMINATE
+            | Command::RELOAD
+            | Command::DUMP
+            | Command::DISCONNECT
+            | Command::REPLICATE
+            | Command::PEER => Reply::error(req.token),
+        };
+        if res.status == 1 && replayed(&req.cmd) {
+            self.record_command(req.cmd, &req.arg);
+        }
+        Some(res)
+    }
+    // Take the memory for the job, of which `credit` bytes are held by the jobs evicted
+    // by the other shards. The ready jobs of the evictable queues of this shard are taken
+    // out for the rest only if they are enough, and are dropped only once the memory is
+    // taken. Otherwise they are put back.
+    fn reserve(&mut self, size: usize, credit: usize) -> bool {
+        if self.memory_limit == 0 {
+            self.alloc(size);
+            return true;
+        }
+        let need = self.shortage(size).saturating_sub(credit);
+        if need > self.evictable() {
+            return false;
+        }
+        let evicted = self.evict(need);
+        let freed = credit + evicted.iter().map(|(_, _, job)| job.size()).sum::<usize>();
+        // The evicted jobs are still counted, and another shard may have taken the room
+        // meanwhile.
+        let limit = self.memory_limit;
+        let reserved = self
+            .memory
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                let used = used - freed + size;
+                (used <= limit).then_some(used)
+            })
+            .is_ok();
+        match reserved {
+            true => {
+                self.memory_used += size;
+                self.drop_evicted(evicted);
+            }
+            false => self.put_back(evicted),
+        }
+        reserved
+    }
+    #[inline]
+    fn alloc(&mut self, size: usize) {
+        self.memory_used += size;
+        self.memory.fetch_add(size, Ordering::Relaxed);
+    }
+    #[inline]
+    fn free(&mut self, size: usize) {
+        self.memory_used -= size;
+        self.memory.fetch_sub(size, Ordering::Relaxed);
+    }
+    // The bytes to free for the job of the size.
+    fn shortage(&self, size: usize) -> usize {
+        match self.memory_limit {
+            0 => 0,
+            limit => (self.memory.load(Ordering::Relaxed) + size).saturating_sub(limit),
+        }
+    }
+    // The bytes of the ready jobs of the evictable queues of this shard.
+    fn evictable(&self) -> usize {
+        self.evict_queues
+            .iter()
+            .filter_map(|name| self.queues.get(name))
+            .map(|queue| queue.ready_size())
+            .sum()
+    }
+    // Take the oldest ready jobs out of the evictable queues until the bytes are freed.
+    // They are still counted in the memory until they are dropped.
+    fn evict(&mut self, bytes: usize) -> Evicted {
+        let mut freed = 0;
+        let mut evicted = Vec::new();
+        for name in self.evict_queues.iter() {
+            if let Some(queue) = self.queues.get_mut(name) {
+                while freed < bytes {
+                    match queue.evict() {
+                        Some((i, job)) => {
+                            freed += job.size();
+                            evicted.push((name.clone(), i, job));
+                        }
+                        None => break,
+                    }
+                }
+            }
+        }
+        evicted
+    }
+    // Drop the evicted jobs, whose memory has been taken from the shared count.
+    fn drop_evicted(&mut self, evicted: Evicted) {
+        for (_, _, job) in evicted {
+            debug!("Evict job: {:?}", from_utf8(&job.id));
+            self.memory_used -= job.size();
+            self.reverse.remove(&job.id);
+            self.record([b"DROP ", &job.id[..]].concat());
+        }
+    }
+    fn put_back(&mut self, evicted: Evicted) {
+        for (name, i, job) in evicted.into_iter().rev() {
+            self.queues
+                .entry(name)
+                .or_insert_with(Queue::new)
+                .put_back(i, job);
+        }
     }
     #[inline]
     fn handle_quit(&mut self, req: &Request) -> Reply {
@@ -167,6 +1225,7 @@ impl QueueManager {
             token: req.token,
             status: 0,
             data: vec![0; 0],
+            payload: None,
         }
     }
     #[inline]
@@ -175,33 +1234,252 @@ impl QueueManager {
             token: req.token,
             status: 0,
             data: b"Hello".to_vec(),
+            payload: None,
         }
     }
     #[inline]
-    fn handle_addjob(&mut self, req: &Request) -> Reply {
+    fn handle_client(&mut self, req: &Request) -> Option<Reply> {
+        // command: CLIENT LIST
+        // The server gives the connections line by line, each of which starts with `id=<token>`.
+        let mut lines = req.arg.split(|b| *b == b'\n');
+        if lines.next() != Some(b"LIST") {
+            return Some(Reply::error(req.token));
+        }
+        self.client_list(req.token, lines.map(|line| (0, line)).collect())
+    }
+    // Add the number of the jobs leased by each connection in this shard to the ones
+    // counted by the previous shards. The last shard replies.
+    fn client_list(&mut self, token: Token, entries: Vec<(usize, &[u8])>) -> Option<Reply> {
+        let mut leased: HashMap<Token, usize> = HashMap::new();
+        for job in self.queues.values().flat_map(|queue| queue.jobs.iter()) {
+            if let (true, Some(owner)) = (job.is_leased(), job.owner) {
+                *leased.entry(owner).or_default() += 1;
+            }
+        }
+        let entries = entries.into_iter().map(|(count, line)| {
+            let token = from_utf8(line)
+                .ok()
+                .and_then(|line| line.strip_prefix("id="))
+                .and_then(|line| line.split(' ').next())
+                .and_then(|id| id.parse().ok())
+                .map(Token);
+            (
+                count + token.and_then(|t| leased.get(&t)).unwrap_or(&0),
+                line,
+            )
+        });
+        if !self.is_last() {
+            // arg: b"CLIENT <count> <connection>\n...\n<count> <connection>"
+            let lines: Vec<Vec<u8>> = entries
+                .map(|(count, line)| [format!("{} ", count).as_bytes(), line].concat())
+                .collect();
+            let arg = [b"CLIENT ".as_ref(), &lines.join(b"\n".as_ref())].concat();
+            self.forward(self.index + 1, token, Command::SHARD, arg);
+            return None;
+        }
+        let entries: Vec<Vec<u8>> = entries
+            .map(|(count, line)| [line, format!(" leased={}", count).as_bytes()].concat())
+            .collect();
+        Some(Reply {
+            token,
+            status: 1,
+            // data: b"<connection>; ...; <connection>"
+            data: entries.join(b"; ".as_ref()),
+            payload: None,
+        })
+    }
+    // command: SHARD <kind> <arg>, passed on by another shard to continue the request.
+    fn handle_shard(&mut self, req: &Request) -> Option<Reply> {
+        let mut iter = req.arg.splitn(2, is_delimiter);
+        let kind = iter.next().unwrap_or_default();
+        let arg = iter.next().unwrap_or_default();
+        match kind {
+            b"ACKJOB" | b"DELJOB" => {
+                // arg: b"<hops> <count> <job id> ... <job id>"
+                let mut iter = arg.splitn(3, is_delimiter);
+                match (
+                    Command::from(kind),
+                    parse::<usize>(iter.next()),
+                    parse::<usize>(iter.next()),
+                ) {
+                    (Some(cmd), Ok(hops), Ok(count)) => {
+                        let ids = iter.next().unwrap_or_default();
+                        self.remove_jobs(req, cmd, ids, hops, count)
+                    }
+                    _ => Some(Reply::error(req.token)),
+                }
+            }
+            b"COUNT" | b"EVICT" => {
+                // arg: b"<bytes> <freed bytes> <queue name> <job id> <retry seconds> <job>"
+                let mut iter = arg.splitn(3, is_delimiter);
+                let bytes = parse::<usize>(iter.next());
+                let freed = parse::<usize>(iter.next());
+                let record = iter.next().unwrap_or_default();
+                let mut fields = record.split(is_delimiter);
+                let name = fields.next().unwrap_or_default();
+                match (bytes, freed, self.owns(name)) {
+                    (Ok(bytes), Ok(freed), false) => {
+                        // COUNT adds the bytes this shard can free, and EVICT takes the jobs
+                        // out until the bytes are freed, and keeps them until the job is added.
+                        let (bytes, freed) = match (kind, fields.next().and_then(job_id)) {
+                            (b"COUNT", _) => (bytes + self.evictable(), freed),
+                            (_, Some(id)) => {
+                                let evicted = self.evict(bytes.saturating_sub(freed));
+                                let size: usize =
+                                    evicted.iter().map(|(_, _, job)| job.size()).sum();
+                                if !evicted.is_empty() {
+                                    self.evicted.insert(id, evicted);
+                                }
+                                (bytes, freed + size)
+                            }
+                            _ => return Some(Reply::error(req.token)),
+                        };
+                        let arg =
+                            [kind, format!(" {} {} ", bytes, freed).as_bytes(), record].concat();
+                        self.forward(self.next(), req.token, Command::SHARD, arg);
+                        None
+                    }
+                    (Ok(bytes), Ok(freed), true) => {
+                        let (name, job) = match restore_job(record) {
+                            Ok(res) => res,
+                            Err(_) => return Some(Reply::error(req.token)),
+                        };
+                        if kind == b"EVICT" {
+                            let id = job.id;
+                            let reply = self.add_job(req.token, name, job, freed);
+                            // The other shards drop the jobs evicted for the job, or put them back.
+                            let added = if reply.status == 1 { b"1 " } else { b"0 " };
+                            let arg = [b"EVICTED ".as_ref(), added, &id[..]].concat();
+                            for index in (0..self.shards.len()).filter(|i| *i != self.index) {
+                                self.forward(index, req.token, Command::SHARD, arg.clone());
+                            }
+                            return Some(reply);
+                        }
+                        let need = self.shortage(job.size()).saturating_sub(self.evictable());
+                        if need == 0 {
+                            Some(self.add_job(req.token, name, job, 0))
+                        } else if bytes < need {
+                            Some(Reply::out_of_memory(req.token))
+                        } else {
+                            // The rest is freed by this shard when the job is added.
+                            let arg = [format!("EVICT {} 0 ", need).as_bytes(), record].concat();
+                            self.forward(self.next(), req.token, Command::SHARD, arg);
+                            None
+                        }
+                    }
+                    _ => Some(Reply::error(req.token)),
+                }
+            }
+            // arg: b"<1 if the job is added, or 0> <job id>"
+            b"EVICTED" => {
+                let mut iter = arg.splitn(2, is_delimiter);
+                let added = iter.next() == Some(b"1");
+                let evicted = iter
+                    .next()
+                    .and_then(job_id)
+                    .and_then(|id| self.evicted.remove(&id));
+                match (evicted, added) {
+                    (Some(evicted), true) => self.drop_evicted(evicted),
+                    (Some(evicted), false) => self.put_back(evicted),
+                    (None, _) => {}
+                }
+                None
+            }
+            b"MOVEQUE" => {
+                // arg: b"<destination queue name> <count>\n<jobs in the snapshot format>"
+                let mut iter = arg.splitn(2, |b| *b == TERMINATION);
+                let mut head = iter.next().unwrap_or_default().split(is_delimiter);
+                let mut jobs = iter.next().unwrap_or_default();
+                let (dst, count) = match (head.next(), parse::<usize>(head.next())) {
+                    (Some(dst), Ok(count)) => (dst, count),
+                    _ => return Some(Reply::error(req.token)),
+                };
+                self.queues.entry(dst.to_vec()).or_insert_with(Queue::new);
+                if let Err(err) = self.read_snapshot(&mut jobs, Some(dst), true) {
+                    error!("Failed to move jobs: {}", err);
+                    return Some(Reply::error(req.token));
+                }
+                Some(Reply {
+                    token: req.token,
+                    status: 1,
+                    data: count.to_string().into_bytes(),
+                    payload: None,
+                })
+            }
+            // arg: b"<snapshot>"
+            b"SYNC" => self.sync(req.token, arg.to_vec()),
+            // arg: b"<count> <connection>\n...\n<count> <connection>"
+            b"CLIENT" => {
+                let entries = arg
+                    .split(|b| *b == b'\n')
+                    .map(|line| {
+                        let mut iter = line.splitn(2, |b| *b == b' ');
+                        let count = parse::<usize>(iter.next()).unwrap_or(0);
+                        (count, iter.next().unwrap_or_default())
+                    })
+                    .collect();
+                self.client_list(req.token, entries)
+            }
+            // arg: b"<hops> <record>"
+            b"REPLICATE" => {
+                let mut iter = arg.splitn(2, is_delimiter);
+                let res = parse::<usize>(iter.next())
+                    .and_then(|hops| self.replicate(iter.next().unwrap_or_default(), hops));
+                if let Err(err) = res {
+                    error!("Failed to replicate: {}", err);
+                }
+                None
+            }
+            _ => Some(Reply::error(req.token)),
+        }
+    }
+    #[inline]
+    fn handle_addjob(&mut self, req: &Request) -> Option<Reply> {
         // command: ADDJOB <queue name> <retry seconds> <job>
         let mut iter = req.arg.split(is_delimiter);
 
         let queue_name = match next!(iter) {
             Some(name) => name,
-            None => return Reply::error(req.token),
+            None => return Some(Reply::error(req.token)),
         };
 
         let secs = match next!(iter) {
             Some(buf) => match from_utf8(buf) {
                 Ok(s) => match s.parse::<u64>() {
                     Ok(secs) => secs,
-                    Err(_err) => return Reply::error(req.token),
+                    Err(_err) => return Some(Reply::error(req.token)),
                 },
-                Err(_err) => return Reply::error(req.token),
+                Err(_err) => return Some(Reply::error(req.token)),
             },
-            None => return Reply::error(req.token),
+            None => return Some(Reply::error(req.token)),
         };
 
         let job = match next!(iter) {
-            Some(job) => Job::new(job.to_vec(), Duration::from_secs(secs)),
-            None => return Reply::error(req.token),
+            Some(job) => Job::with_id(self.new_id(), job.into(), Duration::from_secs(secs)),
+            None => return Some(Reply::error(req.token)),
         };
+        // The other shards count the memory they can free for the job.
+        if self.shards.len() > 1 && self.shortage(job.size()) > self.evictable() {
+            let record = [
+                b"COUNT 0 0 ",
+                queue_name,
+                b" ",
+                &job.id[..],
+                format!(" {} ", secs).as_bytes(),
+                &job.job,
+            ]
+            .concat();
+            self.forward(self.next(), req.token, Command::SHARD, record);
+            return None;
+        }
+        Some(self.add_job(req.token, queue_name, job, 0))
+    }
+    // `credit` is the memory held for the job by the jobs evicted by the other shards.
+    fn add_job(&mut self, token: Token, queue_name: &[u8], job: Job, credit: usize) -> Reply {
+        let size = job.size();
+        if !self.reserve(size, credit) {
+            return Reply::out_of_memory(token);
+        }
 
         let queue = match self.queues.get_mut(queue_name) {
             Some(queue) => queue,
@@ -211,60 +1489,160 @@ impl QueueManager {
             }
         };
         let job_id = job.id;
+        let retry = job.retry.as_secs();
+        let record = [
+            b"ADD ",
+            queue_name,
+            b" ",
+            &job_id[..],
+            format!(" {} ", retry).as_bytes(),
+            &job.job,
+        ]
+        .concat();
+        // The memory has been taken by reserve.
         queue.add(job);
         self.reverse.insert(job_id, queue_name.to_vec());
+        self.record(record);
         Reply {
-            token: req.token,
+            token,
             status: 1,
             data: job_id.to_vec(),
+            payload: None,
         }
     }
     #[inline]
-    fn handle_getjob(&mut self, req: &Request) -> Reply {
+    fn handle_getjob(&mut self, req: &Request) -> Option<Reply> {
         // command: GETJOB <queue name> ... <queue name>
-        for name in req.arg.split(is_delimiter) {
-            if name.len() == 0 {
-                continue;
+        self.get_job(req.token, &req.arg, Some(req.token), SystemTime::now())
+    }
+    // Get a job from the queues in order. The rest of the queues from the first one of
+    // another shard are passed on to the shard.
+    fn get_job(
+        &mut self,
+        token: Token,
+        names: &[u8],
+        owner: Option<Token>,
+        now: SystemTime,
+    ) -> Option<Reply> {
+        let names: Vec<&[u8]> = names
+            .split(is_delimiter)
+            .filter(|name| !name.is_empty())
+            .collect();
+        for (i, name) in names.iter().enumerate() {
+            let index = self.shards.index(name);
+            if index != self.index {
+                let arg = names[i..].join(b" ".as_ref());
+                self.forward(index, token, Command::GETJOB, arg);
+                return None;
             }
-            if let Some(queue) = self.queues.get_mut(name) {
-                if let Some(job) = queue.get() {
-                    return Reply {
-                        token: req.token,
+            if let Some(queue) = self.queues.get_mut(*name) {
+                if queue.paused {
+                    continue;
+                }
+                if let Some(job) = queue.get(owner, now) {
+                    let job_id = job.id;
+                    // data: b"<job id> ", payload: b"<job data>"
+                    let data = [&job.id[..], b" "].concat();
+                    let payload = job.job.clone();
+                    self.record([b"RUN ", &job_id[..]].concat());
+                    return Some(Reply {
+                        token,
                         status: 1,
-                        // data: b"<job id> <job data>"
-                        data: [&job.id[..], b" ", job.job.as_slice()].concat(),
-                    };
+                        data,
+                        payload: Some(payload),
+                    });
                 }
             }
         }
-        Reply {
-            token: req.token,
+        Some(Reply {
+            token,
             status: 0,
             data: vec![0; 0],
-        }
+            payload: None,
+        })
     }
     #[inline]
-    fn handle_ackjob(&mut self, req: &Request) -> Reply {
+    fn handle_ackjob(&mut self, req: &Request) -> Option<Reply> {
         // command: ACKJOB <job id> ... <job id>
-        let mut count = 0;
-        for name in req.arg.split(is_delimiter) {
-            if name.len() == 0 {
+        self.remove_jobs(req, Command::ACKJOB, &req.arg, 1, 0)
+    }
+    #[inline]
+    fn handle_deljob(&mut self, req: &Request) -> Option<Reply> {
+        // command: DELJOB <job id> ... <job id>
+        self.remove_jobs(req, Command::DELJOB, &req.arg, 1, 0)
+    }
+    // Remove the jobs by ACKJOB or DELJOB, and pass the unknown ones on to the next shard
+    // with the count of the removed ones. The shard visited the last by `hops` replies.
+    // The jobs of the queues which the user may not use are unknown to the user.
+    fn remove_jobs(
+        &mut self,
+        req: &Request,
+        cmd: Command,
+        ids: &[u8],
+        hops: usize,
+        mut count: usize,
+    ) -> Option<Reply> {
+        let mut removed = Vec::new();
+        let mut unknown = Vec::new();
+        for job_id in ids.split(is_delimiter) {
+            if job_id.is_empty() {
                 continue;
             }
-            if let Some(name) = self.reverse.get(name) {
-                if let Some(queue) = self.queues.get_mut(name) {
-                    if let Some(_) = queue.ack(&req.arg[..]) {
-                        self.reverse.remove(&req.arg[..]);
-                        count += 1;
-                    }
+            let permitted = match (req.user.as_ref(), self.reverse.get(job_id)) {
+                (Some(user), Some(name)) => user.permits_queue(name),
+                _ => true,
+            };
+            if !permitted {
+                unknown.push(job_id);
+                continue;
+            }
+            let job = self.reverse.remove(job_id).and_then(|name| {
+                let queue = self.queues.get_mut(&name)?;
+                let job = queue.remove(job_id)?;
+                match cmd {
+                    Command::ACKJOB => queue.acked += 1,
+                    _ => queue.deleted += 1,
+                }
+                Some(job)
+            });
+            match job {
+                Some(job) => {
+                    self.free(job.size());
+                    count += 1;
+                    removed.push(job_id);
                 }
+                None => unknown.push(job_id),
             }
         }
-        Reply {
-            token: req.token,
-            status: count,
-            data: vec![0; 0],
+        if !removed.is_empty() {
+            self.record_command(cmd, &removed.join(b" ".as_ref()));
         }
+        if !unknown.is_empty() && hops < self.shards.len() {
+            // arg: b"<ACKJOB or DELJOB> <hops> <count> <job id> ... <job id>"
+            let arg = [
+                cmd.as_str(),
+                format!(" {} {} ", hops + 1, count).as_bytes(),
+                &unknown.join(b" ".as_ref()),
+            ]
+            .concat();
+            let index = (self.index + 1) % self.shards.len();
+            self.forward_as(index, req.token, Command::SHARD, arg, req.user.clone());
+            return None;
+        }
+        let mut data = count.to_string().into_bytes();
+        if cmd == Command::ACKJOB {
+            // data: b"<count> <unknown job id> ... <unknown job id>"
+            for job_id in unknown {
+                data.push(b' ');
+                data.extend(job_id);
+            }
+        }
+        Some(Reply {
+            token: req.token,
+            status: 1,
+            data,
+            payload: None,
+        })
     }
     #[inline]
     fn handle_statque(&mut self, req: &Request) -> Reply {
@@ -272,19 +1650,25 @@ impl QueueManager {
         let mut iter = req.arg.split(is_delimiter);
         next!(iter)
             .and_then(|queue_name| self.queues.get(queue_name))
-            .and_then(|queue| {
-                Some(Reply {
-                    token: req.token,
-                    status: 1,
-                    data: format!("{} {}", queue.len(), queue.running_jobs())
-                        .as_bytes()
-                        .to_vec(),
-                })
+            .map(|queue| Reply {
+                token: req.token,
+                status: 1,
+                data: format!(
+                    "{} {} {} {}",
+                    queue.len(),
+                    queue.running_jobs(),
+                    queue.acked,
+                    queue.deleted
+                )
+                .as_bytes()
+                .to_vec(),
+                payload: None,
             })
             .unwrap_or(Reply {
                 token: req.token,
                 status: 0,
-                data: b"0 0".to_vec(),
+                data: b"0 0 0 0".to_vec(),
+                payload: None,
             })
     }
     #[inline]
@@ -299,6 +1683,7 @@ impl QueueManager {
                     token: req.token,
                     status: 0,
                     data: vec![0; 0],
+                    payload: None,
                 }
             }
         };
@@ -309,19 +1694,159 @@ impl QueueManager {
                     token: req.token,
                     status: 0,
                     data: vec![0; 0],
+                    payload: None,
                 }
             }
         };
         for job in queue.jobs.iter() {
             self.reverse.remove(&job.id[..]);
         }
+        let size = queue.size();
         queue.clean();
         self.queues.remove(queue_name);
+        self.free(size);
         debug!("reverse: {}", self.reverse.len());
         Reply {
             token: req.token,
             status: 1,
             data: vec![0; 0],
+            payload: None,
+        }
+    }
+    #[inline]
+    fn handle_createque(&mut self, req: &Request) -> Reply {
+        // command: CREATEQUE <queue name>
+        let mut iter = req.arg.split(is_delimiter);
+        let queue_name = match next!(iter) {
+            Some(queue_name) => queue_name,
+            None => return Reply::error(req.token),
+        };
+        if self.queues.contains_key(queue_name) {
+            return Reply::empty(req.token);
+        }
+        self.queues.insert(queue_name.to_vec(), Queue::new());
+        Reply {
+            token: req.token,
+            status: 1,
+            data: vec![0; 0],
+            payload: None,
+        }
+    }
+    #[inline]
+    fn handle_pauseque(&mut self, req: &Request, paused: bool) -> Reply {
+        // command: PAUSEQUE <queue name>
+        // command: RESUMEQUE <queue name>
+        let mut iter = req.arg.split(is_delimiter);
+        match next!(iter).and_then(|queue_name| self.queues.get_mut(queue_name)) {
+            Some(queue) => {
+                queue.paused = paused;
+                Reply {
+                    token: req.token,
+                    status: 1,
+                    data: vec![0; 0],
+                    payload: None,
+                }
+            }
+            None => Reply::empty(req.token),
+        }
+    }
+    #[inline]
+    fn handle_purgeque(&mut self, req: &Request) -> Reply {
+        // command: PURGEQUE <queue name>
+        let mut iter = req.arg.split(is_delimiter);
+        let queue = match next!(iter).and_then(|queue_name| self.queues.get_mut(queue_name)) {
+            Some(queue) => queue,
+            None => return Reply::empty(req.token),
+        };
+        let jobs = queue.purge();
+        for job in jobs.iter() {
+            self.free(job.size());
+            self.reverse.remove(&job.id);
+        }
+        Reply {
+            token: req.token,
+            status: 1,
+            data: jobs.len().to_string().into_bytes(),
+            payload: None,
+        }
+    }
+    #[inline]
+    fn handle_moveque(&mut self, req: &Request) -> Option<Reply> {
+        // command: MOVEQUE <source queue name> <destination queue name> [<number of jobs>]
+        let mut iter = req.arg.split(is_delimiter);
+
+        let src = match next!(iter) {
+            Some(name) => name,
+            None => return Some(Reply::error(req.token)),
+        };
+        let dst = match next!(iter) {
+            Some(name) => name,
+            None => return Some(Reply::error(req.token)),
+        };
+        let n = match next!(iter) {
+            Some(buf) => match from_utf8(buf) {
+                Ok(s) => match s.parse::<usize>() {
+                    Ok(n) => n,
+                    Err(_err) => return Some(Reply::error(req.token)),
+                },
+                Err(_err) => return Some(Reply::error(req.token)),
+            },
+            None => usize::MAX,
+        };
+        if src == dst {
+            return Some(Reply::empty(req.token));
+        }
+
+        let jobs = match self.queues.get_mut(src) {
+            Some(queue) => queue.take(n),
+            None => return Some(Reply::empty(req.token)),
+        };
+        let index = self.shards.index(dst);
+        if index != self.index {
+            // The jobs are passed on to the shard of the destination, which replies.
+            // arg: b"MOVEQUE <destination queue name> <count>\n<jobs in the snapshot format>"
+            let mut arg = [b"MOVEQUE ", dst, format!(" {}\n", jobs.len()).as_bytes()].concat();
+            for job in jobs.iter() {
+                self.free(job.size());
+                self.reverse.remove(&job.id);
+                // Writing to a Vec never fails.
+                write_job(&mut arg, job, true).unwrap();
+            }
+            self.record_command(Command::MOVEQUE, &req.arg);
+            self.forward(index, req.token, Command::SHARD, arg);
+            return None;
+        }
+        let queue = match self.queues.get_mut(dst) {
+            Some(queue) => queue,
+            None => {
+                self.queues.insert(dst.to_vec(), Queue::new());
+                self.queues.get_mut(dst).unwrap()
+            }
+        };
+        let count = jobs.len();
+        for job in jobs {
+            self.reverse.insert(job.id, dst.to_vec());
+            queue.add(job);
+        }
+        Some(Reply {
+            token: req.token,
+            status: 1,
+            data: count.to_string().into_bytes(),
+            payload: None,
+        })
+    }
+    #[inline]
+    fn handle_requeue(&mut self, req: &Request) -> Reply {
+        // command: REQUEUE <queue name>
+        let mut iter = req.arg.split(is_delimiter);
+        match next!(iter).and_then(|queue_name| self.queues.get_mut(queue_name)) {
+            Some(queue) => Reply {
+                token: req.token,
+                status: 1,
+                data: queue.requeue().to_string().into_bytes(),
+                payload: None,
+            },
+            None => Reply::empty(req.token),
         }
     }
 }
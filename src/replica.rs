@@ -0,0 +1,154 @@
+use crate::command::Command;
+use crate::message::{Request, PUSH, TERMINATION};
+use crate::shard::Shards;
+use mio::Token;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpStream};
+use std::str::from_utf8;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// How long to wait before connecting to the primary again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+// Follows the primary given by `replica_of`. It receives a snapshot of the queues
+// by SYNC and then the records of the changes, which are applied by the queue manager.
+pub(crate) struct Replica {
+    primary: String,
+    // Sent by `AUTH <auth>` before SYNC.
+    auth: Option<String>,
+    following: AtomicBool,
+    // The connection to the primary, which is shut down on promotion.
+    stream: Mutex<Option<TcpStream>>,
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "closed by the primary")
+}
+
+// Read bytes until the delimiter, which is removed.
+fn read_until<R: BufRead>(reader: &mut R, delimiter: u8) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.read_until(delimiter, &mut buf)?;
+    match buf.pop() {
+        Some(b) if b == delimiter => Ok(buf),
+        _ => Err(closed()),
+    }
+}
+
+// Read `<status> <data>\n` and return the data, or an error unless the status is `1`.
+fn read_reply<R: BufRead>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let status = read_until(reader, b' ')?;
+    let data = read_until(reader, TERMINATION)?;
+    match status.as_slice() {
+        b"1" => Ok(data),
+        _ => Err(io::Error::other(format!(
+            "{} {}",
+            String::from_utf8_lossy(&status),
+            String::from_utf8_lossy(&data)
+        ))),
+    }
+}
+
+// Read `<status> <size> <data>\n`, in which the data may contain line feeds.
+fn read_frame<R: BufRead>(reader: &mut R, status: i8) -> io::Result<Vec<u8>> {
+    let head = read_until(reader, b' ')?;
+    if head != status.to_string().as_bytes() {
+        let data = read_until(reader, TERMINATION)?;
+        return Err(io::Error::other(format!(
+            "{} {}",
+            String::from_utf8_lossy(&head),
+            String::from_utf8_lossy(&data)
+        )));
+    }
+    let size: usize = from_utf8(&read_until(reader, b' ')?)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid size"))?;
+    let mut data = vec![0; size + 1];
+    reader.read_exact(&mut data)?;
+    match data.pop() {
+        Some(TERMINATION) => Ok(data),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid size")),
+    }
+}
+
+impl Replica {
+    pub(crate) fn new(primary: &str, auth: Option<&str>) -> Replica {
+        Replica {
+            primary: primary.to_owned(),
+            auth: auth.map(str::to_owned),
+            following: AtomicBool::new(true),
+            stream: Mutex::new(None),
+        }
+    }
+
+    #[inline]
+    pub(crate) fn is_following(&self) -> bool {
+        self.following.load(Ordering::Relaxed)
+    }
+
+    // Stop following the primary. Returns false if it has been promoted already.
+    pub(crate) fn promote(&self) -> bool {
+        let following = self.following.swap(false, Ordering::Relaxed);
+        if let Some(stream) = self.stream.lock().unwrap().take() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        following
+    }
+
+    pub(crate) fn run(self: Arc<Self>, sender: Shards) -> JoinHandle<()> {
+        thread::spawn(move || {
+            while self.is_following() {
+                match self.follow(&sender) {
+                    // The queue manager has stopped.
+                    Ok(()) => return,
+                    Err(err) if self.is_following() => {
+                        warn!("Replication from {}: {}", self.primary, err);
+                        thread::sleep(RETRY_INTERVAL);
+                    }
+                    Err(_) => {}
+                }
+            }
+            info!("Stopped replication from {}", self.primary);
+        })
+    }
+
+    // Pass the snapshot and the records to the queue manager until the connection is closed.
+    fn follow(&self, sender: &Shards) -> io::Result<()> {
+        let stream = TcpStream::connect(&self.primary)?;
+        *self.stream.lock().unwrap() = Some(stream.try_clone()?);
+        if !self.is_following() {
+            // Promoted while connecting.
+            return Err(closed());
+        }
+        let mut writer = stream.try_clone()?;
+        let mut reader = BufReader::new(stream);
+        if let Some(auth) = self.auth.as_ref() {
+            writer.write_all(format!("AUTH {}\n", auth).as_bytes())?;
+            read_reply(&mut reader)?;
+        }
+        writer.write_all(b"SYNC\n")?;
+        let mut record = [b"SNAPSHOT ".to_vec(), read_frame(&mut reader, 1)?].concat();
+        info!("Following the primary {}", self.primary);
+        loop {
+            let req = Box::new(Request {
+                // Not replied by the queue manager.
+                token: Token(0),
+                cmd: Command::REPLICATE,
+                arg: record,
+                user: None,
+                seq: 0,
+            });
+            if sender.send(req).is_err() {
+                return Ok(());
+            }
+            record = read_frame(&mut reader, PUSH)?;
+            if !self.is_following() {
+                return Err(closed());
+            }
+        }
+    }
+}
@@ -1,37 +1,114 @@
+use crate::acl::{Auth, User};
 use crate::command::Command;
-use crate::message::{Reply, Request, TERMINATION};
+use crate::config::Config;
+use crate::limit::{Buckets, Limit};
+use crate::message::{Outbox, Reply, Request, PUSH, TERMINATION};
+use crate::net::{Listener, Stream};
 use crate::queue::QueueManager;
+use crate::replica::Replica;
+use crate::shard::Shards;
 use crate::signal::Sig;
-use crate::utils::is_delimiter;
-use mio::net::{TcpListener, TcpStream};
+use crate::utils::{is_delimiter, secure_eq};
+use crate::worker::{Inbox, Workers};
 use mio::{Events, Interest, Poll, Registry, Token, Waker};
-use std::collections::HashMap;
+#[cfg(feature = "tls")]
+use rustls::ServerConfig;
+use std::collections::{BTreeMap, HashMap};
 use std::io::{self, Read, Write};
-use std::net::{Shutdown, SocketAddr};
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::Arc;
+use std::mem;
+use std::net::Shutdown;
+use std::path::Path;
+use std::str::from_utf8;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::SendError;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-const SERVER: Token = Token(0);
-const WAKER: Token = Token(1);
-const START_POINT: usize = 2;
-const BUFFER_SIZE: usize = 128 * 1024; // 128KB
+const WAKER: Token = Token(0);
+// Listeners are registered with `Token(LISTENER)`, `Token(LISTENER + 1)`, ...
+const LISTENER: usize = 1;
 const CONN_SIZE: usize = 128;
-const EVENTS_SIZE: usize = 1 * 1024;
-const MAX_BUFFER_SIZE: usize = 1 * 1024 * 1024 + 1024; // about 1MB
+const EVENTS_SIZE: usize = 1024;
+// How often to look for the timed out connections.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
 
+// Each I/O thread takes every `step`th token, so that the tokens are unique over the threads.
 #[inline]
-fn next(current: &mut Token) -> Token {
+fn next(current: &mut Token, first: usize, step: usize) -> Token {
     let next = current.0;
-    match current.0.checked_add(1) {
+    match current.0.checked_add(step) {
         Some(v) => current.0 = v,
-        None => current.0 = START_POINT,
+        None => current.0 = first,
     }
     Token(next)
 }
 
+#[inline]
+fn notify(sender: &Shards, cmd: Command) {
+    sender
+        .send(Box::new(Request {
+            token: WAKER,
+            cmd,
+            arg: vec![0; 0],
+            user: None,
+            seq: 0,
+        }))
+        .unwrap();
+}
+
+// The last I/O thread to stop terminates the queue manager.
+#[inline]
+fn stop(running: &AtomicUsize, sender: &Shards) {
+    if running.fetch_sub(1, Ordering::AcqRel) == 1 {
+        notify(sender, Command::TERMINATE);
+    }
+}
+
+#[cfg(feature = "tls")]
+fn tls_config(config: &Config) -> io::Result<Option<Arc<ServerConfig>>> {
+    match (config.tls_cert.as_ref(), config.tls_key.as_ref()) {
+        (Some(cert), Some(key)) => {
+            crate::tls::server_config(cert, key, config.tls_client_ca.as_deref()).map(Some)
+        }
+        (None, None) => Ok(None),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "both of tls_cert and tls_key are required",
+        )),
+    }
+}
+
+#[cfg(not(feature = "tls"))]
+fn tls_config(config: &Config) -> io::Result<Option<()>> {
+    match config.tls_cert.is_some() || config.tls_key.is_some() {
+        true => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "TLS is not supported. Build with the `tls` feature",
+        )),
+        false => Ok(None),
+    }
+}
+
+// The reply to CLIENT KILL.
+#[inline]
+fn killed(token: Token, found: bool) -> Reply {
+    match found {
+        true => Reply {
+            token,
+            status: 1,
+            data: b"1".to_vec(),
+            payload: None,
+        },
+        false => Reply {
+            token,
+            status: 0,
+            data: b"0".to_vec(),
+            payload: None,
+        },
+    }
+}
+
 #[inline]
 fn would_block(err: &io::Error) -> bool {
     err.kind() == io::ErrorKind::WouldBlock
@@ -43,53 +120,401 @@ fn interrupted(err: &io::Error) -> bool {
 }
 
 struct Connection {
-    conn: TcpStream,
-    reply: Vec<u8>,
+    conn: Stream,
+    addr: String,
+    reply: Outbox,
+    // The sequence number of the next request.
+    seq: u64,
+    // The sequence number of the next reply to be put to `reply`.
+    next: u64,
+    // The replies which have come before the ones to the earlier requests.
+    waiting: BTreeMap<u64, Reply>,
     received_data: Vec<u8>,
+    // Commands other than HELLO, QUIT and AUTH are rejected until it is set.
+    authenticated: bool,
+    // The commands and the queues are restricted to the user's, if it is set.
+    user: Option<Arc<User>>,
+    // Authenticated by `cluster_auth`, which allows RAFT.
+    peer: bool,
+    // The last time a request was received or a reply was sent or queued.
+    active: Instant,
+    buckets: Buckets,
+    // Given by CLIENT SETNAME.
+    name: Vec<u8>,
+    created: Instant,
+    last_cmd: Option<Command>,
+    // Set by SYNC. The records are pushed to it, so it is never idle.
+    replica: bool,
+    // The records pushed by the shards which have synced, until the last one replies
+    // with the whole snapshot to the request of the sequence number.
+    pushed: Option<(u64, Vec<Reply>)>,
 }
 
 impl Connection {
-    fn new(conn: TcpStream) -> Connection {
+    fn new(conn: Stream, addr: String, authenticated: bool) -> Connection {
         Connection {
             conn,
-            reply: vec![0; 0],
+            addr,
+            reply: Outbox::default(),
+            seq: 0,
+            next: 0,
+            waiting: BTreeMap::new(),
             received_data: vec![0; 0],
+            authenticated,
+            user: None,
+            peer: false,
+            active: Instant::now(),
+            buckets: Buckets::default(),
+            name: vec![0; 0],
+            created: Instant::now(),
+            last_cmd: None,
+            replica: false,
+            pushed: None,
         }
     }
+    // Drop the received request. The replies are kept until they are written.
     fn clean(&mut self) {
-        self.reply = vec![0; 0];
         self.received_data = vec![0; 0];
     }
+    // Number the request, so that its reply is written in order.
+    fn number(&mut self) -> u64 {
+        self.seq += 1;
+        self.seq - 1
+    }
+    // Put the reply after the ones to the earlier requests, which may be handled by
+    // other shards. The replies to the later requests wait until then.
+    fn put(&mut self, seq: u64, reply: Reply) {
+        self.waiting.insert(seq, reply);
+        while let Some(reply) = self.waiting.remove(&self.next) {
+            self.reply.push(reply);
+            self.next += 1;
+        }
+        // The records for replicas may come before the snapshot is sent.
+        match self.pushed.take() {
+            Some((sync, pushed)) if sync < self.next => {
+                for rep in pushed {
+                    self.reply.push(rep);
+                }
+            }
+            pushed => self.pushed = pushed,
+        }
+    }
+    // Reply to the request handled by the I/O thread itself, after the replies to
+    // the earlier requests in flight.
+    fn respond(&mut self, reply: Reply) {
+        let seq = self.number();
+        self.put(seq, reply);
+    }
+    // Read the requests while the replies waiting to be written are below the high
+    // watermark, and write the replies if any.
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        watermark: usize,
+    ) -> io::Result<()> {
+        let interest = match self.reply.len() {
+            0 => Interest::READABLE,
+            len if watermark > 0 && len >= watermark => Interest::WRITABLE,
+            _ => Interest::READABLE | Interest::WRITABLE,
+        };
+        registry.reregister(&mut self.conn, token, interest)
+    }
 }
 
+// The state shared by the I/O threads.
+#[derive(Clone)]
+struct Shared {
+    // The number of the connections of all threads.
+    connections: Arc<AtomicUsize>,
+    // Token buckets per user name.
+    user_buckets: Arc<Mutex<HashMap<String, Buckets>>>,
+    // Set if the server follows a primary.
+    replica: Option<Arc<Replica>>,
+    // The current configuration, which is replaced on reload.
+    config: Arc<Mutex<Config>>,
+}
+
+impl Shared {
+    fn new(config: &Config) -> Shared {
+        Shared {
+            connections: Arc::new(AtomicUsize::new(0)),
+            user_buckets: Arc::new(Mutex::new(HashMap::new())),
+            replica: config
+                .replica_of
+                .as_ref()
+                .map(|primary| Arc::new(Replica::new(primary, config.replica_auth.as_deref()))),
+            config: Arc::new(Mutex::new(config.clone())),
+        }
+    }
+}
+
+// An I/O thread, which serves the connections accepted by itself.
 pub struct Server {
-    addr: SocketAddr,
+    // The first token for connections, next to the listeners.
+    start: usize,
+    // The index of this thread in `workers`.
+    index: usize,
+    // The first token of this thread.
+    first: usize,
     token: Token,
     connections: HashMap<Token, Connection>,
-    buffer: [u8; BUFFER_SIZE],
+    buffer: Vec<u8>,
+    max_buffer_size: usize,
+    reply_high_watermark: usize,
+    // The number of requests sent to the queue manager and not replied yet.
+    in_flight: usize,
+    // The deadline of the graceful shutdown, if it has been started.
+    closing: Option<Instant>,
+    // Accept TCP connections with TLS.
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<ServerConfig>>,
+    auth: Auth,
+    // Sent by AUTH by the other nodes of the cluster.
+    cluster_auth: Option<Vec<u8>>,
+    max_connections: usize,
+    idle_timeout: Duration,
+    reply_timeout: Duration,
+    // The last time the timed out connections were looked for.
+    swept: Instant,
+    // Rate limits per connection.
+    limit: Limit,
+    workers: Workers,
+    shared: Shared,
 }
 
 impl Server {
-    pub fn new(addr: SocketAddr) -> Server {
-        Server {
-            addr,
-            token: Token(START_POINT),
+    fn new(
+        config: &Config,
+        listeners: usize,
+        index: usize,
+        workers: Workers,
+        shared: Shared,
+    ) -> io::Result<Server> {
+        let start = LISTENER + listeners;
+        let step = workers.len();
+        let first = start + (index + step - start % step) % step;
+        #[cfg(feature = "tls")]
+        let tls = tls_config(config)?;
+        #[cfg(not(feature = "tls"))]
+        tls_config(config)?;
+        Ok(Server {
+            start,
+            index,
+            first,
+            token: Token(first),
             connections: HashMap::with_capacity(CONN_SIZE),
-            buffer: [0; BUFFER_SIZE],
+            buffer: vec![0; config.buffer_size],
+            max_buffer_size: config.max_buffer_size,
+            reply_high_watermark: config.reply_high_watermark,
+            in_flight: 0,
+            closing: None,
+            #[cfg(feature = "tls")]
+            tls,
+            auth: Auth::new(config)?,
+            cluster_auth: config
+                .cluster_auth
+                .as_ref()
+                .map(|auth| auth.as_bytes().to_vec()),
+            max_connections: config.max_connections,
+            idle_timeout: config.idle_timeout,
+            reply_timeout: config.reply_timeout,
+            swept: Instant::now(),
+            limit: Limit::new("rate_limit", &config.rate_limit)?,
+            workers,
+            shared,
+        })
+    }
+
+    // Read the configuration file again and apply it to every thread and the queue manager.
+    fn reload_all(&mut self, sender: &Shards) {
+        let mut config = self.shared.config.lock().unwrap().clone();
+        let reloaded = config.reload().map(Path::to_path_buf).and_then(|path| {
+            Limit::new("rate_limit", &config.rate_limit)?;
+            Auth::new(&config)?;
+            Ok(path)
+        });
+        match reloaded {
+            Ok(path) => info!("Reloaded {}", path.display()),
+            Err(err) => {
+                error!("Failed to reload: {}", err);
+                return;
+            }
+        }
+        log::set_max_level(config.log_level);
+        *self.shared.config.lock().unwrap() = config;
+        self.reload();
+        for index in (0..self.workers.len()).filter(|index| *index != self.index) {
+            let _ = self.workers.pass(
+                index,
+                Box::new(Request {
+                    token: WAKER,
+                    cmd: Command::RELOAD,
+                    arg: vec![0; 0],
+                    user: None,
+                    seq: 0,
+                }),
+            );
+        }
+        notify(sender, Command::RELOAD);
+    }
+    // Take the limits of the reloaded configuration. The connections keep their buckets,
+    // which are refilled by the new rates, and their users until they authenticate again.
+    fn reload(&mut self) {
+        let config = self.shared.config.clone();
+        let config = config.lock().unwrap();
+        self.max_connections = config.max_connections;
+        self.idle_timeout = config.idle_timeout;
+        self.reply_timeout = config.reply_timeout;
+        // Both have been validated by `reload_all`.
+        if let Ok(limit) = Limit::new("rate_limit", &config.rate_limit) {
+            self.limit = limit;
+        }
+        if let Ok(auth) = Auth::new(&config) {
+            self.auth = auth;
         }
     }
 
+    // Dump the connections of every thread.
+    fn dump_all(&self) {
+        self.dump();
+        for index in (0..self.workers.len()).filter(|index| *index != self.index) {
+            let _ = self.workers.pass(
+                index,
+                Box::new(Request {
+                    token: WAKER,
+                    cmd: Command::DUMP,
+                    arg: vec![0; 0],
+                    user: None,
+                    seq: 0,
+                }),
+            );
+        }
+    }
+    fn dump(&self) {
+        info!(
+            "I/O thread {}: connections: {}, in-flight requests: {}",
+            self.index,
+            self.connections.len(),
+            self.in_flight
+        );
+        for (token, connection) in self.connections.iter() {
+            info!(
+                "Connection {}: peer={} received={} reply={}",
+                token.0,
+                connection.addr,
+                connection.received_data.len(),
+                connection.reply.len()
+            );
+        }
+    }
+    // How long to wait for events until the next deadline.
+    fn poll_timeout(&self) -> Option<Duration> {
+        let closing = self
+            .closing
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let sweep = match self.idle_timeout.is_zero() && self.reply_timeout.is_zero() {
+            true => None,
+            false => Some(SWEEP_INTERVAL.saturating_sub(self.swept.elapsed())),
+        };
+        closing.into_iter().chain(sweep).min()
+    }
+    // Close the connections which have sent nothing or have not read the reply for too long.
+    fn sweep(&mut self, registry: &Registry, sender: &Shards) {
+        if self.swept.elapsed() < SWEEP_INTERVAL {
+            return;
+        }
+        let now = Instant::now();
+        self.swept = now;
+        let (idle_timeout, reply_timeout) = (self.idle_timeout, self.reply_timeout);
+        let expired: Vec<Token> = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| {
+                let elapsed = now.saturating_duration_since(connection.active);
+                match connection.reply.is_empty() {
+                    true => {
+                        !idle_timeout.is_zero() && elapsed >= idle_timeout && !connection.replica
+                    }
+                    false => !reply_timeout.is_zero() && elapsed >= reply_timeout,
+                }
+            })
+            .map(|(token, _)| *token)
+            .collect();
+        for token in expired {
+            if let Some(mut connection) = self.remove(token) {
+                info!("Timed out connection from: {}", connection.addr);
+                let _ = registry.deregister(&mut connection.conn);
+                let _ = connection.conn.shutdown(Shutdown::Both);
+                self.disconnect(token, sender);
+            }
+        }
+    }
+    // Forget the connection, which has been closed.
+    fn remove(&mut self, token: Token) -> Option<Connection> {
+        let connection = self.connections.remove(&token)?;
+        self.shared.connections.fetch_sub(1, Ordering::Relaxed);
+        Some(connection)
+    }
+    // Tell the queue manager that the connection has been closed.
+    #[inline]
+    fn disconnect(&self, token: Token, sender: &Shards) {
+        sender
+            .send(Box::new(Request {
+                token,
+                cmd: Command::DISCONNECT,
+                arg: vec![0; 0],
+                user: None,
+                seq: 0,
+            }))
+            .unwrap();
+    }
     #[inline]
-    fn serve(&mut self, registry: &Registry, server: &TcpListener) -> io::Result<()> {
+    fn is_drained(&self) -> bool {
+        self.in_flight == 0 && self.connections.values().all(|c| c.reply.is_empty())
+    }
+
+    #[inline]
+    fn serve(&mut self, registry: &Registry, server: &Listener) -> io::Result<()> {
         loop {
-            // Received an event for the TCP server socket, which
+            // Received an event for the server socket, which
             // indicates we can accept an connection.
             match server.accept() {
                 Ok((mut connection, address)) => {
+                    if self.max_connections > 0
+                        && self.shared.connections.load(Ordering::Relaxed) >= self.max_connections
+                    {
+                        warn!("Too many connections. Rejected: {}", address);
+                        // The reply is not sent to the queue manager, so any token will do.
+                        // A TLS client would take it for the handshake, so it is only closed.
+                        #[cfg(feature = "tls")]
+                        let plain = self.tls.is_none();
+                        #[cfg(not(feature = "tls"))]
+                        let plain = true;
+                        if plain {
+                            let _ = connection.write(&Reply::too_many_connections(WAKER).message());
+                        }
+                        let _ = connection.shutdown(Shutdown::Both);
+                        continue;
+                    }
                     debug!("Accepted connection from: {}", address);
-                    let token = next(&mut self.token);
+                    #[cfg(feature = "tls")]
+                    let mut connection = match self.tls.as_ref() {
+                        Some(config) => match connection.into_tls(config.clone()) {
+                            Ok(connection) => connection,
+                            Err(e) => {
+                                error!("{}: {}", address, e);
+                                continue;
+                            }
+                        },
+                        None => connection,
+                    };
+                    let token = next(&mut self.token, self.first, self.workers.len());
                     registry.register(&mut connection, token, Interest::READABLE)?;
-                    self.connections.insert(token, Connection::new(connection));
+                    let authenticated = self.auth.is_open();
+                    self.connections
+                        .insert(token, Connection::new(connection, address, authenticated));
+                    self.shared.connections.fetch_add(1, Ordering::Relaxed);
                 }
                 Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
                     // If we get a `WouldBlock` error we know our
@@ -107,10 +532,13 @@ impl Server {
         }
     }
     #[inline]
-    fn wake(&mut self, registry: &Registry, receiver: &Receiver<Box<Reply>>) -> io::Result<()> {
+    fn wake(&mut self, registry: &Registry, receiver: &Inbox, sender: &Shards) -> io::Result<()> {
+        while let Ok(req) = receiver.requests.try_recv() {
+            self.handle_request(registry, *req, sender)?;
+        }
         loop {
-            match receiver.recv_timeout(Duration::from_nanos(1)) {
-                Ok(rep) => {
+            match receiver.replies.recv_timeout(Duration::from_nanos(1)) {
+                Ok((seq, rep)) => {
                     debug!(
                         "Catch reply: {:?} {:?} {:?} [{:p}]",
                         rep.token,
@@ -118,66 +546,90 @@ impl Server {
                         rep.data.len(),
                         rep
                     );
+                    if rep.status != PUSH {
+                        self.in_flight = self.in_flight.saturating_sub(1);
+                    }
                     let token = rep.token;
-                    match self.connections.get_mut(&token) {
-                        Some(connection) => {
-                            connection.reply = rep.message();
-                            registry.reregister(&mut connection.conn, token, Interest::WRITABLE)?;
+                    if let Some(connection) = self.connections.get_mut(&token) {
+                        match (rep.status, connection.pushed.as_mut()) {
+                            (PUSH, Some((_, pushed))) => {
+                                pushed.push(*rep);
+                                continue;
+                            }
+                            (PUSH, None) => connection.reply.push(*rep),
+                            _ => connection.put(seq, *rep),
                         }
-                        None => {}
-                    };
+                        connection.active = Instant::now();
+                        connection.reregister(registry, token, self.reply_high_watermark)?;
+                    } else if rep.status == 1 {
+                        // A GETJOB forwarded between shards may lease a job after a shard
+                        // has handled DISCONNECT, so tell the shards again to release it.
+                        self.disconnect(token, sender);
+                    }
                 }
                 Err(_) => return Ok(()),
             };
         }
     }
     #[inline]
-    fn handle_to_write(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
-        let connection = self.connections.get_mut(&token).unwrap();
+    fn handle_to_write(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        sender: &Shards,
+    ) -> io::Result<()> {
+        let connection = match self.connections.get_mut(&token) {
+            Some(c) => c,
+            None => return Ok(()),
+        };
         debug!("Reply size: {}", connection.reply.len());
         // We can (maybe) write to the connection.
 
-        match connection.conn.write(connection.reply.as_slice()) {
-            // We want to write the entire `DATA` buffer in a single go. If we
-            // write less we'll return a short write error (same as
-            // `io::Write::write_all` does).
-            Ok(n) if n < connection.reply.len() => {
-                connection.reply = connection.reply.drain(n..).collect();
+        match connection.reply.write_to(&mut connection.conn) {
+            // The written bytes are dropped from the reply. If some are left, we
+            // wait until the connection is writable again.
+            Ok(n) if !connection.reply.is_empty() => {
+                if n > 0 {
+                    connection.active = Instant::now();
+                }
                 debug!("Catch Error; n<len");
-                registry.reregister(&mut connection.conn, token, Interest::WRITABLE)?;
-                // return Err(io::ErrorKind::WriteZero.into())
-            }
-            Ok(_) => {
-                // After we've written something we'll re-register the connection
-                // to only respond to readable events.
-                connection.clean();
-                registry.reregister(&mut connection.conn, token, Interest::READABLE)?;
             }
+            // After we've written everything we'll re-register the connection
+            // to only respond to readable events. The received data is kept,
+            // since the event may be only to flush TLS records.
+            Ok(_) => connection.active = Instant::now(),
             // Would block "errors" are the OS's way of saying that the
             // connection is not actually ready to perform this I/O operation.
             Err(ref err) if would_block(err) => {
                 debug!("has would_block");
-                registry.reregister(&mut connection.conn, token, Interest::WRITABLE)?;
+                return connection.reregister(registry, token, self.reply_high_watermark);
             }
             // Got interrupted (how rude!), we'll try again.
             Err(ref err) if interrupted(err) => {
                 debug!("has would_block");
-                registry.reregister(&mut connection.conn, token, Interest::WRITABLE)?;
+                return connection.reregister(registry, token, self.reply_high_watermark);
             }
             // Other errors we'll consider fatal.
             Err(err) => {
-                // return Err(err)
-                debug!("error: {}", err);
+                error!("{}", err);
+                debug!("Closed connection from: {}", connection.addr);
+                if let Some(mut connection) = self.remove(token) {
+                    let _ = registry.deregister(&mut connection.conn);
+                    let _ = connection.conn.shutdown(Shutdown::Both);
+                }
+                self.disconnect(token, sender);
+                return Ok(());
             }
         }
-        Ok(())
+        // The requests left by the high watermark are handled as the replies are written.
+        self.handle_requests(registry, token, sender)
     }
     #[inline]
     fn handle_to_read(
         &mut self,
         registry: &Registry,
         token: Token,
-        sender: &Sender<Box<Request>>,
+        sender: &Shards,
     ) -> io::Result<()> {
         let connection = match self.connections.get_mut(&token) {
             Some(c) => c,
@@ -188,68 +640,218 @@ impl Server {
             Ok(0) => {
                 // Reading 0 bytes means the other side has closed the
                 // connection or is done writing, then so are we.
-                debug!(
-                    "Closed connection from: {}",
-                    connection.conn.local_addr().unwrap()
-                );
-                self.connections.remove(&token);
+                debug!("Closed connection from: {}", connection.addr);
+                self.remove(token);
+                self.disconnect(token, sender);
                 return Ok(());
             }
             Ok(n) => {
-                if n + connection.received_data.len() > MAX_BUFFER_SIZE {
+                connection.active = Instant::now();
+                if n + connection.received_data.len() > self.max_buffer_size {
                     connection.clean();
-                    connection.reply = Reply::error(token).message();
-                    registry.reregister(&mut connection.conn, token, Interest::WRITABLE)?;
+                    connection.respond(Reply::error(token));
+                    connection.reregister(registry, token, self.reply_high_watermark)?;
                     return Ok(());
                 }
                 connection.received_data.extend(&self.buffer[0..n]);
-                if connection.received_data[connection.received_data.len() - 1] != TERMINATION {
-                    registry.reregister(&mut connection.conn, token, Interest::READABLE)?;
-                    return Ok(());
-                }
             }
             // Would block "errors" are the OS's way of saying that the
             // connection is not actually ready to perform this I/O operation.
+            // It also happens while a TLS handshake is in progress.
             Err(ref err) if would_block(err) => {
                 debug!("has would_block");
-                registry.reregister(&mut connection.conn, token, Interest::READABLE)?;
+                connection.reregister(registry, token, self.reply_high_watermark)?;
+                return Ok(());
             }
             Err(ref err) if interrupted(err) => {
                 debug!("has interrupted");
-                registry.reregister(&mut connection.conn, token, Interest::READABLE)?;
+                connection.reregister(registry, token, self.reply_high_watermark)?;
+                return Ok(());
             }
             // Other errors we'll consider fatal.
             Err(err) => {
                 error!("{}", err);
-                debug!(
-                    "Closed connection from: {}",
-                    connection.conn.local_addr().unwrap()
-                );
+                debug!("Closed connection from: {}", connection.addr);
                 let _ = connection.conn.shutdown(Shutdown::Both);
-                self.connections.remove(&token);
+                self.remove(token);
+                self.disconnect(token, sender);
                 return Ok(());
             }
         }
-        debug!("received_data: {}", connection.received_data.len());
-        // `\n`を除く
-        let mut iter =
-            connection.received_data[..connection.received_data.len() - 1].splitn(2, is_delimiter);
-        match Command::from(iter.next().unwrap()) {
+        self.handle_requests(registry, token, sender)
+    }
+    // Handle the received requests in order, each of which ends with `\n`, while the
+    // replies waiting to be written are below the high watermark. The rest are handled
+    // once the replies are written.
+    fn handle_requests(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        sender: &Shards,
+    ) -> io::Result<()> {
+        let watermark = self.reply_high_watermark;
+        while let Some(connection) = self.connections.get_mut(&token) {
+            let full = watermark > 0 && connection.reply.len() >= watermark;
+            match connection
+                .received_data
+                .iter()
+                .position(|b| *b == TERMINATION)
+            {
+                Some(end) if !full && self.closing.is_none() => {
+                    let rest = connection.received_data.split_off(end + 1);
+                    let mut line = mem::replace(&mut connection.received_data, rest);
+                    // `\n`を除く
+                    line.pop();
+                    self.handle_command(registry, token, &line, sender)?;
+                }
+                _ => return connection.reregister(registry, token, watermark),
+            }
+        }
+        Ok(())
+    }
+    fn handle_command(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        line: &[u8],
+        sender: &Shards,
+    ) -> io::Result<()> {
+        let connection = match self.connections.get_mut(&token) {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        debug!("request: {}", line.len());
+        let mut iter = line.splitn(2, is_delimiter);
+        let cmd = Command::from(iter.next().unwrap());
+        let arg = iter.next().unwrap_or(&[]);
+        if cmd.is_some() {
+            connection.last_cmd = cmd;
+        }
+        match cmd {
             Some(Command::QUIT) => {
                 connection.conn.shutdown(Shutdown::Both)?;
-                debug!(
-                    "Closed connection from: {}",
-                    connection.conn.local_addr().unwrap()
-                );
-                self.connections.remove(&token);
+                debug!("Closed connection from: {}", connection.addr);
+                self.remove(token);
+                self.disconnect(token, sender);
+                return Ok(());
+            }
+            Some(Command::AUTH) => {
+                let auth = self.auth.authenticate(arg);
+                let reply = match auth {
+                    Some(user) => {
+                        if let Some(user) = user.as_ref() {
+                            debug!("Authenticated {} as: {}", connection.addr, user.name);
+                        }
+                        connection.authenticated = true;
+                        connection.user = user;
+                        connection.peer = self
+                            .cluster_auth
+                            .as_ref()
+                            .is_some_and(|auth| secure_eq(auth, arg));
+                        Reply::ok(token)
+                    }
+                    None => {
+                        warn!("Authentication failed from: {}", connection.addr);
+                        connection.authenticated = false;
+                        connection.user = None;
+                        connection.peer = false;
+                        Reply::auth_failed(token)
+                    }
+                };
+                connection.respond(reply);
+                return Ok(());
+            }
+            Some(cmd) if !connection.authenticated && cmd != Command::HELLO => {
+                connection.respond(Reply::no_auth(token));
+                return Ok(());
+            }
+            Some(Command::RAFT) if !connection.peer => {
+                connection.respond(Reply::no_perm(token));
+                return Ok(());
+            }
+            Some(cmd)
+                if cmd != Command::HELLO
+                    && connection
+                        .user
+                        .as_ref()
+                        .is_some_and(|user| !user.permits(&cmd, arg)) =>
+            {
+                connection.respond(Reply::no_perm(token));
+                return Ok(());
+            }
+            Some(cmd)
+                if cmd.is_write()
+                    && self
+                        .shared
+                        .replica
+                        .as_ref()
+                        .is_some_and(|replica| replica.is_following()) =>
+            {
+                connection.respond(Reply::read_only(token));
                 return Ok(());
             }
             Some(cmd) => {
-                let arg = match iter.next() {
-                    Some(a) => a.to_vec(),
-                    None => vec![0; 0],
+                // Take the tokens of the connection and the user only if both have one.
+                let throttled = {
+                    let user_buckets = &self.shared.user_buckets;
+                    let mut user = connection
+                        .user
+                        .as_ref()
+                        .filter(|user| !user.limit.is_empty())
+                        .map(|user| (user, user_buckets.lock().unwrap()));
+                    let allowed = connection.buckets.has(&self.limit, cmd)
+                        && match user.as_mut() {
+                            Some((user, buckets)) => buckets
+                                .entry(user.name.clone())
+                                .or_default()
+                                .has(&user.limit, cmd),
+                            None => true,
+                        };
+                    if allowed {
+                        connection.buckets.spend(&self.limit, cmd);
+                        if let Some((user, mut buckets)) = user {
+                            if let Some(buckets) = buckets.get_mut(&user.name) {
+                                buckets.spend(&user.limit, cmd);
+                            }
+                        }
+                    }
+                    !allowed
                 };
-                let req = Box::new(Request { token, cmd, arg });
+                if throttled {
+                    connection.respond(Reply::throttled(token));
+                    return Ok(());
+                }
+                let arg = arg.to_vec();
+                if cmd == Command::CLIENT {
+                    return self.handle_client(registry, token, &arg, sender);
+                }
+                if cmd == Command::PROMOTE {
+                    // command: PROMOTE
+                    let promoted = match self.shared.replica.as_ref() {
+                        Some(replica) => replica.promote(),
+                        None => false,
+                    };
+                    if promoted {
+                        info!("Promoted to primary by: {}", connection.addr);
+                    }
+                    connection.respond(match promoted {
+                        true => Reply::ok(token),
+                        false => Reply::empty(token),
+                    });
+                    return Ok(());
+                }
+                let seq = connection.number();
+                if cmd == Command::SYNC {
+                    connection.replica = true;
+                    connection.pushed = Some((seq, Vec::new()));
+                }
+                let req = Box::new(Request {
+                    token,
+                    cmd,
+                    arg,
+                    user: connection.user.clone(),
+                    seq,
+                });
                 debug!(
                     "Send Request: {:?} {:?} {:?} [{:p}]",
                     req.token,
@@ -258,75 +860,317 @@ impl Server {
                     req
                 );
                 sender.send(req).unwrap();
-                connection.clean();
+                self.in_flight += 1;
             }
             None => {
-                if connection.received_data.len() == 1 {
-                    connection.clean();
-                    registry.reregister(&mut connection.conn, token, Interest::READABLE)?;
-                } else {
-                    connection.clean();
-                    connection.reply = Reply::error(token).message();
-                    registry.reregister(&mut connection.conn, token, Interest::WRITABLE)?;
+                // An empty line is ignored.
+                if !line.is_empty() {
+                    connection.respond(Reply::error(token));
                 }
                 return Ok(());
             }
         }
         Ok(())
     }
-    pub fn run(addr: SocketAddr) -> io::Result<()> {
-        let mut app = Server::new(addr);
-        let mut poll = Poll::new()?;
-        let mut events = Events::with_capacity(EVENTS_SIZE);
-        let mut server = TcpListener::bind(app.addr)?;
-
-        poll.registry()
-            .register(&mut server, SERVER, Interest::READABLE)?;
-
-        let (req_tx, req_rx) = channel::<Box<Request>>();
-        let (rep_tx, rep_rx) = channel::<Box<Reply>>();
+    fn handle_client(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        arg: &[u8],
+        sender: &Shards,
+    ) -> io::Result<()> {
+        // command: CLIENT SETNAME <name> | CLIENT LIST | CLIENT KILL <id>
+        // Its reply may come from another thread or the queue manager.
+        let seq = match self.connections.get_mut(&token) {
+            Some(connection) => connection.number(),
+            None => return Ok(()),
+        };
+        let mut iter = arg.split(is_delimiter).filter(|s| !s.is_empty());
+        let subcommand = iter.next().map(|s| s.to_ascii_uppercase());
+        let reply = match (subcommand.as_deref(), iter.next(), iter.next()) {
+            (Some(b"SETNAME"), Some(name), None) => {
+                if let Some(connection) = self.connections.get_mut(&token) {
+                    connection.name = name.to_vec();
+                }
+                Reply::ok(token)
+            }
+            (Some(b"LIST"), None, None) => {
+                self.list_clients(token, seq, b"LIST".to_vec(), sender);
+                self.in_flight += 1;
+                return Ok(());
+            }
+            (Some(b"KILL"), Some(id), None) => {
+                let target = from_utf8(id).ok().and_then(|id| id.parse().ok()).map(Token);
+                match target {
+                    // The thread which owns the connection replies.
+                    Some(target) if self.workers.index(target) != self.index => {
+                        let req = Box::new(Request {
+                            token,
+                            cmd: Command::CLIENT,
+                            arg: format!("KILL {}", target.0).into_bytes(),
+                            user: None,
+                            seq,
+                        });
+                        match self.workers.pass(self.workers.index(target), req) {
+                            Ok(_) => {
+                                self.in_flight += 1;
+                                return Ok(());
+                            }
+                            Err(_) => killed(token, false),
+                        }
+                    }
+                    Some(target) if self.kill(registry, target, sender)? => {
+                        if target == token {
+                            return Ok(());
+                        }
+                        killed(token, true)
+                    }
+                    _ => killed(token, false),
+                }
+            }
+            _ => Reply::error(token),
+        };
+        if let Some(connection) = self.connections.get_mut(&token) {
+            connection.put(seq, reply);
+        }
+        Ok(())
+    }
+    // Add the connections of this thread to the list, and pass it on to the next thread.
+    // The last one sends it to the queue manager, which adds the number of the jobs
+    // leased by each connection.
+    fn list_clients(&self, token: Token, seq: u64, mut arg: Vec<u8>, sender: &Shards) {
+        let now = Instant::now();
+        let mut tokens: Vec<&Token> = self.connections.keys().collect();
+        tokens.sort();
+        for t in tokens {
+            let connection = &self.connections[t];
+            let name = match connection.name.is_empty() {
+                true => b"-".as_ref(),
+                false => connection.name.as_slice(),
+            };
+            let cmd = match connection.last_cmd.as_ref() {
+                Some(cmd) => cmd.as_str(),
+                None => b"-",
+            };
+            arg.extend(format!("\nid={} addr={} name=", t.0, connection.addr).as_bytes());
+            arg.extend(name);
+            arg.extend(
+                format!(
+                    " age={} cmd=",
+                    now.saturating_duration_since(connection.created).as_secs()
+                )
+                .as_bytes(),
+            );
+            arg.extend(cmd);
+        }
+        let req = Box::new(Request {
+            token,
+            cmd: Command::CLIENT,
+            arg,
+            user: None,
+            seq,
+        });
+        let next = (self.index + 1) % self.workers.len();
+        let req = match next == self.workers.index(token) {
+            true => req,
+            // The request comes back if the next thread has stopped.
+            false => match self.workers.pass(next, req) {
+                Ok(_) => return,
+                Err(SendError(req)) => req,
+            },
+        };
+        sender.send(req).unwrap();
+    }
+    // Close the connection of this thread. False if it is not found.
+    fn kill(&mut self, registry: &Registry, target: Token, sender: &Shards) -> io::Result<bool> {
+        match self.remove(target) {
+            Some(mut connection) => {
+                info!("Killed connection from: {}", connection.addr);
+                registry.deregister(&mut connection.conn)?;
+                let _ = connection.conn.shutdown(Shutdown::Both);
+                self.disconnect(target, sender);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+    // Handle the request passed on by another I/O thread.
+    fn handle_request(
+        &mut self,
+        registry: &Registry,
+        req: Request,
+        sender: &Shards,
+    ) -> io::Result<()> {
+        match req.cmd {
+            Command::DUMP => self.dump(),
+            Command::RELOAD => self.reload(),
+            // arg: LIST\n<connection>\n...
+            Command::CLIENT if req.arg.starts_with(b"LIST") => {
+                self.list_clients(req.token, req.seq, req.arg, sender)
+            }
+            // arg: KILL <id>
+            Command::CLIENT => {
+                let target = from_utf8(&req.arg[b"KILL ".len()..])
+                    .ok()
+                    .and_then(|id| id.parse().ok())
+                    .map(Token);
+                let found = match target {
+                    Some(target) => self.kill(registry, target, sender)?,
+                    None => false,
+                };
+                self.workers
+                    .send(req.seq, Box::new(killed(req.token, found)));
+                self.workers.wake(self.workers.index(req.token));
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+    pub fn run(config: Config) -> io::Result<()> {
+        if config.io_threads == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "io_threads must be positive",
+            ));
+        }
+        let mut listeners = Vec::new();
+        if config.tcp {
+            listeners.extend(Listener::bind_host(&config.host, config.port)?);
+        }
+        if let Some(path) = config.unix_socket.as_ref() {
+            listeners.push(Listener::bind_unix(path)?);
+        }
+        for address in config.bind.iter() {
+            listeners.extend(Listener::bind(address)?);
+        }
+        if listeners.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no address to listen on",
+            ));
+        }
+        for listener in listeners.iter() {
+            info!("Listening on {}", listener.describe());
+        }
+        // Each thread polls its own clones of the listeners and its connections.
+        let mut polls = Vec::with_capacity(config.io_threads);
+        let mut wakers = Vec::with_capacity(config.io_threads);
+        for _ in 0..config.io_threads {
+            let poll = Poll::new()?;
+            wakers.push(Arc::new(Waker::new(poll.registry(), WAKER)?));
+            polls.push(poll);
+        }
+        let (workers, inboxes) = Workers::new(wakers);
+        let shared = Shared::new(&config);
+        let mut threads = Vec::with_capacity(config.io_threads);
+        for (index, poll) in polls.into_iter().enumerate() {
+            let mut listeners = listeners
+                .iter()
+                .map(Listener::try_clone)
+                .collect::<io::Result<Vec<Listener>>>()?;
+            for (i, listener) in listeners.iter_mut().enumerate() {
+                poll.registry()
+                    .register(listener, Token(LISTENER + i), Interest::READABLE)?;
+            }
+            let app = Server::new(
+                &config,
+                listeners.len(),
+                index,
+                workers.clone(),
+                shared.clone(),
+            )?;
+            threads.push((poll, listeners, app));
+        }
 
-        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
-        let queue = QueueManager::run(waker.clone(), rep_tx, req_rx);
+        let (req_tx, req_rx) = Shards::new(config.shards);
+        let queues = QueueManager::run(&shared.config, workers.clone(), &req_tx, req_rx)?;
+        if let Some(replica) = shared.replica.clone() {
+            info!(
+                "Replica of {}",
+                config.replica_of.as_deref().unwrap_or_default()
+            );
+            replica.run(req_tx.clone());
+        }
         let stat = Arc::new(AtomicBool::new(false));
         let sig = Sig::new(stat.clone());
+        let shutdown_timeout = config.shutdown_timeout;
+        let running = Arc::new(AtomicUsize::new(config.io_threads));
 
-        let job = thread::spawn(move || loop {
-            poll.poll(&mut events, None).unwrap();
-            let registry = poll.registry();
-            for event in events.iter() {
-                match event.token() {
-                    SERVER => app.serve(registry, &server).unwrap(),
-                    WAKER => {
-                        if Arc::try_unwrap(Arc::clone(&stat))
-                            .unwrap_err()
-                            .load(Ordering::Relaxed)
-                        {
-                            info!("Shutdown");
-                            req_tx
-                                .send(Box::new(Request {
-                                    token: WAKER,
-                                    cmd: Command::TERMINATE,
-                                    arg: vec![0; 0],
-                                }))
-                                .unwrap();
-                            return;
+        let jobs: Vec<thread::JoinHandle<()>> = threads
+            .into_iter()
+            .zip(inboxes)
+            .map(|((mut poll, mut listeners, mut app), inbox)| {
+                let (req_tx, stat, running) = (req_tx.clone(), stat.clone(), running.clone());
+                let (reload, dump) = (sig.reload.clone(), sig.dump.clone());
+                let mut events = Events::with_capacity(EVENTS_SIZE);
+                thread::spawn(move || loop {
+                    poll.poll(&mut events, app.poll_timeout()).unwrap();
+                    let registry = poll.registry();
+                    for event in events.iter() {
+                        match event.token() {
+                            token if LISTENER <= token.0 && token.0 < app.start => {
+                                app.serve(registry, &listeners[token.0 - LISTENER]).unwrap()
+                            }
+                            WAKER => {
+                                if app.closing.is_none() && stat.load(Ordering::Relaxed) {
+                                    if app.index == 0 {
+                                        info!("Shutdown");
+                                    }
+                                    if shutdown_timeout.as_secs() == 0 {
+                                        stop(&running, &req_tx);
+                                        return;
+                                    }
+                                    // Stop accepting connections, then wait until every
+                                    // in-flight request is replied and flushed.
+                                    for listener in listeners.iter_mut() {
+                                        registry.deregister(listener).unwrap();
+                                    }
+                                    app.closing = Some(Instant::now() + shutdown_timeout);
+                                }
+                                // Only the thread which takes the flag tells the queue manager.
+                                if reload.swap(false, Ordering::Relaxed) {
+                                    app.reload_all(&req_tx);
+                                }
+                                if dump.swap(false, Ordering::Relaxed) {
+                                    app.dump_all();
+                                    notify(&req_tx, Command::DUMP);
+                                }
+                                app.wake(registry, &inbox, &req_tx).unwrap();
+                            }
+                            token => {
+                                if event.is_writable() {
+                                    app.handle_to_write(registry, token, &req_tx).unwrap();
+                                } else if event.is_readable() && app.closing.is_none() {
+                                    app.handle_to_read(registry, token, &req_tx).unwrap();
+                                }
+                            }
                         }
-                        app.wake(registry, &rep_rx).unwrap();
                     }
-                    token => {
-                        if event.is_writable() {
-                            app.handle_to_write(registry, token).unwrap();
-                        } else if event.is_readable() {
-                            app.handle_to_read(registry, token, &req_tx).unwrap();
+                    app.sweep(registry, &req_tx);
+                    if let Some(deadline) = app.closing {
+                        if app.is_drained() {
+                            stop(&running, &req_tx);
+                            return;
+                        } else if Instant::now() >= deadline {
+                            warn!(
+                                "Shutdown timeout exceeded: {} requests, {} connections",
+                                app.in_flight,
+                                app.connections.len()
+                            );
+                            stop(&running, &req_tx);
+                            return;
                         }
                     }
-                }
-            }
-        });
-        sig.run(waker.clone());
-        queue.join().unwrap();
-        job.join().unwrap();
+                })
+            })
+            .collect();
+        sig.run(&workers);
+        for queue in queues {
+            queue.join().unwrap();
+        }
+        for job in jobs {
+            job.join().unwrap();
+        }
         Ok(())
     }
 }
@@ -0,0 +1,113 @@
+use crate::command::Command;
+use crate::message::Request;
+use crate::utils::is_delimiter;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::sync::mpsc::{channel, Receiver, SendError, Sender};
+
+// The channels to the queue manager threads, each of which owns the queues whose
+// names are hashed to it. A request is sent to the shard of its first queue name
+// or job ID. Requests over several shards are passed on from one shard to another
+// by `Command::SHARD`, and replied by the last one.
+#[derive(Clone)]
+pub struct Shards {
+    senders: Vec<Sender<Box<Request>>>,
+}
+
+impl Shards {
+    pub(crate) fn new(n: usize) -> (Shards, Vec<Receiver<Box<Request>>>) {
+        let (senders, receivers) = (0..n.max(1)).map(|_| channel()).unzip();
+        (Shards { senders }, receivers)
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.senders.len()
+    }
+
+    // The shard which owns the queue name or the job ID.
+    pub(crate) fn index(&self, key: &[u8]) -> usize {
+        if self.senders.len() == 1 {
+            return 0;
+        }
+        let mut hasher = DefaultHasher::new();
+        hasher.write(key);
+        (hasher.finish() % self.senders.len() as u64) as usize
+    }
+
+    #[inline]
+    pub(crate) fn sender(&self, index: usize) -> &Sender<Box<Request>> {
+        &self.senders[index]
+    }
+
+    #[inline]
+    pub(crate) fn send_to(
+        &self,
+        index: usize,
+        req: Box<Request>,
+    ) -> Result<(), SendError<Box<Request>>> {
+        self.senders[index].send(req)
+    }
+
+    // Send the request to the shard which handles it first, or to all shards.
+    pub(crate) fn send(&self, req: Box<Request>) -> Result<(), SendError<Box<Request>>> {
+        let broadcast = match req.cmd {
+            Command::DISCONNECT | Command::RELOAD | Command::DUMP => true,
+            // Each shard keeps the queues which it owns.
+            Command::REPLICATE => req.arg.starts_with(b"SNAPSHOT "),
+            _ => false,
+        };
+        if !broadcast || self.senders.len() == 1 {
+            return self.send_to(self.route(req.cmd, &req.arg), req);
+        }
+        for sender in self.senders.iter() {
+            sender.send(Box::new(Request {
+                token: req.token,
+                cmd: req.cmd,
+                arg: req.arg.clone(),
+                user: req.user.clone(),
+                seq: req.seq,
+            }))?;
+        }
+        Ok(())
+    }
+
+    // The shard of the first queue name or job ID in the arguments.
+    fn first(&self, arg: &[u8]) -> usize {
+        arg.split(is_delimiter)
+            .find(|s| !s.is_empty())
+            .map(|key| self.index(key))
+            .unwrap_or(0)
+    }
+
+    fn route(&self, cmd: Command, arg: &[u8]) -> usize {
+        match cmd {
+            // record: <kind> <queue name or job id> ...
+            Command::REPLICATE => {
+                let mut iter = arg.splitn(2, is_delimiter);
+                let kind = iter.next().unwrap_or_default();
+                let arg = iter.next().unwrap_or_default();
+                match kind {
+                    b"ADD" | b"RUN" | b"READY" | b"DROP" => self.first(arg),
+                    _ => Command::from(kind)
+                        .map(|cmd| self.route(cmd, arg))
+                        .unwrap_or(0),
+                }
+            }
+            Command::ADDJOB
+            | Command::GETJOB
+            | Command::ACKJOB
+            | Command::DELJOB
+            | Command::STATQUE
+            | Command::DELQUE
+            | Command::CREATEQUE
+            | Command::PAUSEQUE
+            | Command::RESUMEQUE
+            | Command::PURGEQUE
+            | Command::MOVEQUE
+            | Command::REQUEUE => self.first(arg),
+            // SYNC, CLIENT LIST and TERMINATE go through the shards from the first one.
+            _ => 0,
+        }
+    }
+}
@@ -1,32 +1,44 @@
-use mio::Waker;
-use signal_hook::consts::TERM_SIGNALS;
+use crate::worker::Workers;
+use signal_hook::consts::{SIGHUP, SIGUSR1, TERM_SIGNALS};
 use signal_hook::flag;
 use signal_hook::iterator::exfiltrator::WithRawSiginfo;
 use signal_hook::iterator::SignalsInfo;
-use std::sync::atomic::AtomicBool;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 pub struct Sig {
     pub stat: Arc<AtomicBool>,
+    // Set by SIGHUP; the configuration file should be reloaded.
+    pub reload: Arc<AtomicBool>,
+    // Set by SIGUSR1; the statistics should be dumped to the log.
+    pub dump: Arc<AtomicBool>,
 }
 
 impl Sig {
     pub fn new(stat: Arc<AtomicBool>) -> Sig {
-        Sig { stat }
+        Sig {
+            stat,
+            reload: Arc::new(AtomicBool::new(false)),
+            dump: Arc::new(AtomicBool::new(false)),
+        }
     }
-    pub fn run(&self, waker: Arc<Waker>) {
+    // Wake every I/O thread to handle the signal.
+    pub fn run(&self, workers: &Workers) {
         for sig in TERM_SIGNALS {
             flag::register_conditional_shutdown(*sig, 1, Arc::clone(&self.stat)).unwrap();
             flag::register(*sig, Arc::clone(&self.stat)).unwrap();
         }
-        let mut sigs = Vec::new();
+        let mut sigs = vec![SIGHUP, SIGUSR1];
         sigs.extend(TERM_SIGNALS);
         let mut signals = SignalsInfo::<WithRawSiginfo>::new(&sigs).unwrap();
         for info in &mut signals {
             match info.si_signo {
+                SIGHUP => self.reload.store(true, Ordering::Relaxed),
+                SIGUSR1 => self.dump.store(true, Ordering::Relaxed),
                 _ => break,
             }
+            workers.wake_all();
         }
-        waker.wake().expect("unable to wake");
+        workers.wake_all();
     }
 }
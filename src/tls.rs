@@ -0,0 +1,216 @@
+use mio::event::Source;
+use mio::net::TcpStream;
+use mio::{Interest, Registry, Token};
+use rustls::server::{AllowAnyAuthenticatedClient, NoClientAuth};
+use rustls::{
+    Certificate, ClientConfig, ClientConnection, PrivateKey, RootCertStore, ServerConfig,
+    ServerConnection, ServerName,
+};
+use rustls_pemfile::Item;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader, IoSlice, Read, Write};
+use std::net::Shutdown;
+use std::path::Path;
+use std::sync::Arc;
+
+fn invalid_data<E: std::fmt::Display>(path: &Path, e: E) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), e),
+    )
+}
+
+fn read_items(path: &Path) -> io::Result<Vec<Item>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::read_all(&mut reader).map_err(|e| invalid_data(path, e))
+}
+
+fn read_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let certs: Vec<Certificate> = read_items(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    match certs.is_empty() {
+        true => Err(invalid_data(path, "no certificate")),
+        false => Ok(certs),
+    }
+}
+
+fn read_key(path: &Path) -> io::Result<PrivateKey> {
+    read_items(path)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(der) | Item::PKCS8Key(der) | Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| invalid_data(path, "no private key"))
+}
+
+fn read_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(path)? {
+        roots.add(&cert).map_err(|e| invalid_data(path, e))?;
+    }
+    Ok(roots)
+}
+
+// Build the configuration to accept TLS connections. Clients must present a
+// certificate signed by `client_ca`, if it is given.
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+) -> io::Result<Arc<ServerConfig>> {
+    let verifier = match client_ca {
+        Some(path) => AllowAnyAuthenticatedClient::new(read_roots(path)?),
+        None => NoClientAuth::new(),
+    };
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(read_certs(cert)?, read_key(key)?)
+        .map(Arc::new)
+        .map_err(|e| invalid_data(key, e))
+}
+
+// Build the configuration to connect to a server whose certificate is signed
+// by `ca`, presenting the client certificate if it is given.
+pub fn client_config(ca: &Path, cert_key: Option<(&Path, &Path)>) -> io::Result<Arc<ClientConfig>> {
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(read_roots(ca)?);
+    let config = match cert_key {
+        Some((cert, key)) => builder
+            .with_single_cert(read_certs(cert)?, read_key(key)?)
+            .map_err(|e| invalid_data(key, e))?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(Arc::new(config))
+}
+
+// Connect to the server over TLS with a blocking stream.
+pub fn connect(
+    config: Arc<ClientConfig>,
+    server_name: &str,
+    sock: std::net::TcpStream,
+) -> io::Result<rustls::StreamOwned<ClientConnection, std::net::TcpStream>> {
+    let name = ServerName::try_from(server_name)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let conn = ClientConnection::new(config, name).map_err(io::Error::other)?;
+    Ok(rustls::StreamOwned::new(conn, sock))
+}
+
+// A TLS session over a non-blocking TCP stream. The handshake proceeds while
+// reading and writing, so it looks like a plain stream to the server.
+pub(crate) struct TlsStream {
+    conn: ServerConnection,
+    sock: TcpStream,
+}
+
+impl TlsStream {
+    pub(crate) fn new(config: Arc<ServerConfig>, sock: TcpStream) -> io::Result<TlsStream> {
+        let conn = ServerConnection::new(config).map_err(io::Error::other)?;
+        Ok(TlsStream { conn, sock })
+    }
+
+    // Send the pending TLS records as many as the socket accepts. The rest is
+    // sent on the next writable event.
+    fn flush_tls(&mut self) -> io::Result<()> {
+        while self.conn.wants_write() {
+            match self.conn.write_tls(&mut self.sock) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        self.conn.send_close_notify();
+        let _ = self.flush_tls();
+        self.sock.shutdown(how)
+    }
+
+    // Wait for writable events too, while TLS records are left to send.
+    fn interests(&self, interests: Interest) -> Interest {
+        match self.conn.wants_write() {
+            true => interests | Interest::WRITABLE,
+            false => interests,
+        }
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.reader().read(buf) {
+                // `0` means the peer has closed the session.
+                Ok(n) => return Ok(n),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+                Err(e) if e.kind() != io::ErrorKind::WouldBlock => return Err(e),
+                Err(_) => {}
+            }
+            // Need more records from the socket.
+            match self.conn.read_tls(&mut self.sock) {
+                Ok(_) => {
+                    let processed = self.conn.process_new_packets();
+                    // Send the handshake messages or an alert.
+                    self.flush_tls()?;
+                    processed.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.flush_tls()?;
+        let n = self.conn.writer().write(buf)?;
+        self.flush_tls()?;
+        Ok(n)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.flush_tls()?;
+        let n = self.conn.writer().write_vectored(bufs)?;
+        self.flush_tls()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.flush_tls()
+    }
+}
+
+impl Source for TlsStream {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        let interests = self.interests(interests);
+        self.sock.register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        let interests = self.interests(interests);
+        self.sock.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.sock.deregister(registry)
+    }
+}
@@ -8,30 +8,14 @@ pub(crate) fn compare<T: Eq>(a: &[T], b: &[T]) -> Option<usize> {
     let mut aiter = a.iter();
     let mut biter = b.iter();
 
-    let s = match aiter.next() {
-        Some(s) => s,
-        None => return None,
-    };
-
-    let o = match biter.next() {
-        Some(o) => o,
-        None => return None,
-    };
+    let s = aiter.next()?;
+    let o = biter.next()?;
 
     if s != o {
         return None;
     }
     let mut index = 0;
-    loop {
-        let s = match aiter.next() {
-            Some(s) => s,
-            None => break,
-        };
-
-        let o = match biter.next() {
-            Some(o) => o,
-            None => break,
-        };
+    while let (Some(s), Some(o)) = (aiter.next(), biter.next()) {
         if s != o {
             break;
         }
@@ -39,3 +23,67 @@ pub(crate) fn compare<T: Eq>(a: &[T], b: &[T]) -> Option<usize> {
     }
     Some(index)
 }
+
+// Compare secrets in a constant time for the same length.
+pub(crate) fn secure_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Match the name with the pattern, where `*` matches any bytes. On a mismatch, the last
+// `*` takes one more byte of the name, so that the time is linear in each of them.
+pub(crate) fn glob(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // The positions in the pattern after the last `*` and in the name where it ends.
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                star = Some((p, n));
+            }
+            Some(c) if *c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((after, end)) => {
+                    p = after;
+                    n = end + 1;
+                    star = Some((after, n));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob(b"billing.*", b"billing.invoice"));
+        assert!(glob(b"billing.*", b"billing."));
+        assert!(glob(b"*", b""));
+        assert!(glob(b"*.done", b"billing.invoice.done"));
+        assert!(glob(b"a*b*c", b"aXbYbZc"));
+        assert!(glob(b"billing", b"billing"));
+        assert!(!glob(b"billing", b"billing."));
+        assert!(!glob(b"billing.*", b"billing"));
+        assert!(!glob(b"a*b*c", b"aXbYbZ"));
+        assert!(!glob(b"", b"a"));
+    }
+
+    // A name which fails to match the stars late is matched in linear time.
+    #[test]
+    fn glob_long_name() {
+        let name = [b"billing.".as_ref(), &b".".repeat(20000)].concat();
+        let start = Instant::now();
+        assert!(!glob(b"billing.*.*.done", &name));
+        assert!(glob(b"billing.*.*.", &name));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,82 @@
+use crate::message::{Reply, Request};
+use mio::{Token, Waker};
+use std::sync::mpsc::{channel, Receiver, SendError, Sender};
+use std::sync::Arc;
+
+// The channels to the network I/O threads. Each thread numbers its connections so
+// that `token % len` is its index, so a reply is sent to the thread which owns the
+// connection. The threads also pass on the requests over the connections of all
+// threads, e.g. CLIENT LIST, to each other.
+#[derive(Clone)]
+pub struct Workers {
+    replies: Vec<Sender<(u64, Box<Reply>)>>,
+    requests: Vec<Sender<Box<Request>>>,
+    wakers: Vec<Arc<Waker>>,
+}
+
+// The receiving ends of the channels to an I/O thread.
+pub(crate) struct Inbox {
+    // The replies with the sequence numbers of their requests.
+    pub(crate) replies: Receiver<(u64, Box<Reply>)>,
+    pub(crate) requests: Receiver<Box<Request>>,
+}
+
+impl Workers {
+    pub(crate) fn new(wakers: Vec<Arc<Waker>>) -> (Workers, Vec<Inbox>) {
+        let mut workers = Workers {
+            replies: Vec::with_capacity(wakers.len()),
+            requests: Vec::with_capacity(wakers.len()),
+            wakers,
+        };
+        let inboxes = (0..workers.wakers.len())
+            .map(|_| {
+                let (rep_tx, replies) = channel();
+                let (req_tx, requests) = channel();
+                workers.replies.push(rep_tx);
+                workers.requests.push(req_tx);
+                Inbox { replies, requests }
+            })
+            .collect();
+        (workers, inboxes)
+    }
+
+    #[inline]
+    pub(crate) fn len(&self) -> usize {
+        self.wakers.len()
+    }
+
+    // The thread which owns the connection.
+    #[inline]
+    pub(crate) fn index(&self, token: Token) -> usize {
+        token.0 % self.wakers.len()
+    }
+
+    // Send the reply to the request of the sequence number without waking the thread.
+    // It is dropped if the thread has stopped.
+    #[inline]
+    pub(crate) fn send(&self, seq: u64, reply: Box<Reply>) {
+        let _ = self.replies[self.index(reply.token)].send((seq, reply));
+    }
+
+    // Pass the request on to the thread and wake it. It fails if the thread has stopped.
+    pub(crate) fn pass(
+        &self,
+        index: usize,
+        req: Box<Request>,
+    ) -> Result<(), SendError<Box<Request>>> {
+        self.requests[index].send(req)?;
+        self.wake(index);
+        Ok(())
+    }
+
+    #[inline]
+    pub(crate) fn wake(&self, index: usize) {
+        self.wakers[index].wake().expect("unable to wake");
+    }
+
+    pub(crate) fn wake_all(&self) {
+        for index in 0..self.wakers.len() {
+            self.wake(index);
+        }
+    }
+}
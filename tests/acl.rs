@@ -0,0 +1,165 @@
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::prelude::*;
+    use std::net::TcpStream;
+    use std::path::PathBuf;
+    use std::process::{Child, Command, Stdio};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    const PORT: u16 = 9410;
+    const CONFIG: &str = r#"
+auth_tokens = ["token"]
+
+[users.producer]
+password = "secret"
+commands = ["ADDJOB", "GETJOB", "ACKJOB", "DELJOB", "STATQUE", "CLIENT"]
+queues = ["test-acl.*", "test-acl"]
+
+[users.reader]
+password = "secret"
+commands = ["*"]
+queues = ["test-acl"]
+
+[users.admin]
+password = "topsecret"
+commands = ["*"]
+queues = ["*"]
+"#;
+
+    // Kill the server and remove its configuration file even if the test fails.
+    struct Server(Child, PathBuf);
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+            let _ = fs::remove_file(&self.1);
+        }
+    }
+
+    // Jobs are spread among the shards, so that ACKJOB visits all of them.
+    fn start() -> Server {
+        let path = std::env::temp_dir().join(format!("qust-acl-{}.toml", PORT));
+        fs::write(&path, CONFIG).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_qust"))
+            .args(["-p", &PORT.to_string(), "--shards", "4", "-c"])
+            .arg(&path)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = Server(child, path);
+        let start = Instant::now();
+        while TcpStream::connect(("127.0.0.1", PORT)).is_err() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            sleep(Duration::from_millis(50));
+        }
+        server
+    }
+
+    fn request(stream: &mut TcpStream, message: &[u8]) -> Vec<u8> {
+        stream.write_all(message).unwrap();
+        let mut ret = vec![0; 0];
+        let mut buffer = [0u8; 4096];
+        while ret.last() != Some(&b'\n') {
+            let n = stream.read(&mut buffer).unwrap();
+            assert_ne!(n, 0);
+            ret.extend(&buffer[0..n]);
+        }
+        ret
+    }
+
+    fn connect(auth: &[u8]) -> TcpStream {
+        let mut stream = TcpStream::connect(("127.0.0.1", PORT)).unwrap();
+        assert_eq!(
+            request(&mut stream, &[b"AUTH ", auth, b"\n"].concat()),
+            b"1 OK\n"
+        );
+        stream
+    }
+
+    // Add a job and return its ID.
+    fn add(stream: &mut TcpStream, queue: &str) -> Vec<u8> {
+        let ret = request(stream, format!("ADDJOB {} 300 job\n", queue).as_bytes());
+        assert_eq!(&ret[..2], b"1 ");
+        ret[2..ret.len() - 1].to_vec()
+    }
+
+    #[test]
+    fn acl() {
+        let _server = start();
+
+        // Authentication by a token or a user and the password.
+        let mut stream = TcpStream::connect(("127.0.0.1", PORT)).unwrap();
+        assert_eq!(request(&mut stream, b"STATQUE test-acl\n"), b"-1 NoAuth\n");
+        assert_eq!(request(&mut stream, b"AUTH bogus\n"), b"-1 AuthFailed\n");
+        assert_eq!(
+            request(&mut stream, b"AUTH producer admin\n"),
+            b"-1 AuthFailed\n"
+        );
+        assert_eq!(
+            request(&mut stream, b"AUTH nobody secret\n"),
+            b"-1 AuthFailed\n"
+        );
+        assert_eq!(request(&mut stream, b"STATQUE test-acl\n"), b"-1 NoAuth\n");
+        assert_eq!(request(&mut stream, b"AUTH producer secret\n"), b"1 OK\n");
+        let mut producer = stream;
+        let mut admin = connect(b"admin topsecret");
+        let mut root = connect(b"token");
+
+        // The queues by the patterns.
+        for queue in ["test-acl", "test-acl.", "test-acl.a.b"].iter() {
+            add(&mut producer, queue);
+        }
+        for queue in ["test-acl-a", "test-acl2", "other.test-acl"].iter() {
+            assert_eq!(
+                request(
+                    &mut producer,
+                    format!("ADDJOB {} 300 job\n", queue).as_bytes()
+                ),
+                b"-1 NoPerm\n"
+            );
+        }
+        // Every queue given to a command must be allowed.
+        assert_eq!(
+            request(&mut producer, b"GETJOB test-acl.a test-acl-other\n"),
+            b"-1 NoPerm\n"
+        );
+        // The commands by the names.
+        assert_eq!(
+            request(&mut producer, b"PURGEQUE test-acl\n"),
+            b"-1 NoPerm\n"
+        );
+        assert_eq!(&request(&mut admin, b"PURGEQUE test-acl\n")[..2], b"1 ");
+
+        // The jobs of the other queues are unknown to the user.
+        let mut ids = Vec::new();
+        for i in 0..8 {
+            ids.push(add(&mut root, &format!("test-acl-other{}", i)));
+        }
+        let mine = add(&mut producer, "test-acl.a");
+        let arg = [mine.as_slice(), b" ", &ids.join(b" ".as_ref())].concat();
+        assert_eq!(
+            request(&mut producer, &[b"ACKJOB ", arg.as_slice(), b"\n"].concat()),
+            [b"1 1 ", &ids.join(b" ".as_ref())[..], b"\n"].concat()
+        );
+        assert_eq!(
+            request(&mut producer, &[b"DELJOB ", arg.as_slice(), b"\n"].concat()),
+            b"1 0\n"
+        );
+        assert_eq!(
+            request(&mut admin, &[b"DELJOB ", arg.as_slice(), b"\n"].concat()),
+            b"1 8\n"
+        );
+
+        // SYNC and CLIENT show every queue.
+        assert_eq!(request(&mut producer, b"CLIENT LIST\n"), b"-1 NoPerm\n");
+        let mut reader = connect(b"reader secret");
+        assert_eq!(&request(&mut reader, b"STATQUE test-acl\n")[..2], b"1 ");
+        assert_eq!(request(&mut reader, b"SYNC\n"), b"-1 NoPerm\n");
+        assert_eq!(request(&mut reader, b"CLIENT LIST\n"), b"-1 NoPerm\n");
+        assert_eq!(&request(&mut admin, b"CLIENT LIST\n")[..2], b"1 ");
+    }
+}
@@ -0,0 +1,118 @@
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::net::TcpStream;
+    use std::process::{Child, Command, Stdio};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    const JOBS: usize = 30;
+    const JOB_SIZE: usize = 1024 * 1024;
+
+    // Kill the server even if the test fails.
+    struct Server(Child);
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn start(port: u16) -> Server {
+        let server = Server(
+            Command::new(env!("CARGO_BIN_EXE_qust"))
+                .args(["-p", &port.to_string(), "--reply-high-watermark", "1000000"])
+                .args(["--max-buffer-size", "2000000"])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap(),
+        );
+        let start = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            sleep(Duration::from_millis(50));
+        }
+        server
+    }
+
+    // Read the replies until the number of them.
+    fn read_replies(stream: &mut TcpStream, count: usize) -> Vec<Vec<u8>> {
+        let mut ret = vec![0; 0];
+        let mut buffer = [0u8; 65536];
+        let mut replies = 0;
+        while replies < count {
+            let n = stream.read(&mut buffer).unwrap();
+            assert_ne!(n, 0);
+            replies += buffer[0..n].iter().filter(|b| **b == b'\n').count();
+            ret.extend(&buffer[0..n]);
+        }
+        ret.split(|b| *b == b'\n')
+            .take(count)
+            .map(|reply| reply.to_vec())
+            .collect()
+    }
+
+    fn request(stream: &mut TcpStream, message: &[u8]) -> Vec<u8> {
+        stream.write_all(message).unwrap();
+        read_replies(stream, 1).pop().unwrap()
+    }
+
+    #[test]
+    fn high_watermark() {
+        let _server = start(9360);
+        let mut producer = TcpStream::connect(("127.0.0.1", 9360)).unwrap();
+        let mut jobs = Vec::new();
+        for i in 0..JOBS {
+            let job = [format!("{}-", i).into_bytes(), vec![b'x'; JOB_SIZE]].concat();
+            let ret = request(
+                &mut producer,
+                &[b"ADDJOB test-backlog-que 300 ".as_ref(), &job, b"\n"].concat(),
+            );
+            assert_eq!(&ret[0..2], b"1 ");
+            jobs.push([&ret[2..], b" ", &job].concat());
+        }
+
+        // The consumer sends the requests one by one without reading the replies.
+        let mut consumer = TcpStream::connect(("127.0.0.1", 9360)).unwrap();
+        for _ in 0..JOBS {
+            consumer.write_all(b"GETJOB test-backlog-que\n").unwrap();
+            sleep(Duration::from_millis(20));
+        }
+        // The server has stopped reading them.
+        let ret = request(&mut producer, b"STATQUE test-backlog-que\n");
+        let stat: Vec<usize> = String::from_utf8(ret[2..].to_vec())
+            .unwrap()
+            .split(' ')
+            .map(|n| n.parse().unwrap())
+            .collect();
+        assert!(stat[1] < JOBS, "{:?}", stat);
+
+        // Every reply is written in order once the consumer reads them.
+        let replies = read_replies(&mut consumer, JOBS);
+        for (reply, job) in replies.iter().zip(jobs.iter()) {
+            assert_eq!(&reply[0..2], b"1 ");
+            assert!(&reply[2..] == job.as_slice());
+        }
+        assert_eq!(
+            request(&mut producer, b"STATQUE test-backlog-que\n"),
+            format!("1 {} {} 0 0", JOBS, JOBS).as_bytes()
+        );
+    }
+
+    // The replies by the I/O thread itself wait for the ones by the queue manager to
+    // the earlier requests.
+    #[test]
+    fn mixed_pipeline() {
+        let _server = start(9361);
+        let mut stream = TcpStream::connect(("127.0.0.1", 9361)).unwrap();
+        let ret = request(&mut stream, b"ADDJOB test-backlog-mixed 300 job\n");
+        assert_eq!(&ret[0..2], b"1 ");
+        let message = b"STATQUE test-backlog-mixed\nCLIENT SETNAME test\nUNKNOWN\n".repeat(100);
+        stream.write_all(&message).unwrap();
+        for reply in read_replies(&mut stream, 300).chunks(3) {
+            assert_eq!(reply, [b"1 1 0 0 0".as_ref(), b"1 OK", b"-1 Error"]);
+        }
+    }
+}
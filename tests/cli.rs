@@ -0,0 +1,142 @@
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::io::BufReader;
+    use std::net::{TcpListener, TcpStream};
+    use std::process::{Child, Command, Stdio};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    const ADDR: &str = "127.0.0.1:9370";
+
+    // Kill the server even if the test fails.
+    struct Server(Child);
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn start() -> Server {
+        let server = Server(
+            Command::new(env!("CARGO_BIN_EXE_qust"))
+                .args(["-p", "9370"])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap(),
+        );
+        let start = Instant::now();
+        while TcpStream::connect(ADDR).is_err() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            sleep(Duration::from_millis(50));
+        }
+        server
+    }
+
+    // Run qust_cli with the arguments and the standard input, and return the exit code and the output.
+    fn cli(args: &[&str], input: &[u8]) -> (i32, String) {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_qust_cli"))
+            .args(["-p", "9370"])
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output = child.wait_with_output().unwrap();
+        (
+            output.status.code().unwrap(),
+            String::from_utf8(output.stdout).unwrap(),
+        )
+    }
+
+    #[test]
+    fn batch() {
+        let _server = start();
+
+        // A command by the arguments.
+        let (code, out) = cli(&["ADDJOB", "test-cli-que", "300", "payload"], b"");
+        assert_eq!(code, 0);
+        assert!(out.starts_with("1 "));
+        let id = out[2..].trim_end().to_owned();
+        assert_eq!(id.len(), 32);
+
+        let (code, out) = cli(&["-o", "json", "getjob", "test-cli-que"], b"");
+        assert_eq!(code, 0);
+        assert_eq!(
+            out,
+            format!("{{\"status\": 1, \"data\": \"{} payload\"}}\n", id)
+        );
+        let (code, out) = cli(&["GETJOB", "test-cli-que"], b"");
+        assert_eq!((code, out.as_str()), (1, "0 \n"));
+
+        // The commands by the standard input, over one connection.
+        let (code, out) = cli(
+            &[],
+            format!("ACKJOB {}\r\n\nSTATQUE test-cli-que\nCLIENT LIST\n", id).as_bytes(),
+        );
+        assert_eq!(code, 0);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[..2], ["1 1", "1 0 0 1 0"]);
+        assert!(lines[2].starts_with("1 id="));
+        assert!(!lines[2].contains("; "));
+
+        // The worst reply decides the exit code, and an invalid command does not stop the rest.
+        let (code, out) = cli(
+            &["-o", "pretty"],
+            b"BOGUS\nGETJOB test-cli-que\nSTATQUE test-cli-que\n",
+        );
+        assert_eq!(code, 2);
+        assert_eq!(
+            out,
+            "(empty)\njobs: 0\nrunning: 0\nacknowledged: 1\ndeleted: 0\n"
+        );
+
+        // The commands by the file. QUIT stops them.
+        let path = std::env::temp_dir().join(format!("qust-cli-{}", std::process::id()));
+        std::fs::write(&path, "CREATEQUE test-cli-que\nQUIT\nBOGUS\n").unwrap();
+        let (code, out) = cli(&["-f", path.to_str().unwrap()], b"");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!((code, out.as_str()), (1, "0 \n"));
+
+        let (code, _) = cli(&["-p", "9371", "STATQUE", "test-cli-que"], b"");
+        assert_eq!(code, 2);
+    }
+
+    #[test]
+    fn closed_after_request() {
+        // A server which replies to the first request, and closes the connection after
+        // reading the second one.
+        let listener = TcpListener::bind("127.0.0.1:9372").unwrap();
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for stream in listener.incoming().take(2) {
+                let mut stream = BufReader::new(stream.unwrap());
+                let mut line = String::new();
+                while stream.read_line(&mut line).unwrap() > 0 {
+                    requests.push(line.clone());
+                    if requests.len() != 1 {
+                        break;
+                    }
+                    stream.get_mut().write_all(b"1 OK\n").unwrap();
+                    line.clear();
+                }
+            }
+            requests
+        });
+
+        // The second request may have been run, so it is not sent again.
+        let (code, out) = cli(
+            &["-p", "9372"],
+            b"STATQUE test-cli-que\nSTATQUE test-cli-que\n",
+        );
+        assert_eq!((code, out.as_str()), (2, "1 OK\n"));
+        // Unblock the server, which waits for a second connection the client does not make.
+        TcpStream::connect("127.0.0.1:9372").unwrap();
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,221 @@
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::net::TcpStream;
+    use std::path::PathBuf;
+    use std::process::{Child, Command, Stdio};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    const NODES: [&str; 3] = ["127.0.0.1:9320", "127.0.0.1:9321", "127.0.0.1:9322"];
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    // Kill the nodes and remove their logs even if the test fails.
+    struct Cluster {
+        nodes: &'static [&'static str],
+        children: Vec<Option<Child>>,
+    }
+
+    impl Cluster {
+        // Start the nodes given by `started` among `nodes`.
+        fn start(nodes: &'static [&'static str], started: usize) -> Cluster {
+            for node in nodes.iter() {
+                let _ = std::fs::remove_file(log(node));
+            }
+            let mut cluster = Cluster {
+                nodes,
+                children: (0..nodes.len()).map(|_| None).collect(),
+            };
+            for i in 0..started {
+                cluster.restart(i);
+            }
+            cluster
+        }
+        fn restart(&mut self, i: usize) {
+            let mut cmd = Command::new(env!("CARGO_BIN_EXE_qust"));
+            cmd.arg("-p")
+                .arg(self.nodes[i].rsplit(':').next().unwrap())
+                .args(["--cluster-auth", "secret", "--cluster-node"])
+                .arg(i.to_string())
+                .arg("--persist")
+                .arg(log(self.nodes[i]));
+            for node in self.nodes.iter() {
+                cmd.arg("--cluster").arg(node);
+            }
+            let child = cmd
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap();
+            self.children[i] = Some(child);
+        }
+        fn kill(&mut self, i: usize) {
+            if let Some(mut child) = self.children[i].take() {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+        }
+    }
+
+    impl Drop for Cluster {
+        fn drop(&mut self) {
+            for i in 0..self.nodes.len() {
+                self.kill(i);
+                let _ = std::fs::remove_file(log(self.nodes[i]));
+            }
+        }
+    }
+
+    fn log(node: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "qust-cluster-{}.log",
+            node.rsplit(':').next().unwrap()
+        ))
+    }
+
+    // Send the request on a new connection. Empty if the node is not available.
+    fn request(node: &str, message: &[u8]) -> Vec<u8> {
+        let mut stream = match TcpStream::connect(node) {
+            Ok(stream) => stream,
+            Err(_) => return vec![],
+        };
+        stream.write_all(message).unwrap();
+        let mut ret = vec![0; 0];
+        let mut buffer = [0u8; 4096];
+        while ret.last() != Some(&b'\n') {
+            match stream.read(&mut buffer) {
+                Ok(0) | Err(_) => return vec![],
+                Ok(n) => ret.extend(&buffer[0..n]),
+            }
+        }
+        ret
+    }
+
+    // Add a job on each node until the leader accepts it, and return the leader and the job ID.
+    fn add_job(all: &[&str], nodes: &[usize]) -> (usize, Vec<u8>) {
+        let start = Instant::now();
+        while start.elapsed() < TIMEOUT {
+            for i in nodes.iter() {
+                let ret = request(all[*i], b"ADDJOB test-cluster-que 300 job\n");
+                if ret.starts_with(b"1 ") {
+                    return (*i, ret[2..ret.len() - 1].to_vec());
+                }
+            }
+            sleep(Duration::from_millis(100));
+        }
+        panic!("no leader is elected");
+    }
+
+    fn wait_for(node: &str, message: &[u8], expected: &[u8]) {
+        let start = Instant::now();
+        while request(node, message) != expected {
+            assert!(start.elapsed() < TIMEOUT, "{}", node);
+            sleep(Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn failover() {
+        let mut cluster = Cluster::start(&NODES, NODES.len());
+        let (leader, first) = add_job(&NODES, &[0, 1, 2]);
+        let followers: Vec<usize> = (0..NODES.len()).filter(|i| *i != leader).collect();
+        assert_eq!(
+            request(NODES[followers[0]], b"ADDJOB test-cluster-que 300 job\n"),
+            format!("-1 Redirect {}\n", NODES[leader]).as_bytes()
+        );
+        for node in NODES.iter() {
+            wait_for(node, b"STATQUE test-cluster-que\n", b"1 1 0 0 0\n");
+        }
+
+        cluster.kill(leader);
+        let (leader, _) = add_job(&NODES, &followers);
+        assert_eq!(
+            request(
+                NODES[leader],
+                &[b"ACKJOB ".as_ref(), &first, b"\n"].concat()
+            ),
+            b"1 1\n"
+        );
+        for i in followers {
+            wait_for(NODES[i], b"STATQUE test-cluster-que\n", b"1 1 0 1 0\n");
+        }
+    }
+
+    #[test]
+    fn restart() {
+        const NODES: [&str; 3] = ["127.0.0.1:9323", "127.0.0.1:9324", "127.0.0.1:9325"];
+        let mut cluster = Cluster::start(&NODES, NODES.len());
+        add_job(&NODES, &[0, 1, 2]);
+        for node in NODES.iter() {
+            wait_for(node, b"STATQUE test-cluster-que\n", b"1 1 0 0 0\n");
+        }
+
+        // The nodes apply their logs again once a new leader commits an entry.
+        for i in 0..NODES.len() {
+            cluster.kill(i);
+        }
+        for i in 0..NODES.len() {
+            cluster.restart(i);
+        }
+        for node in NODES.iter() {
+            wait_for(node, b"STATQUE test-cluster-que\n", b"1 1 0 0 0\n");
+        }
+    }
+
+    // Connect to the node once it is started.
+    fn connect(node: &str) -> TcpStream {
+        let start = Instant::now();
+        loop {
+            match TcpStream::connect(node) {
+                Ok(stream) => return stream,
+                Err(_) => {
+                    assert!(start.elapsed() < TIMEOUT, "{}", node);
+                    sleep(Duration::from_millis(50));
+                }
+            }
+        }
+    }
+
+    fn call(stream: &mut TcpStream, message: &[u8]) -> Vec<u8> {
+        stream.write_all(message).unwrap();
+        let mut ret = vec![0; 0];
+        let mut buffer = [0u8; 4096];
+        while ret.last() != Some(&b'\n') {
+            let n = stream.read(&mut buffer).unwrap();
+            assert_ne!(n, 0);
+            ret.extend(&buffer[0..n]);
+        }
+        ret
+    }
+
+    #[test]
+    fn raft() {
+        // Only the first node runs, so no leader is elected.
+        const NODES: [&str; 3] = ["127.0.0.1:9326", "127.0.0.1:9327", "127.0.0.1:9328"];
+        let mut cluster = Cluster::start(&NODES, 1);
+        let mut stream = connect(NODES[0]);
+
+        // RAFT is accepted only from the other nodes.
+        assert_eq!(call(&mut stream, b"RAFT VOTE 100 1 0 0\n"), b"-1 NoPerm\n");
+        assert_eq!(call(&mut stream, b"AUTH other\n"), b"1 OK\n");
+        assert_eq!(call(&mut stream, b"RAFT VOTE 100 1 0 0\n"), b"-1 NoPerm\n");
+        assert_eq!(call(&mut stream, b"AUTH secret\n"), b"1 OK\n");
+
+        // Invalid indexes of the nodes and the log.
+        assert!(call(&mut stream, b"RAFT APPEND 100 99 0 0 0\n").starts_with(b"-1 "));
+        assert!(call(&mut stream, b"RAFT VOTE 100 99 0 0\n").starts_with(b"-1 "));
+        assert_eq!(
+            call(&mut stream, b"RAFT APPEND 100 1 0 5 0\n"),
+            b"1 100 0 0\n"
+        );
+        assert_eq!(call(&mut stream, b"RAFT VOTE 100 2 0 0\n"), b"1 100 1\n");
+
+        // The vote is kept by a restarted node.
+        cluster.kill(0);
+        cluster.restart(0);
+        let mut stream = connect(NODES[0]);
+        assert_eq!(call(&mut stream, b"AUTH secret\n"), b"1 OK\n");
+        assert_eq!(call(&mut stream, b"RAFT VOTE 100 1 0 0\n"), b"1 100 0\n");
+        assert_eq!(call(&mut stream, b"RAFT VOTE 100 2 0 0\n"), b"1 100 1\n");
+    }
+}
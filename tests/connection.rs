@@ -0,0 +1,153 @@
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::net::TcpStream;
+    use std::process::{Child, Command, Stdio};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    const JOBS: usize = 32;
+    const JOB_SIZE: usize = 1024 * 1024;
+
+    // Kill the server even if the test fails.
+    struct Server(Child);
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    // The first connection is kept, so that it is not counted after it is closed.
+    fn start(port: u16, args: &[&str]) -> (Server, TcpStream) {
+        let server = Server(
+            Command::new(env!("CARGO_BIN_EXE_qust"))
+                .args(["-p", &port.to_string()])
+                .args(args)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap(),
+        );
+        let start = Instant::now();
+        loop {
+            match TcpStream::connect(("127.0.0.1", port)) {
+                Ok(stream) => return (server, stream),
+                Err(_) => {
+                    assert!(start.elapsed() < Duration::from_secs(10));
+                    sleep(Duration::from_millis(50));
+                }
+            }
+        }
+    }
+
+    fn request(stream: &mut TcpStream, message: &[u8]) -> Vec<u8> {
+        stream.write_all(message).unwrap();
+        let mut ret = vec![0; 0];
+        let mut buffer = [0u8; 4096];
+        while ret.last() != Some(&b'\n') {
+            let n = stream.read(&mut buffer).unwrap();
+            assert_ne!(n, 0);
+            ret.extend(&buffer[0..n]);
+        }
+        ret
+    }
+
+    // True if the server closes the connection within the time.
+    fn is_closed(stream: &mut TcpStream, timeout: Duration) -> bool {
+        stream.set_read_timeout(Some(timeout)).unwrap();
+        let mut buffer = [0u8; 4096];
+        loop {
+            match stream.read(&mut buffer) {
+                Ok(0) => return true,
+                Ok(_) => {}
+                Err(ref err) if err.kind() == std::io::ErrorKind::ConnectionReset => return true,
+                Err(_) => return false,
+            }
+        }
+    }
+
+    #[test]
+    fn max_connections() {
+        let port = 9440;
+        let (_server, mut first) = start(port, &["--max-connections", "2"]);
+        let mut second = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        assert_eq!(
+            request(&mut first, b"STATQUE test-conn-max\n"),
+            b"0 0 0 0 0\n"
+        );
+        assert_eq!(
+            request(&mut second, b"STATQUE test-conn-max\n"),
+            b"0 0 0 0 0\n"
+        );
+
+        // The connection beyond the limit is closed with the error.
+        let mut rejected = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut buffer = [0u8; 64];
+        let n = rejected.read(&mut buffer).unwrap();
+        assert_eq!(&buffer[0..n], b"-1 TooManyConnections\n");
+        assert!(is_closed(&mut rejected, Duration::from_secs(5)));
+
+        // A closed connection leaves room for another one.
+        drop(second);
+        let start = Instant::now();
+        loop {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream.write_all(b"STATQUE test-conn-max\n").unwrap();
+            let n = stream.read(&mut buffer).unwrap();
+            if &buffer[0..n] == b"0 0 0 0 0\n" {
+                break;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10));
+            sleep(Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let port = 9441;
+        let (_server, mut idle) = start(port, &["--idle-timeout", "1"]);
+        let mut active = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        // The connection sending the requests is kept.
+        for _ in 0..5 {
+            assert_eq!(
+                request(&mut active, b"STATQUE test-conn-idle\n"),
+                b"0 0 0 0 0\n"
+            );
+            sleep(Duration::from_millis(500));
+        }
+        assert!(is_closed(&mut idle, Duration::from_secs(5)));
+        assert_eq!(
+            request(&mut active, b"STATQUE test-conn-idle\n"),
+            b"0 0 0 0 0\n"
+        );
+    }
+
+    #[test]
+    fn reply_timeout() {
+        let port = 9442;
+        let (_server, mut producer) = start(port, &["--reply-timeout", "1"]);
+        let job = vec![b'x'; JOB_SIZE];
+        for _ in 0..JOBS {
+            let ret = request(
+                &mut producer,
+                &[b"ADDJOB test-conn-reply 300 ".as_ref(), &job, b"\n"].concat(),
+            );
+            assert_eq!(&ret[0..2], b"1 ");
+        }
+
+        // The consumer does not read the replies, which are more than the socket takes.
+        let mut consumer = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        for _ in 0..JOBS {
+            // The connection may have been closed already.
+            let _ = consumer.write_all(b"GETJOB test-conn-reply\n");
+            sleep(Duration::from_millis(50));
+        }
+        sleep(Duration::from_millis(2000));
+        assert!(is_closed(&mut consumer, Duration::from_secs(5)));
+        // The producer is idle but has no reply waiting, so it is kept.
+        let ret = request(&mut producer, b"STATQUE test-conn-reply\n");
+        assert!(ret.starts_with(format!("1 {} ", JOBS).as_bytes()));
+    }
+}
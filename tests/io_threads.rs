@@ -0,0 +1,117 @@
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::net::TcpStream;
+    use std::process::{Child, Command, Stdio};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    const ADDR: &str = "127.0.0.1:9350";
+    const CONNECTIONS: usize = 6;
+
+    // Kill the server even if the test fails.
+    struct Server(Child);
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    // The first connection is kept, so that it is not counted after it is closed.
+    fn start() -> (Server, TcpStream) {
+        let server = Server(
+            Command::new(env!("CARGO_BIN_EXE_qust"))
+                .args(["-p", "9350", "--io-threads", "3", "--max-connections"])
+                .arg(CONNECTIONS.to_string())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap(),
+        );
+        let start = Instant::now();
+        loop {
+            match TcpStream::connect(ADDR) {
+                Ok(stream) => return (server, stream),
+                Err(_) => {
+                    assert!(start.elapsed() < Duration::from_secs(10));
+                    sleep(Duration::from_millis(50));
+                }
+            }
+        }
+    }
+
+    fn request(stream: &mut TcpStream, message: &[u8]) -> Vec<u8> {
+        stream.write_all(message).unwrap();
+        let mut ret = vec![0; 0];
+        let mut buffer = [0u8; 4096];
+        while ret.last() != Some(&b'\n') {
+            let n = stream.read(&mut buffer).unwrap();
+            assert_ne!(n, 0);
+            ret.extend(&buffer[0..n]);
+        }
+        ret
+    }
+
+    // The ids of the connections listed by CLIENT LIST, by their names.
+    fn list(stream: &mut TcpStream) -> Vec<(String, String)> {
+        let ret = String::from_utf8(request(stream, b"CLIENT LIST\n")).unwrap();
+        assert!(ret.starts_with("1 "));
+        let mut clients: Vec<(String, String)> = ret[2..]
+            .trim_end()
+            .split("; ")
+            .map(|entry| {
+                let field = |key: &str| {
+                    entry
+                        .split(' ')
+                        .find_map(|field| field.strip_prefix(key))
+                        .unwrap()
+                        .to_owned()
+                };
+                (field("name="), field("id="))
+            })
+            .collect();
+        clients.sort();
+        clients
+    }
+
+    #[test]
+    fn across_threads() {
+        let (_server, stream) = start();
+        let mut streams: Vec<TcpStream> = (1..CONNECTIONS)
+            .map(|_| TcpStream::connect(ADDR).unwrap())
+            .collect();
+        streams.insert(0, stream);
+        for (i, stream) in streams.iter_mut().enumerate() {
+            assert_eq!(
+                request(stream, format!("CLIENT SETNAME test-{}\n", i).as_bytes()),
+                b"1 OK\n"
+            );
+        }
+
+        // The limit of the connections is shared by the threads.
+        let mut rejected = TcpStream::connect(ADDR).unwrap();
+        let mut buffer = [0u8; 64];
+        let n = rejected.read(&mut buffer).unwrap();
+        assert_eq!(&buffer[0..n], b"-1 TooManyConnections\n");
+
+        // Every thread lists its connections, and kills them for the others.
+        let clients = list(&mut streams[0]);
+        assert_eq!(clients.len(), CONNECTIONS);
+        for (_, id) in clients.iter().skip(1) {
+            assert_eq!(
+                request(&mut streams[0], format!("CLIENT KILL {}\n", id).as_bytes()),
+                b"1 1\n"
+            );
+        }
+        assert_eq!(list(&mut streams[0]), clients[0..1]);
+        assert_eq!(
+            request(
+                &mut streams[0],
+                format!("CLIENT KILL {}\n", clients[1].1).as_bytes()
+            ),
+            b"0 0\n"
+        );
+    }
+}
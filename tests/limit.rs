@@ -0,0 +1,137 @@
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::prelude::*;
+    use std::net::TcpStream;
+    use std::path::PathBuf;
+    use std::process::{Child, Command, Stdio};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    // The connections may take 4 requests at once, and the user 1 STATQUE.
+    const CONFIG: &str = r#"
+auth_tokens = ["token"]
+
+[rate_limit]
+"*" = { rate = 0.01, burst = 4 }
+
+[users.limited]
+password = "secret"
+commands = ["*"]
+queues = ["*"]
+rate_limit = { STATQUE = { rate = 0.01, burst = 1 } }
+
+[users.refilled]
+password = "secret"
+commands = ["*"]
+queues = ["*"]
+rate_limit = { STATQUE = { rate = 5, burst = 1 } }
+"#;
+
+    // Kill the server and remove its configuration file even if the test fails.
+    struct Server(Child, PathBuf);
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+            let _ = fs::remove_file(&self.1);
+        }
+    }
+
+    impl Server {
+        fn start(port: u16) -> Server {
+            let path = std::env::temp_dir().join(format!("qust-limit-{}.toml", port));
+            fs::write(&path, CONFIG).unwrap();
+            let child = Command::new(env!("CARGO_BIN_EXE_qust"))
+                .args(["-p", &port.to_string(), "-c"])
+                .arg(&path)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap();
+            let server = Server(child, path);
+            let start = Instant::now();
+            while TcpStream::connect(("127.0.0.1", port)).is_err() {
+                assert!(start.elapsed() < Duration::from_secs(10));
+                sleep(Duration::from_millis(50));
+            }
+            server
+        }
+    }
+
+    fn connect(port: u16, auth: &[u8]) -> TcpStream {
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        assert_eq!(
+            request(&mut stream, &[b"AUTH ", auth, b"\n"].concat()),
+            b"1 OK\n"
+        );
+        stream
+    }
+
+    fn request(stream: &mut TcpStream, message: &[u8]) -> Vec<u8> {
+        stream.write_all(message).unwrap();
+        let mut ret = vec![0; 0];
+        let mut buffer = [0u8; 4096];
+        while ret.last() != Some(&b'\n') {
+            let n = stream.read(&mut buffer).unwrap();
+            assert_ne!(n, 0);
+            ret.extend(&buffer[0..n]);
+        }
+        ret
+    }
+
+    fn is_throttled(stream: &mut TcpStream, message: &[u8]) -> bool {
+        request(stream, message) == b"-1 Throttled\n"
+    }
+
+    #[test]
+    fn burst() {
+        let port = 9400;
+        let _server = Server::start(port);
+        let mut stream = connect(port, b"token");
+        for _ in 0..4 {
+            assert!(!is_throttled(&mut stream, b"STATQUE test-limit\n"));
+        }
+        assert!(is_throttled(&mut stream, b"STATQUE test-limit\n"));
+        assert!(is_throttled(&mut stream, b"ADDJOB test-limit 300 job\n"));
+
+        // Another connection has its own bucket.
+        let mut stream = connect(port, b"token");
+        assert!(!is_throttled(&mut stream, b"STATQUE test-limit\n"));
+    }
+
+    #[test]
+    fn user() {
+        let port = 9401;
+        let _server = Server::start(port);
+        let mut stream = connect(port, b"limited secret");
+        assert!(!is_throttled(&mut stream, b"STATQUE test-limit\n"));
+        // Throttled by the user, which leaves the tokens of the connection.
+        for _ in 0..2 {
+            assert!(is_throttled(&mut stream, b"STATQUE test-limit\n"));
+        }
+        for _ in 0..3 {
+            assert!(!is_throttled(&mut stream, b"ADDJOB test-limit 300 job\n"));
+        }
+        assert!(is_throttled(&mut stream, b"ADDJOB test-limit 300 job\n"));
+
+        // The bucket of the user is shared by the connections.
+        let mut stream = connect(port, b"limited secret");
+        assert!(is_throttled(&mut stream, b"STATQUE test-limit\n"));
+        assert!(!is_throttled(&mut stream, b"ADDJOB test-limit 300 job\n"));
+    }
+
+    #[test]
+    fn refill() {
+        let port = 9402;
+        let _server = Server::start(port);
+        let mut stream = connect(port, b"refilled secret");
+        assert!(!is_throttled(&mut stream, b"STATQUE test-limit\n"));
+        assert!(is_throttled(&mut stream, b"STATQUE test-limit\n"));
+        // 5 tokens per second.
+        sleep(Duration::from_millis(300));
+        assert!(!is_throttled(&mut stream, b"STATQUE test-limit\n"));
+        assert!(is_throttled(&mut stream, b"STATQUE test-limit\n"));
+    }
+}
@@ -0,0 +1,150 @@
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::net::TcpStream;
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::{Path, PathBuf};
+    use std::process::{Child, Command, Stdio};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    // Kill the server even if the test fails.
+    struct Server(Child);
+
+    impl Server {
+        // Start the server, and wait until the connection is made.
+        fn start<S, F: Fn() -> Option<S>>(args: &[&str], connect: F) -> (Server, S) {
+            let child = Command::new(env!("CARGO_BIN_EXE_qust"))
+                .args(args)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap();
+            let server = Server(child);
+            let start = Instant::now();
+            loop {
+                if let Some(stream) = connect() {
+                    return (server, stream);
+                }
+                assert!(start.elapsed() < Duration::from_secs(10));
+                sleep(Duration::from_millis(50));
+            }
+        }
+        // Shut down by SIGTERM.
+        fn stop(mut self) {
+            Command::new("kill")
+                .arg(self.0.id().to_string())
+                .status()
+                .unwrap();
+            self.0.wait().unwrap();
+        }
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn request<S: Read + Write>(stream: &mut S, message: &[u8]) -> Vec<u8> {
+        stream.write_all(message).unwrap();
+        let mut ret = vec![0; 0];
+        let mut buffer = [0u8; 4096];
+        while ret.last() != Some(&b'\n') {
+            let n = stream.read(&mut buffer).unwrap();
+            assert_ne!(n, 0);
+            ret.extend(&buffer[0..n]);
+        }
+        ret
+    }
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("qust-{}-{}.sock", name, std::process::id()))
+    }
+
+    fn connect_unix(path: &Path) -> Option<UnixStream> {
+        UnixStream::connect(path).ok()
+    }
+
+    #[test]
+    fn unix_socket() {
+        let path = socket_path("unix");
+        let (server, mut unix) =
+            Server::start(&["-p", "9420", "-s", path.to_str().unwrap()], || {
+                connect_unix(&path)
+            });
+        let ret = request(&mut unix, b"ADDJOB test-listen-unix 300 job\n");
+        assert_eq!(&ret[0..2], b"1 ");
+
+        // The TCP listener serves the same queues.
+        let mut tcp = TcpStream::connect(("127.0.0.1", 9420)).unwrap();
+        assert_eq!(
+            request(&mut tcp, b"STATQUE test-listen-unix\n"),
+            b"1 1 0 0 0\n"
+        );
+
+        // The socket file is removed on shutdown.
+        server.stop();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn unix_socket_only() {
+        let path = socket_path("no-tcp");
+        // A stale socket file is replaced.
+        drop(UnixListener::bind(&path).unwrap());
+        let (_server, mut unix) = Server::start(
+            &["-p", "9421", "--no-tcp", "-s", path.to_str().unwrap()],
+            || connect_unix(&path),
+        );
+        assert_eq!(
+            request(&mut unix, b"STATQUE test-listen-no-tcp\n"),
+            b"0 0 0 0 0\n"
+        );
+        assert!(TcpStream::connect(("127.0.0.1", 9421)).is_err());
+    }
+
+    #[test]
+    fn ipv6() {
+        let (_server, mut stream) = Server::start(&["-h", "[::1]", "-p", "9422"], || {
+            TcpStream::connect("[::1]:9422").ok()
+        });
+        assert_eq!(
+            request(&mut stream, b"STATQUE test-listen-ipv6\n"),
+            b"0 0 0 0 0\n"
+        );
+        assert!(TcpStream::connect(("127.0.0.1", 9422)).is_err());
+    }
+
+    // Every address given by `--bind` serves the same queues.
+    #[test]
+    fn multiple_binds() {
+        let path = socket_path("bind");
+        let unix = format!("unix:{}", path.display());
+        let args = [
+            "-p",
+            "9425",
+            "--no-tcp",
+            "-b",
+            "127.0.0.1:9423",
+            "-b",
+            "[::1]:9424",
+            "-b",
+            &unix,
+        ];
+        let (_server, mut unix) = Server::start(&args, || connect_unix(&path));
+        let mut ipv4 = TcpStream::connect("127.0.0.1:9423").unwrap();
+        let mut ipv6 = TcpStream::connect("[::1]:9424").unwrap();
+        let ret = request(&mut ipv4, b"ADDJOB test-listen-bind 300 job\n");
+        assert_eq!(&ret[0..2], b"1 ");
+        let ret = request(&mut ipv6, b"ADDJOB test-listen-bind 300 job\n");
+        assert_eq!(&ret[0..2], b"1 ");
+        assert_eq!(
+            request(&mut unix, b"STATQUE test-listen-bind\n"),
+            b"1 2 0 0 0\n"
+        );
+        // The port is not listened on without TCP.
+        assert!(TcpStream::connect(("127.0.0.1", 9425)).is_err());
+    }
+}
@@ -0,0 +1,228 @@
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hasher;
+    use std::io::prelude::*;
+    use std::net::TcpStream;
+    use std::process::{Child, Command, Stdio};
+    use std::thread::{self, sleep};
+    use std::time::{Duration, Instant};
+
+    // Each job takes its ID of 32 bytes and its data.
+    const JOB_SIZE: usize = 100;
+    const MAX_MEMORY: usize = 10 * JOB_SIZE;
+
+    // Kill the server even if the test fails.
+    struct Server(Child);
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn start(port: u16, args: &[&str]) -> (Server, TcpStream) {
+        let server = Server(
+            Command::new(env!("CARGO_BIN_EXE_qust"))
+                .args(["-p", &port.to_string(), "--max-memory"])
+                .arg(MAX_MEMORY.to_string())
+                .args(["--evict-queue", "test-mem-evict"])
+                .args(args)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap(),
+        );
+        let start = Instant::now();
+        loop {
+            match TcpStream::connect(("127.0.0.1", port)) {
+                Ok(stream) => return (server, stream),
+                Err(_) => {
+                    assert!(start.elapsed() < Duration::from_secs(10));
+                    sleep(Duration::from_millis(50));
+                }
+            }
+        }
+    }
+
+    fn request(stream: &mut TcpStream, message: &[u8]) -> Vec<u8> {
+        stream.write_all(message).unwrap();
+        let mut ret = vec![0; 0];
+        let mut buffer = [0u8; 4096];
+        while ret.last() != Some(&b'\n') {
+            let n = stream.read(&mut buffer).unwrap();
+            assert_ne!(n, 0);
+            ret.extend(&buffer[0..n]);
+        }
+        ret
+    }
+
+    // Add a job taking `size` bytes to the queue, and return the status of the reply.
+    fn add(stream: &mut TcpStream, queue: &str, size: usize) -> Vec<u8> {
+        let job = vec![b'x'; size - 32];
+        let ret = request(
+            stream,
+            &[format!("ADDJOB {} 300 ", queue).as_bytes(), &job, b"\n"].concat(),
+        );
+        ret[..ret.iter().position(|b| *b == b' ').unwrap()].to_vec()
+    }
+
+    fn stat(stream: &mut TcpStream, queue: &str) -> Vec<u8> {
+        request(stream, format!("STATQUE {}\n", queue).as_bytes())
+    }
+
+    // Fill the memory with the evictable queue and the other queues, and check that
+    // only the ready jobs of the evictable queue make room for new ones.
+    fn evict(stream: &mut TcpStream, queues: &[&str]) {
+        for _ in 0..4 {
+            assert_eq!(add(stream, "test-mem-evict", JOB_SIZE), b"1");
+        }
+        // One of the evictable jobs is running, so it is kept.
+        let ret = request(stream, b"GETJOB test-mem-evict\n");
+        assert_eq!(&ret[..2], b"1 ");
+        for i in 0..6 {
+            assert_eq!(add(stream, queues[i % queues.len()], JOB_SIZE), b"1");
+        }
+        // The memory is full. The 3 ready jobs of the evictable queue are dropped
+        // from the oldest to make room, and then nothing is left to drop.
+        assert_eq!(add(stream, queues[0], 2 * JOB_SIZE), b"1");
+        assert_eq!(stat(stream, "test-mem-evict"), b"1 2 1 0 0\n");
+        assert_eq!(add(stream, queues[0], JOB_SIZE), b"1");
+        assert_eq!(stat(stream, "test-mem-evict"), b"1 1 1 0 0\n");
+        assert_eq!(add(stream, queues[0], JOB_SIZE), b"-1");
+        assert_eq!(
+            request(stream, format!("ADDJOB {} 300 x\n", queues[0]).as_bytes()),
+            b"-1 OutOfMemory\n"
+        );
+        assert_eq!(add(stream, "test-mem-evict", JOB_SIZE), b"-1");
+
+        // Removing the jobs frees the memory.
+        assert_eq!(
+            request(stream, format!("PURGEQUE {}\n", queues[0]).as_bytes())[..2],
+            *b"1 "
+        );
+        assert_eq!(add(stream, queues[0], JOB_SIZE), b"1");
+    }
+
+    #[test]
+    fn out_of_memory() {
+        let (_server, mut stream) = start(9380, &[]);
+        evict(&mut stream, &["test-mem-que"]);
+    }
+
+    fn shard(name: &str, shards: u64) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(name.as_bytes());
+        hasher.finish() % shards
+    }
+
+    // The memory is shared by the shards, and the jobs of the evictable queue in one
+    // shard make room for the jobs of the other shards.
+    #[test]
+    fn across_shards() {
+        let (_server, mut stream) = start(9381, &["--shards", "4"]);
+        // Two queues in two other shards.
+        let mut shards = vec![shard("test-mem-evict", 4)];
+        let mut queues = Vec::new();
+        for i in 0.. {
+            let name = format!("test-mem-que{}", i);
+            if !shards.contains(&shard(&name, 4)) {
+                shards.push(shard(&name, 4));
+                queues.push(name);
+                if queues.len() == 2 {
+                    break;
+                }
+            }
+        }
+        let queues: Vec<&str> = queues.iter().map(String::as_str).collect();
+        evict(&mut stream, &queues);
+    }
+
+    // A job for which the evictable jobs are not enough fails, and drops none of them.
+    #[test]
+    fn keep_evictable_jobs() {
+        for (port, shards) in [(9383, "1"), (9384, "4")] {
+            let (_server, mut stream) = start(port, &["--shards", shards]);
+            for _ in 0..2 {
+                assert_eq!(add(&mut stream, "test-mem-evict", JOB_SIZE), b"1");
+            }
+            for _ in 0..8 {
+                assert_eq!(add(&mut stream, "test-mem-keep", JOB_SIZE), b"1");
+            }
+            assert_eq!(add(&mut stream, "test-mem-keep", 3 * JOB_SIZE), b"-1");
+            assert_eq!(stat(&mut stream, "test-mem-evict"), b"1 2 0 0 0\n");
+            assert_eq!(add(&mut stream, "test-mem-keep", 2 * JOB_SIZE), b"1");
+            assert_eq!(stat(&mut stream, "test-mem-keep"), b"1 9 0 0 0\n");
+        }
+    }
+
+    // The jobs added at once in every shard take the room of the evictable jobs. An
+    // evictable job is dropped only for a job which is added, and none for the ones
+    // which fail.
+    #[test]
+    fn evict_for_added_jobs() {
+        const EVICTABLE: usize = 1000;
+        const WORKERS: usize = 8;
+        const JOBS: usize = 100;
+        let max_memory = ((EVICTABLE + 10) * JOB_SIZE).to_string();
+        let args = ["--shards", "4", "--max-memory", &max_memory];
+        let (_server, mut stream) = start(9382, &args);
+        for _ in 0..EVICTABLE {
+            assert_eq!(add(&mut stream, "test-mem-evict", JOB_SIZE), b"1");
+        }
+        for _ in 0..10 {
+            assert_eq!(add(&mut stream, "test-mem-full", JOB_SIZE), b"1");
+        }
+
+        // Two workers for a queue in each shard send their jobs at once.
+        let mut queues: Vec<String> = Vec::new();
+        for i in 0.. {
+            let name = format!("test-mem-race{}", i);
+            if queues
+                .iter()
+                .all(|queue| shard(queue, 4) != shard(&name, 4))
+            {
+                queues.push(name);
+                if queues.len() == 4 {
+                    break;
+                }
+            }
+        }
+        // Half of the workers add jobs of 3 times the size, for which 3 jobs are evicted.
+        let workers: Vec<_> = (0..WORKERS)
+            .map(|i| {
+                let size = (1 + i % 2 * 2) * JOB_SIZE;
+                let job = [
+                    format!("ADDJOB {} 300 ", queues[i % queues.len()]).as_bytes(),
+                    &vec![b'x'; size - 32],
+                    b"\n",
+                ]
+                .concat();
+                thread::spawn(move || {
+                    let mut stream = TcpStream::connect(("127.0.0.1", 9382)).unwrap();
+                    stream.write_all(&job.repeat(JOBS)).unwrap();
+                    let mut replies = Vec::new();
+                    let mut buffer = [0u8; 4096];
+                    while replies.iter().filter(|b| **b == b'\n').count() < JOBS {
+                        let n = stream.read(&mut buffer).unwrap();
+                        assert_ne!(n, 0);
+                        replies.extend(&buffer[0..n]);
+                    }
+                    let added = replies
+                        .split(|b| *b == b'\n')
+                        .filter(|reply| reply.starts_with(b"1 "))
+                        .count();
+                    added * size / JOB_SIZE
+                })
+            })
+            .collect();
+        // The memory is kept full, and the evicted jobs make room only for the added ones.
+        let added: usize = workers.into_iter().map(|w| w.join().unwrap()).sum();
+        assert!(added > 0);
+        assert_eq!(
+            stat(&mut stream, "test-mem-evict"),
+            format!("1 {} 0 0 0\n", EVICTABLE - added).as_bytes()
+        );
+    }
+}
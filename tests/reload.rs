@@ -0,0 +1,137 @@
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::prelude::*;
+    use std::net::TcpStream;
+    use std::path::PathBuf;
+    use std::process::{Child, Command, Stdio};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    const PORT: u16 = 9390;
+
+    // Kill the server and remove its configuration file even if the test fails.
+    struct Server(Child, PathBuf);
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+            let _ = fs::remove_file(&self.1);
+        }
+    }
+
+    impl Server {
+        fn start(config: &str, args: &[&str]) -> Server {
+            let path = std::env::temp_dir().join(format!("qust-reload-{}.toml", PORT));
+            fs::write(&path, config).unwrap();
+            let child = Command::new(env!("CARGO_BIN_EXE_qust"))
+                .args(["-p", &PORT.to_string(), "-c"])
+                .arg(&path)
+                .args(args)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap();
+            let server = Server(child, path);
+            let start = Instant::now();
+            while TcpStream::connect(("127.0.0.1", PORT)).is_err() {
+                assert!(start.elapsed() < Duration::from_secs(10));
+                sleep(Duration::from_millis(50));
+            }
+            server
+        }
+
+        fn reload(&self, config: &str) {
+            fs::write(&self.1, config).unwrap();
+            let status = Command::new("kill")
+                .args(["-HUP", &self.0.id().to_string()])
+                .status()
+                .unwrap();
+            assert!(status.success());
+        }
+    }
+
+    fn connect() -> TcpStream {
+        TcpStream::connect(("127.0.0.1", PORT)).unwrap()
+    }
+
+    // Read the replies until `lines` lines are read.
+    fn receive(stream: &mut TcpStream, lines: usize) -> Vec<u8> {
+        let mut ret = vec![0; 0];
+        let mut buffer = [0u8; 4096];
+        while ret.iter().filter(|b| **b == b'\n').count() < lines {
+            let n = stream.read(&mut buffer).unwrap();
+            assert_ne!(n, 0);
+            ret.extend(&buffer[0..n]);
+        }
+        ret
+    }
+
+    fn request(stream: &mut TcpStream, message: &[u8]) -> Vec<u8> {
+        stream.write_all(message).unwrap();
+        receive(stream, 1)
+    }
+
+    fn add(stream: &mut TcpStream, queue: &str, size: usize) -> Vec<u8> {
+        let job = vec![b'x'; size];
+        let ret = request(
+            stream,
+            &[format!("ADDJOB {} 300 ", queue).as_bytes(), &job, b"\n"].concat(),
+        );
+        ret[..ret.iter().position(|b| *b == b' ').unwrap()].to_vec()
+    }
+
+    #[test]
+    fn reload() {
+        let server = Server::start(
+            "max_memory = 1000000\n",
+            &["--max-memory", "1000", "--evict-queue", "test-reload-evict"],
+        );
+        assert_eq!(add(&mut connect(), "test-reload-que", 2000), b"-1");
+
+        server.reload(concat!(
+            "max_memory = 1000000\n",
+            "max_connections = 2\n",
+            "[rate_limit]\n",
+            "STATQUE = { rate = 1, burst = 3 }\n",
+        ));
+        // Wait until a new connection is throttled.
+        let start = Instant::now();
+        let mut stream = loop {
+            let mut stream = connect();
+            stream
+                .write_all(&b"STATQUE test-reload-que\n".repeat(4))
+                .unwrap();
+            let ret = receive(&mut stream, 4);
+            if ret
+                .split(|b| *b == b'\n')
+                .any(|line| line == b"-1 Throttled")
+            {
+                break stream;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10));
+            sleep(Duration::from_millis(50));
+        };
+        // Let the server close the connections dropped above.
+        sleep(Duration::from_millis(200));
+
+        // The limit of the connections is taken.
+        let mut other = connect();
+        assert_eq!(
+            request(&mut other, b"STATQUE test-reload-evict\n"),
+            b"0 0 0 0 0\n"
+        );
+        assert_eq!(receive(&mut connect(), 1), b"-1 TooManyConnections\n");
+
+        // The memory limit and the evictable queue of the command line are kept.
+        assert_eq!(add(&mut stream, "test-reload-que", 2000), b"-1");
+        for _ in 0..3 {
+            assert_eq!(add(&mut stream, "test-reload-evict", 400), b"1");
+        }
+        assert_eq!(
+            request(&mut other, b"STATQUE test-reload-evict\n"),
+            b"1 2 0 0 0\n"
+        );
+    }
+}
@@ -0,0 +1,142 @@
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::net::TcpStream;
+    use std::process::{Child, Command, Stdio};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    // Kill the server even if the test fails.
+    struct Server(Child);
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn start(port: u16, args: &[&str]) -> (Server, TcpStream) {
+        let server = Server(
+            Command::new(env!("CARGO_BIN_EXE_qust"))
+                .args(["-p", &port.to_string()])
+                .args(args)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap(),
+        );
+        let start = Instant::now();
+        loop {
+            match TcpStream::connect(("127.0.0.1", port)) {
+                Ok(stream) => return (server, stream),
+                Err(_) => {
+                    assert!(start.elapsed() < Duration::from_secs(10));
+                    sleep(Duration::from_millis(50));
+                }
+            }
+        }
+    }
+
+    fn request(stream: &mut TcpStream, message: &[u8]) -> Vec<u8> {
+        stream.write_all(message).unwrap();
+        let mut ret = vec![0; 0];
+        let mut buffer = [0u8; 4096];
+        while ret.last() != Some(&b'\n') {
+            let n = stream.read(&mut buffer).unwrap();
+            assert_ne!(n, 0);
+            ret.extend(&buffer[0..n]);
+        }
+        ret
+    }
+
+    // Get a job of the queue, and return its ID.
+    fn get(stream: &mut TcpStream, queue: &str) -> Vec<u8> {
+        let ret = request(stream, format!("GETJOB {}\n", queue).as_bytes());
+        assert_eq!(&ret[0..2], b"1 ");
+        ret[2..].split(|b| *b == b' ').next().unwrap().to_vec()
+    }
+
+    fn stat(stream: &mut TcpStream, queue: &str) -> Vec<u8> {
+        request(stream, format!("STATQUE {}\n", queue).as_bytes())
+    }
+
+    // Each of two workers gets a job, and the first one acks it, and then both are closed.
+    fn lease(port: u16, producer: &mut TcpStream, queue: &str) -> Vec<u8> {
+        for _ in 0..3 {
+            let ret = request(producer, format!("ADDJOB {} 300 job\n", queue).as_bytes());
+            assert_eq!(&ret[0..2], b"1 ");
+        }
+        let mut first = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut second = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let acked = get(&mut first, queue);
+        assert_eq!(
+            request(&mut first, &[b"ACKJOB ".as_ref(), &acked, b"\n"].concat()),
+            b"1 1\n"
+        );
+        let leased = get(&mut second, queue);
+        // The job got by the producer is kept running.
+        get(producer, queue);
+        assert_eq!(stat(producer, queue), b"1 2 2 1 0\n");
+        drop(first);
+        drop(second);
+        sleep(Duration::from_millis(200));
+        leased
+    }
+
+    #[test]
+    fn requeue_on_disconnect() {
+        let port = 9450;
+        let (_server, mut producer) = start(port, &["--requeue-on-disconnect"]);
+        let queue = "test-requeue";
+        let leased = lease(port, &mut producer, queue);
+        assert_eq!(stat(&mut producer, queue), b"1 2 1 1 0\n");
+
+        // The job is got again at once.
+        let mut worker = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        assert_eq!(get(&mut worker, queue), leased);
+    }
+
+    #[test]
+    fn keep_on_disconnect() {
+        let port = 9451;
+        let (_server, mut producer) = start(port, &[]);
+        let queue = "test-requeue-keep";
+        lease(port, &mut producer, queue);
+        // The job waits for its retry time.
+        assert_eq!(stat(&mut producer, queue), b"1 2 2 1 0\n");
+        let mut worker = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        assert_eq!(
+            request(&mut worker, format!("GETJOB {}\n", queue).as_bytes()),
+            b"0 \n"
+        );
+    }
+
+    // A GETJOB forwarded to the shard of the job may be handled after the close.
+    #[test]
+    fn requeue_forwarded() {
+        let port = 9452;
+        let (_server, mut producer) = start(port, &["--shards", "4", "--requeue-on-disconnect"]);
+        let queue = "test-requeue-forwarded";
+        let jobs = 200;
+        for _ in 0..jobs {
+            let ret = request(
+                &mut producer,
+                format!("ADDJOB {} 300 job\n", queue).as_bytes(),
+            );
+            assert_eq!(&ret[0..2], b"1 ");
+        }
+        let names: Vec<String> = (0..16).map(|i| format!("{}-{}", queue, i)).collect();
+        let message = format!("GETJOB {} {}\n", names.join(" "), queue);
+        for _ in 0..jobs {
+            let mut worker = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            worker.write_all(message.as_bytes()).unwrap();
+        }
+        let expected = format!("1 {} 0 0 0\n", jobs);
+        let start = Instant::now();
+        while stat(&mut producer, queue) != expected.as_bytes() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            sleep(Duration::from_millis(100));
+        }
+    }
+}
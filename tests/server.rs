@@ -4,7 +4,7 @@ mod tests {
     use mio::{Events, Interest, Poll, Token};
     use std::io;
     use std::io::prelude::*;
-    use std::net::Shutdown;
+    use std::net::{self, Shutdown};
 
     fn read(stream: &mut TcpStream) -> Vec<u8> {
         let mut ret = vec![0; 0];
@@ -20,9 +20,36 @@ mod tests {
         ret
     }
 
+    fn request(stream: &mut net::TcpStream, message: &[u8]) -> Vec<u8> {
+        stream.write_all(message).unwrap();
+        let mut ret = vec![0; 0];
+        let mut buffer = [0u8; 4096];
+        while ret.last() != Some(&b'\n') {
+            let n = stream.read(&mut buffer).unwrap();
+            assert_ne!(n, 0);
+            ret.extend(&buffer[0..n]);
+        }
+        ret
+    }
+
+    // Read `<status> <size> <data>\n`, which is sent after SYNC.
+    fn read_frame(reader: &mut io::BufReader<net::TcpStream>) -> (Vec<u8>, Vec<u8>) {
+        let mut status = vec![0; 0];
+        reader.read_until(b' ', &mut status).unwrap();
+        status.pop();
+        let mut size = vec![0; 0];
+        reader.read_until(b' ', &mut size).unwrap();
+        size.pop();
+        let size: usize = String::from_utf8(size).unwrap().parse().unwrap();
+        let mut data = vec![0; size + 1];
+        reader.read_exact(&mut data).unwrap();
+        assert_eq!(data.pop(), Some(b'\n'));
+        (status, data)
+    }
+
     #[test]
     fn job_routine() {
-        let max_size = 1 * 1024 * 1024;
+        let max_size = 1024 * 1024;
         let addr = "127.0.0.1:9000".parse().unwrap();
         let mut stream = TcpStream::connect(addr).unwrap();
 
@@ -38,7 +65,7 @@ mod tests {
         for event in events.iter() {
             assert_eq!(event.token().0, token.0);
             assert!(event.is_writable());
-            stream.write(b"DELQUE test-que\n").unwrap();
+            stream.write_all(b"DELQUE test-que\n").unwrap();
             poll.registry()
                 .reregister(&mut stream, token, Interest::READABLE)
                 .unwrap();
@@ -65,14 +92,14 @@ mod tests {
         }
         assert_eq!(ret.len(), 3);
 
-        let job = vec!['a' as u8; max_size];
+        let job = vec![b'a'; max_size];
 
         poll.poll(&mut events, None).unwrap();
         for event in events.iter() {
             assert_eq!(event.token().0, token.0);
             assert!(event.is_writable());
             stream
-                .write(
+                .write_all(
                     [b"ADDJOB test-que 300 ", job.as_slice(), b"\n"]
                         .concat()
                         .as_slice(),
@@ -110,7 +137,7 @@ mod tests {
         for event in events.iter() {
             assert_eq!(event.token().0, token.0);
             assert!(event.is_writable());
-            stream.write(b"GETJOB test-que\n").unwrap();
+            stream.write_all(b"GETJOB test-que\n").unwrap();
             poll.registry()
                 .reregister(&mut stream, token, Interest::READABLE)
                 .unwrap();
@@ -146,7 +173,7 @@ mod tests {
             assert_eq!(event.token().0, token.0);
             assert!(event.is_writable());
             stream
-                .write([b"ACKJOB ", job_id.as_slice(), b"\n"].concat().as_slice())
+                .write_all([b"ACKJOB ", job_id.as_slice(), b"\n"].concat().as_slice())
                 .unwrap();
             poll.registry()
                 .reregister(&mut stream, token, Interest::READABLE)
@@ -172,14 +199,14 @@ mod tests {
                 }
             }
         }
-        assert_eq!(ret.len(), 3);
-        assert_eq!(&ret[0..ret.len()], b"1 \n");
+        assert_eq!(ret.len(), 4);
+        assert_eq!(&ret[0..ret.len()], b"1 1\n");
 
         poll.poll(&mut events, None).unwrap();
         for event in events.iter() {
             assert_eq!(event.token().0, token.0);
             assert!(event.is_writable());
-            stream.write(b"DELQUE test-que\n").unwrap();
+            stream.write_all(b"DELQUE test-que\n").unwrap();
             poll.registry()
                 .reregister(&mut stream, token, Interest::READABLE)
                 .unwrap();
@@ -209,4 +236,257 @@ mod tests {
 
         let _ = stream.shutdown(Shutdown::Both);
     }
+
+    #[test]
+    fn pause_and_resume_queue() {
+        let mut stream = net::TcpStream::connect("127.0.0.1:9000").unwrap();
+        request(&mut stream, b"DELQUE test-pause-que\n");
+
+        assert_eq!(request(&mut stream, b"CREATEQUE test-pause-que\n"), b"1 \n");
+        assert_eq!(request(&mut stream, b"CREATEQUE test-pause-que\n"), b"0 \n");
+
+        let ret = request(&mut stream, b"ADDJOB test-pause-que 300 job\n");
+        assert_eq!(&ret[0..2], b"1 ");
+
+        assert_eq!(request(&mut stream, b"PAUSEQUE test-pause-que\n"), b"1 \n");
+        assert_eq!(request(&mut stream, b"GETJOB test-pause-que\n"), b"0 \n");
+
+        assert_eq!(request(&mut stream, b"RESUMEQUE test-pause-que\n"), b"1 \n");
+        let ret = request(&mut stream, b"GETJOB test-pause-que\n");
+        assert_eq!(&ret[0..2], b"1 ");
+        assert_eq!(&ret[ret.len() - 5..], b" job\n");
+
+        assert_eq!(
+            request(&mut stream, b"PAUSEQUE test-missing-que\n"),
+            b"0 \n"
+        );
+        assert_eq!(request(&mut stream, b"DELQUE test-pause-que\n"), b"1 \n");
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    #[test]
+    fn operate_queue() {
+        let mut stream = net::TcpStream::connect("127.0.0.1:9000").unwrap();
+        request(&mut stream, b"DELQUE test-src-que\n");
+        request(&mut stream, b"DELQUE test-dst-que\n");
+
+        for _ in 0..3 {
+            let ret = request(&mut stream, b"ADDJOB test-src-que 300 job\n");
+            assert_eq!(&ret[0..2], b"1 ");
+        }
+        let ret = request(&mut stream, b"GETJOB test-src-que\n");
+        assert_eq!(&ret[0..2], b"1 ");
+        assert_eq!(
+            request(&mut stream, b"STATQUE test-src-que\n"),
+            b"1 3 1 0 0\n"
+        );
+        assert_eq!(request(&mut stream, b"REQUEUE test-src-que\n"), b"1 1\n");
+        assert_eq!(
+            request(&mut stream, b"STATQUE test-src-que\n"),
+            b"1 3 0 0 0\n"
+        );
+
+        assert_eq!(
+            request(&mut stream, b"MOVEQUE test-src-que test-dst-que 2\n"),
+            b"1 2\n"
+        );
+        assert_eq!(
+            request(&mut stream, b"STATQUE test-src-que\n"),
+            b"1 1 0 0 0\n"
+        );
+        assert_eq!(
+            request(&mut stream, b"STATQUE test-dst-que\n"),
+            b"1 2 0 0 0\n"
+        );
+
+        let ret = request(&mut stream, b"GETJOB test-dst-que\n");
+        assert_eq!(&ret[0..2], b"1 ");
+        let job_id = ret[2..34].to_vec();
+        assert_eq!(request(&mut stream, b"PURGEQUE test-dst-que\n"), b"1 1\n");
+        assert_eq!(
+            request(&mut stream, b"STATQUE test-dst-que\n"),
+            b"1 1 1 0 0\n"
+        );
+        assert_eq!(
+            request(
+                &mut stream,
+                [b"ACKJOB ", job_id.as_slice(), b"\n"].concat().as_slice()
+            ),
+            b"1 1\n"
+        );
+
+        request(&mut stream, b"DELQUE test-src-que\n");
+        request(&mut stream, b"DELQUE test-dst-que\n");
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    #[test]
+    fn delete_job() {
+        let mut stream = net::TcpStream::connect("127.0.0.1:9000").unwrap();
+        request(&mut stream, b"DELQUE test-del-que\n");
+
+        let ret = request(&mut stream, b"ADDJOB test-del-que 300 job\n");
+        let running_id = ret[2..34].to_vec();
+        let ret = request(&mut stream, b"ADDJOB test-del-que 300 job\n");
+        let ready_id = ret[2..34].to_vec();
+        let ret = request(&mut stream, b"GETJOB test-del-que\n");
+        assert_eq!(&ret[2..34], running_id.as_slice());
+
+        assert_eq!(
+            request(
+                &mut stream,
+                [b"DELJOB ", ready_id.as_slice(), b" unknown\n"]
+                    .concat()
+                    .as_slice()
+            ),
+            b"1 1\n"
+        );
+        assert_eq!(
+            request(&mut stream, b"STATQUE test-del-que\n"),
+            b"1 1 1 0 1\n"
+        );
+        assert_eq!(
+            request(
+                &mut stream,
+                [b"DELJOB ", running_id.as_slice(), b"\n"]
+                    .concat()
+                    .as_slice()
+            ),
+            b"1 1\n"
+        );
+        assert_eq!(
+            request(&mut stream, b"STATQUE test-del-que\n"),
+            b"1 0 0 0 2\n"
+        );
+
+        request(&mut stream, b"DELQUE test-del-que\n");
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    #[test]
+    fn ack_multi_jobs() {
+        let mut stream = net::TcpStream::connect("127.0.0.1:9000").unwrap();
+        request(&mut stream, b"DELQUE test-ack-que\n");
+
+        let mut job_ids = Vec::new();
+        for _ in 0..3 {
+            request(&mut stream, b"ADDJOB test-ack-que 300 job\n");
+            let ret = request(&mut stream, b"GETJOB test-ack-que\n");
+            assert_eq!(&ret[0..2], b"1 ");
+            job_ids.push(ret[2..34].to_vec());
+        }
+
+        let ret = request(
+            &mut stream,
+            [
+                b"ACKJOB ".as_ref(),
+                job_ids[0].as_slice(),
+                b"  ",
+                job_ids[1].as_slice(),
+                b"\n",
+            ]
+            .concat()
+            .as_slice(),
+        );
+        assert_eq!(ret, b"1 2\n");
+        assert_eq!(
+            request(&mut stream, b"STATQUE test-ack-que\n"),
+            b"1 1 1 2 0\n"
+        );
+
+        // Already acknowledged and unknown IDs are reported back.
+        let ret = request(
+            &mut stream,
+            [
+                b"ACKJOB ".as_ref(),
+                job_ids[0].as_slice(),
+                b" ",
+                job_ids[2].as_slice(),
+                b" unknown\n",
+            ]
+            .concat()
+            .as_slice(),
+        );
+        assert_eq!(
+            ret,
+            [b"1 1 ".as_ref(), job_ids[0].as_slice(), b" unknown\n"].concat()
+        );
+        assert_eq!(
+            request(&mut stream, b"STATQUE test-ack-que\n"),
+            b"1 0 0 3 0\n"
+        );
+
+        assert_eq!(request(&mut stream, b"ACKJOB\n"), b"1 0\n");
+
+        request(&mut stream, b"DELQUE test-ack-que\n");
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    #[test]
+    fn auth_without_tokens() {
+        // The test server requires no authentication, so any token is accepted.
+        let mut stream = net::TcpStream::connect("127.0.0.1:9000").unwrap();
+        assert_eq!(request(&mut stream, b"AUTH anything\n"), b"1 OK\n");
+        assert_eq!(
+            request(&mut stream, b"STATQUE test-auth-que\n"),
+            b"0 0 0 0 0\n"
+        );
+    }
+
+    #[test]
+    fn client_list() {
+        let mut stream = net::TcpStream::connect("127.0.0.1:9000").unwrap();
+        request(&mut stream, b"DELQUE test-client-que\n");
+        assert_eq!(
+            request(&mut stream, b"CLIENT SETNAME test-client\n"),
+            b"1 OK\n"
+        );
+        request(&mut stream, b"ADDJOB test-client-que 300 job\n");
+        request(&mut stream, b"GETJOB test-client-que\n");
+
+        let ret = String::from_utf8(request(&mut stream, b"CLIENT LIST\n")).unwrap();
+        assert!(ret.starts_with("1 "));
+        let entry = ret[2..]
+            .trim_end()
+            .split("; ")
+            .find(|entry| entry.contains(" name=test-client "))
+            .unwrap();
+        assert!(entry.ends_with(" cmd=CLIENT leased=1"));
+
+        assert_eq!(request(&mut stream, b"CLIENT KILL 0\n"), b"0 0\n");
+        request(&mut stream, b"DELQUE test-client-que\n");
+    }
+
+    #[test]
+    fn sync_records() {
+        let mut stream = net::TcpStream::connect("127.0.0.1:9000").unwrap();
+        request(&mut stream, b"DELQUE test-sync-que\n");
+        let mut replica = net::TcpStream::connect("127.0.0.1:9000").unwrap();
+        replica.write_all(b"SYNC\n").unwrap();
+        let mut reader = io::BufReader::new(replica);
+        let (status, _) = read_frame(&mut reader);
+        assert_eq!(status, b"1");
+
+        let ret = request(&mut stream, b"ADDJOB test-sync-que 300 job\n");
+        let job_id = &ret[2..ret.len() - 1];
+        request(&mut stream, b"GETJOB test-sync-que\n");
+        // The records of the other tests may come in between.
+        let mut records = vec![];
+        while records.len() < 2 {
+            let (status, record) = read_frame(&mut reader);
+            assert_eq!(status, b"2");
+            if record.windows(job_id.len()).any(|w| w == job_id) {
+                records.push(record);
+            }
+        }
+        assert_eq!(
+            records[0],
+            [b"ADD test-sync-que ".as_ref(), job_id, b" 300 job"].concat()
+        );
+        assert_eq!(records[1], [b"RUN ".as_ref(), job_id].concat());
+
+        // The test server is not a replica.
+        assert_eq!(request(&mut stream, b"PROMOTE\n"), b"0 \n");
+        request(&mut stream, b"DELQUE test-sync-que\n");
+    }
 }
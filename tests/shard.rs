@@ -0,0 +1,277 @@
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hasher;
+    use std::io::prelude::*;
+    use std::net::TcpStream;
+    use std::process::{Child, Command, Stdio};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    const QUEUES: usize = 8;
+    const SHARDS: u64 = 4;
+
+    // Kill the server even if the test fails.
+    struct Server(Child);
+
+    impl Server {
+        fn start(port: u16, args: &[&str]) -> (Server, TcpStream) {
+            let child = Command::new(env!("CARGO_BIN_EXE_qust"))
+                .args(["-p", &port.to_string(), "--shards", &SHARDS.to_string()])
+                .args(args)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap();
+            let start = Instant::now();
+            loop {
+                match TcpStream::connect(("127.0.0.1", port)) {
+                    Ok(stream) => return (Server(child), stream),
+                    Err(_) => {
+                        assert!(start.elapsed() < Duration::from_secs(10));
+                        sleep(Duration::from_millis(50));
+                    }
+                }
+            }
+        }
+        // Shut down by SIGTERM, which saves the queues.
+        fn stop(mut self) {
+            Command::new("kill")
+                .arg(self.0.id().to_string())
+                .status()
+                .unwrap();
+            self.0.wait().unwrap();
+        }
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn request(stream: &mut TcpStream, message: &[u8]) -> Vec<u8> {
+        stream.write_all(message).unwrap();
+        let mut ret = vec![0; 0];
+        let mut buffer = [0u8; 4096];
+        while ret.last() != Some(&b'\n') {
+            let n = stream.read(&mut buffer).unwrap();
+            assert_ne!(n, 0);
+            ret.extend(&buffer[0..n]);
+        }
+        ret
+    }
+
+    fn name(i: usize) -> String {
+        format!("test-shard-que-{}", i)
+    }
+
+    // The shard of the queue, hashed as the server does.
+    fn shard(name: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        hasher.write(name.as_bytes());
+        hasher.finish() % SHARDS
+    }
+
+    #[test]
+    fn across_shards() {
+        let persist = std::env::temp_dir().join(format!("qust-shard-{}", std::process::id()));
+        let persist_arg = persist.to_str().unwrap();
+        let (server, mut stream) = Server::start(9330, &["--persist", persist_arg]);
+
+        // GETJOB takes the queues in order, which are spread over the shards.
+        let mut ids = Vec::new();
+        for i in 0..QUEUES {
+            let ret = request(
+                &mut stream,
+                format!("ADDJOB {} 300 job{}\n", name(i), i).as_bytes(),
+            );
+            assert_eq!(&ret[0..2], b"1 ");
+            ids.push(ret[2..ret.len() - 1].to_vec());
+        }
+        let names: Vec<String> = (0..QUEUES).rev().map(name).collect();
+        for i in (0..QUEUES).rev() {
+            let ret = request(
+                &mut stream,
+                format!("GETJOB {}\n", names.join(" ")).as_bytes(),
+            );
+            assert_eq!(
+                ret,
+                [b"1 ", ids[i].as_slice(), format!(" job{}\n", i).as_bytes()].concat()
+            );
+        }
+        assert_eq!(
+            request(
+                &mut stream,
+                format!("GETJOB {}\n", names.join(" ")).as_bytes()
+            ),
+            b"0 \n"
+        );
+        let ret = request(&mut stream, b"CLIENT LIST\n");
+        assert!(ret.ends_with(format!(" leased={}\n", QUEUES).as_bytes()));
+
+        // ACKJOB finds the jobs in any shard.
+        let unknown = b"0123456789abcdef0123456789abcdef";
+        let ret = request(
+            &mut stream,
+            &[
+                b"ACKJOB ",
+                ids.join(b" ".as_ref()).as_slice(),
+                b" ",
+                unknown,
+                b"\n",
+            ]
+            .concat(),
+        );
+        assert_eq!(
+            ret,
+            [format!("1 {} ", QUEUES).as_bytes(), unknown, b"\n"].concat()
+        );
+
+        // MOVEQUE passes the jobs on to the shard of the destination.
+        for i in 0..QUEUES {
+            request(
+                &mut stream,
+                format!("ADDJOB {} 300 job\n", name(QUEUES)).as_bytes(),
+            );
+            assert_eq!(
+                request(&mut stream, format!("DELQUE {}\n", name(i)).as_bytes()),
+                b"1 \n"
+            );
+        }
+        for i in 0..QUEUES {
+            assert_eq!(
+                request(
+                    &mut stream,
+                    format!("MOVEQUE {} {} 1\n", name(QUEUES), name(i)).as_bytes()
+                ),
+                b"1 1\n"
+            );
+        }
+        let ret = request(&mut stream, format!("GETJOB {}\n", name(0)).as_bytes());
+        assert_eq!(&ret[0..2], b"1 ");
+        assert_eq!(
+            request(&mut stream, &[b"DELJOB ", &ret[2..34], b"\n"].concat()),
+            b"1 1\n"
+        );
+
+        // The queues of all shards are saved to the file and loaded again.
+        server.stop();
+        let (_server, mut stream) = Server::start(9330, &["--persist", persist_arg]);
+        let _ = std::fs::remove_file(&persist);
+        assert_eq!(
+            request(&mut stream, format!("STATQUE {}\n", name(0)).as_bytes()),
+            b"1 0 0 0 1\n"
+        );
+        for i in 1..QUEUES {
+            assert_eq!(
+                request(&mut stream, format!("STATQUE {}\n", name(i)).as_bytes()),
+                b"1 1 0 0 0\n"
+            );
+        }
+        assert_eq!(
+            request(
+                &mut stream,
+                format!("STATQUE {}\n", name(QUEUES)).as_bytes()
+            ),
+            b"1 0 0 0 0\n"
+        );
+    }
+
+    #[test]
+    fn move_running_jobs() {
+        let (_server, mut stream) = Server::start(9331, &["--requeue-on-disconnect"]);
+        let src = "test-shard-move-src";
+        let dst = (0..)
+            .map(|i| format!("test-shard-move-dst-{}", i))
+            .find(|dst| shard(dst) != shard(src))
+            .unwrap();
+
+        // The running jobs keep their owners and their start times in the other shard.
+        let mut worker = TcpStream::connect(("127.0.0.1", 9331)).unwrap();
+        for retry in [300, 2] {
+            let ret = request(
+                &mut stream,
+                format!("ADDJOB {} {} job\n", src, retry).as_bytes(),
+            );
+            assert_eq!(&ret[0..2], b"1 ");
+        }
+        for _ in 0..2 {
+            let ret = request(&mut worker, format!("GETJOB {}\n", src).as_bytes());
+            assert_eq!(&ret[0..2], b"1 ");
+        }
+        sleep(Duration::from_millis(1500));
+        assert_eq!(
+            request(&mut stream, format!("MOVEQUE {} {}\n", src, dst).as_bytes()),
+            b"1 2\n"
+        );
+        assert_eq!(
+            request(&mut stream, format!("STATQUE {}\n", dst).as_bytes()),
+            b"1 2 2 0 0\n"
+        );
+        let ret = request(&mut stream, b"CLIENT LIST\n");
+        assert!(String::from_utf8(ret).unwrap().contains(" leased=2"));
+
+        // The retry time of the second job has passed since it was got.
+        sleep(Duration::from_millis(1000));
+        let ret = request(&mut stream, format!("GETJOB {}\n", dst).as_bytes());
+        assert_eq!(&ret[0..2], b"1 ");
+
+        // The first job returns to ready when its worker is gone.
+        drop(worker);
+        sleep(Duration::from_millis(200));
+        assert_eq!(
+            request(&mut stream, format!("STATQUE {}\n", dst).as_bytes()),
+            b"1 2 1 0 0\n"
+        );
+    }
+
+    // The replies to the pipelined requests come in order, though the shards handle
+    // them at the same time.
+    #[test]
+    fn pipelined() {
+        let (_server, mut stream) = Server::start(9332, &[]);
+        // The queue i has i jobs.
+        for i in 0..QUEUES {
+            for _ in 0..i {
+                let ret = request(
+                    &mut stream,
+                    format!(
+                        "ADDJOB {} 300 job
+",
+                        name(i)
+                    )
+                    .as_bytes(),
+                );
+                assert_eq!(&ret[0..2], b"1 ");
+            }
+        }
+        let mut message = Vec::new();
+        let mut expected = Vec::new();
+        for _ in 0..200 {
+            for i in 0..QUEUES {
+                message.extend(format!("STATQUE {}\n", name(i)).as_bytes());
+                expected.extend(
+                    match i {
+                        0 => "0 0 0 0 0\n".to_string(),
+                        i => format!("1 {} 0 0 0\n", i),
+                    }
+                    .as_bytes(),
+                );
+            }
+        }
+        stream.write_all(&message).unwrap();
+        let mut ret = vec![0; 0];
+        let mut buffer = [0u8; 4096];
+        while ret.len() < expected.len() {
+            let n = stream.read(&mut buffer).unwrap();
+            assert_ne!(n, 0);
+            ret.extend(&buffer[0..n]);
+        }
+        assert_eq!(
+            String::from_utf8(ret).unwrap(),
+            String::from_utf8(expected).unwrap()
+        );
+    }
+}
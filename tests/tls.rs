@@ -0,0 +1,132 @@
+#[cfg(all(test, feature = "tls"))]
+mod tests {
+    use qust::tls;
+    use std::io::prelude::*;
+    use std::net::TcpStream;
+    use std::path::PathBuf;
+    use std::process::{Child, Command, Stdio};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    // Kill the server even if the test fails.
+    struct Server(Child);
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    // The certificates signed by the CA for `localhost`, and for a client.
+    fn path(name: &str) -> PathBuf {
+        [env!("CARGO_MANIFEST_DIR"), "tests", "tls", name]
+            .iter()
+            .collect()
+    }
+
+    fn start(port: u16, args: &[&str]) -> Server {
+        let server = Server(
+            Command::new(env!("CARGO_BIN_EXE_qust"))
+                .args(["-p", &port.to_string(), "--tls-cert"])
+                .arg(path("server.pem"))
+                .arg("--tls-key")
+                .arg(path("server.key"))
+                .args(args)
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap(),
+        );
+        let start = Instant::now();
+        while TcpStream::connect(("127.0.0.1", port)).is_err() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            sleep(Duration::from_millis(50));
+        }
+        server
+    }
+
+    fn connect(port: u16, client: bool) -> std::io::Result<impl Read + Write> {
+        let (cert, key) = (path("client.pem"), path("client.key"));
+        let cert_key = match client {
+            true => Some((cert.as_path(), key.as_path())),
+            false => None,
+        };
+        let config = tls::client_config(&path("ca.pem"), cert_key)?;
+        let sock = TcpStream::connect(("127.0.0.1", port))?;
+        tls::connect(config, "localhost", sock)
+    }
+
+    // Send the request over TLS, and return the reply or the error of the handshake.
+    fn request(port: u16, client: bool, message: &[u8]) -> std::io::Result<Vec<u8>> {
+        exchange(&mut connect(port, client)?, message)
+    }
+
+    fn exchange<S: Read + Write>(stream: &mut S, message: &[u8]) -> std::io::Result<Vec<u8>> {
+        stream.write_all(message)?;
+        let mut ret = vec![0; 0];
+        let mut buffer = [0u8; 4096];
+        while ret.last() != Some(&b'\n') {
+            match stream.read(&mut buffer)? {
+                0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                n => ret.extend(&buffer[0..n]),
+            }
+        }
+        Ok(ret)
+    }
+
+    #[test]
+    fn handshake() {
+        let port = 9430;
+        let _server = start(port, &[]);
+        assert_eq!(
+            request(port, false, b"ADDJOB test-tls 300 job\n").unwrap()[0..2],
+            *b"1 "
+        );
+        assert_eq!(
+            request(port, false, b"STATQUE test-tls\n").unwrap(),
+            b"1 1 0 0 0\n"
+        );
+
+        // A plain connection gets no reply.
+        let mut plain = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        plain
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        plain.write_all(b"STATQUE test-tls\n").unwrap();
+        let mut buffer = [0u8; 4096];
+        let n = plain.read(&mut buffer).unwrap_or(0);
+        assert!(!buffer[0..n].starts_with(b"1 "));
+    }
+
+    #[test]
+    fn client_certificate() {
+        let port = 9431;
+        let _server = start(port, &["--tls-client-ca", path("ca.pem").to_str().unwrap()]);
+        assert!(request(port, false, b"STATQUE test-tls\n").is_err());
+        assert_eq!(
+            request(port, true, b"STATQUE test-tls\n").unwrap(),
+            b"0 0 0 0 0\n"
+        );
+    }
+
+    // The connection beyond the limit is closed without a reply in plain text.
+    #[test]
+    fn max_connections() {
+        let port = 9432;
+        let _server = start(port, &["--max-connections", "1"]);
+        // The connection made to wait for the server is closed.
+        sleep(Duration::from_millis(200));
+        let mut first = connect(port, false).unwrap();
+        assert_eq!(
+            exchange(&mut first, b"STATQUE test-tls-max\n").unwrap(),
+            b"0 0 0 0 0\n"
+        );
+        let mut rejected = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        rejected
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut buffer = [0u8; 64];
+        assert_eq!(rejected.read(&mut buffer).unwrap_or(0), 0);
+    }
+}
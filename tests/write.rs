@@ -0,0 +1,83 @@
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::net::TcpStream;
+    use std::process::{Child, Command, Stdio};
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+
+    const JOBS: usize = 4;
+    const JOB_SIZE: usize = 4 * 1024 * 1024;
+
+    // Kill the server even if the test fails.
+    struct Server(Child);
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    fn start(port: u16) -> (Server, TcpStream) {
+        let server = Server(
+            Command::new(env!("CARGO_BIN_EXE_qust"))
+                .args(["-p", &port.to_string(), "--max-buffer-size", "8000000"])
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .unwrap(),
+        );
+        let start = Instant::now();
+        loop {
+            match TcpStream::connect(("127.0.0.1", port)) {
+                Ok(stream) => return (server, stream),
+                Err(_) => {
+                    assert!(start.elapsed() < Duration::from_secs(10));
+                    sleep(Duration::from_millis(50));
+                }
+            }
+        }
+    }
+
+    fn request(stream: &mut TcpStream, message: &[u8]) -> Vec<u8> {
+        stream.write_all(message).unwrap();
+        let mut ret = vec![0; 0];
+        let mut buffer = [0u8; 4096];
+        while ret.last() != Some(&b'\n') {
+            let n = stream.read(&mut buffer).unwrap();
+            assert_ne!(n, 0);
+            ret.extend(&buffer[0..n]);
+        }
+        ret
+    }
+
+    // The reply of the status, the ID and the job is written in parts to a slow reader,
+    // each of which may end in the middle of a chunk.
+    #[test]
+    fn partial_writes() {
+        let port = 9460;
+        let (_server, mut producer) = start(port);
+        let mut consumer = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        for i in 0..JOBS {
+            let job: Vec<u8> = (0..JOB_SIZE).map(|j| b'a' + ((i + j) % 26) as u8).collect();
+            let ret = request(
+                &mut producer,
+                &[b"ADDJOB test-write-partial 300 ".as_ref(), &job, b"\n"].concat(),
+            );
+            assert_eq!(&ret[0..2], b"1 ");
+            let expected = [&ret[..ret.len() - 1], b" ", &job, b"\n"].concat();
+
+            consumer.write_all(b"GETJOB test-write-partial\n").unwrap();
+            let mut ret = vec![0; 0];
+            let mut buffer = [0u8; 65536];
+            while ret.len() < expected.len() {
+                sleep(Duration::from_millis(5));
+                let n = consumer.read(&mut buffer).unwrap();
+                assert_ne!(n, 0);
+                ret.extend(&buffer[0..n]);
+            }
+            assert!(ret == expected);
+        }
+    }
+}